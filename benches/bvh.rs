@@ -0,0 +1,136 @@
+//! Benchmark BVH construction in each build mode and CPU ray traversal on the bundled
+//! `suzanne2.obj`: `cargo bench --bench bvh`.
+
+#![allow(dead_code)]
+
+// Shared with the renderer, which is where it is linted.
+#[allow(clippy::all)]
+#[path = "../src/mesh.rs"]
+mod mesh;
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use glam::Vec3;
+use obj::Obj;
+
+use mesh::{BvhBuildMode, BvhConfig, Mesh};
+
+/// Number of random rays traced per iteration.
+const RAYS: usize = 1_000;
+
+/// Build modes to compare, with whether they build in double precision.
+const MODES: [(BvhBuildMode, bool); 3] = [
+    (BvhBuildMode::Sah, false),
+    (BvhBuildMode::Sah, true),
+    (BvhBuildMode::Median, false),
+];
+
+/// Small xorshift generator so the rays are the same on every run.
+struct Rng(u32);
+
+impl Rng {
+    fn next_f32(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 >> 8) as f32 / 16777216.0
+    }
+
+    fn next_vec3(&mut self) -> Vec3 {
+        Vec3::new(self.next_f32(), self.next_f32(), self.next_f32())
+    }
+}
+
+/// Load the bundled benchmark mesh without a BVH.
+fn load_mesh() -> Mesh {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/suzanne2.obj");
+    let obj = Obj::load(path).expect("failed to load suzanne2.obj");
+    let groups: Vec<_> = obj
+        .data
+        .objects
+        .iter()
+        .flat_map(|f| f.groups.iter())
+        .collect();
+    let mut mesh = Mesh::from_groups(&obj, &groups, None);
+    mesh.bvh = None;
+    mesh
+}
+
+/// Random rays from a sphere around the mesh towards random points in its bounds.
+fn random_rays(mesh: &Mesh, count: usize) -> Vec<(Vec3, Vec3)> {
+    let (min, max) = mesh
+        .verts
+        .iter()
+        .fold((Vec3::MAX, Vec3::MIN), |(min, max), f| {
+            (min.min(*f), max.max(*f))
+        });
+    let center = (min + max) * 0.5;
+    let radius = (max - min).length();
+    let mut rng = Rng(0x2545f491);
+    (0..count)
+        .map(|_| {
+            let pos = center + (rng.next_vec3() * 2.0 - 1.0).normalize_or(Vec3::X) * radius;
+            let target = min + (max - min) * rng.next_vec3();
+            (pos, (target - pos).normalize())
+        })
+        .collect()
+}
+
+fn mode_name(mode: BvhBuildMode, double_precision: bool) -> String {
+    format!("{mode:?}{}", if double_precision { "-f64" } else { "" })
+}
+
+fn bench_build(c: &mut Criterion) {
+    let mesh = load_mesh();
+    let mut group = c.benchmark_group("bvh_build");
+    group.sample_size(10);
+    for (mode, double_precision) in MODES {
+        let config = BvhConfig {
+            mode,
+            double_precision,
+            ..Default::default()
+        };
+        group.bench_function(mode_name(mode, double_precision), |b| {
+            b.iter_batched(
+                || mesh.clone(),
+                |mut mesh| {
+                    mesh.create_bvh(&config);
+                    mesh
+                },
+                criterion::BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_rays(c: &mut Criterion) {
+    let mesh = load_mesh();
+    let rays = random_rays(&mesh, RAYS);
+    let mut group = c.benchmark_group("ray_test");
+    group.throughput(Throughput::Elements(RAYS as u64));
+    for (mode, double_precision) in MODES {
+        let mut built = mesh.clone();
+        built.create_bvh(&BvhConfig {
+            mode,
+            double_precision,
+            ..Default::default()
+        });
+        group.bench_with_input(
+            BenchmarkId::from_parameter(mode_name(mode, double_precision)),
+            &built,
+            |b, mesh| {
+                b.iter(|| {
+                    rays.iter()
+                        .filter(|(pos, dir)| black_box(mesh).ray_test(*pos, *dir).is_some())
+                        .count()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_build, bench_rays);
+criterion_main!(benches);
@@ -1,8 +1,42 @@
 #version 450
 
+// Format of the ray-tracing images; the 16-bit fallback is compiled with -DRT_FORMAT=rgba16f.
+#ifndef RT_FORMAT
+#define RT_FORMAT rgba32f
+#endif
+
 layout(location = 0) out vec4 outColor;
-layout(binding = 0, rgba32f) uniform image2D img;
-layout(push_constant, std430) uniform pc { uint frameCounter; };
+layout(binding = 0, RT_FORMAT) uniform image2D img;
+// Sum of the traversal cost per sample in the first channel and the sample count in the fourth.
+layout(binding = 1, RT_FORMAT) uniform image2D costImg;
+layout(push_constant, std430) uniform pc {
+  uint frameCounter;
+  uint showHud;
+  float fps;
+  // Opacity of the traversal cost overlay; 0 hides it.
+  float costBlend;
+  // Traversal cost shown as the hottest color.
+  float costScale;
+  // Whether to show signed luminance in false color, for debugging negative emission.
+  uint signedDisplay;
+  // Working color space of the image: 0 is linear sRGB, 1 is ACEScg.
+  uint colorSpace;
+  // Factor applied to the image before tone mapping.
+  float exposure;
+  // Output variable in the image, see `Aov`; 0 is the tone-mapped beauty pass.
+  uint aov;
+};
+
+// ACEScg to linear sRGB; must match `ACESCG_TO_SRGB`.
+const mat3 acesCgToSrgb =
+    mat3(1.704859, -0.130077, -0.023964, -0.621716, 1.140736, -0.128976,
+         -0.083299, -0.010560, 1.153014);
+
+// 3x5 bitmap font for the digits 0-9; bit 14 is the top-left pixel.
+const uint hudFont[10] = uint[](0x7b6f, 0x2c97, 0x73e7, 0x73cf, 0x5bc9, 0x79cf,
+                                0x79ef, 0x7292, 0x7bef, 0x7bcf);
+// Size of a single font pixel in screen pixels.
+const int hudScale = 3;
 
 vec3 aces(vec3 x) {
   const float a = 2.51;
@@ -13,11 +47,106 @@ vec3 aces(vec3 x) {
   return clamp((x * (a * x + b)) / (x * (c * x + d) + e), 0.0, 1.0);
 }
 
+// Blue-green-yellow-red false color for `x` in [0, 1].
+vec3 heatmap(float x) {
+  x = clamp(x, 0, 1);
+  return clamp(vec3(4 * x - 2, 2 - abs(4 * x - 2), 2 - 4 * x), 0, 1);
+}
+
+// Distinct color for an ID; 0 is black.
+vec3 idColor(uint id) {
+  if (id == 0) {
+    return vec3(0);
+  }
+  uint hash = id * 0x9e3779b9u;
+  hash ^= hash >> 15;
+  return 0.2 + 0.8 * vec3(hash & 0xff, (hash >> 8) & 0xff, (hash >> 16) & 0xff) / 255.0;
+}
+
+// Map a raw output variable to a displayable color.
+vec3 showAov(vec3 value) {
+  switch (aov) {
+  case 1: // World normal.
+    return value * 0.5 + 0.5;
+  case 2: // View depth.
+    return vec3(1 / (1 + max(value.x, 0)));
+  case 3: // UV coordinates, repeating.
+    return vec3(fract(value.xy), 0);
+  case 4: // Object ID.
+  case 5: // Material ID.
+    return idColor(uint(value.x));
+  default: // Albedo.
+    return colorSpace == 1 ? acesCgToSrgb * value : value;
+  }
+}
+
+// Whether `pos`, relative to the top-left of the text, is covered by `value`.
+bool hudNumber(ivec2 pos, uint value) {
+  ivec2 cell = pos / hudScale;
+  if (pos.x < 0 || pos.y < 0 || cell.y >= 5 || cell.x % 4 == 3) {
+    return false;
+  }
+
+  uint digits = 1;
+  for (uint tmp = value / 10; tmp > 0; tmp /= 10) {
+    digits++;
+  }
+  uint charIndex = uint(cell.x / 4);
+  if (charIndex >= digits) {
+    return false;
+  }
+
+  uint digit = value;
+  for (uint i = charIndex + 1; i < digits; i++) {
+    digit /= 10;
+  }
+  uint bit = 14 - uint(cell.y * 3 + cell.x % 4);
+  return ((hudFont[digit % 10] >> bit) & 1) != 0;
+}
+
 void main() {
   ivec2 pixelCoords = ivec2(gl_FragCoord.xy);
-  vec4 baseColor = imageLoad(img, pixelCoords) / frameCounter;
+  // The fourth channel holds the number of samples for this pixel.
+  vec4 texel = imageLoad(img, pixelCoords);
+  vec4 baseColor = vec4(texel.xyz / max(texel.w, 1), 1);
+  if (colorSpace == 1) {
+    baseColor.xyz = acesCgToSrgb * baseColor.xyz;
+  }
+  baseColor.xyz *= exposure;
   // outColor = baseColor / (baseColor + vec4(1));
   // outColor = log(baseColor + vec4(1));
   outColor = vec4(aces(baseColor.xyz), 0);
   // outColor = baseColor;
+
+  if (aov != 0) {
+    // Output variables are shown as they are, without exposure or tone mapping.
+    outColor = vec4(showAov(texel.xyz), 0);
+  }
+
+  if (signedDisplay != 0) {
+    // Positive luminance in orange, negative in blue.
+    float lum = dot(baseColor.xyz, vec3(0.2126, 0.7152, 0.0722));
+    vec3 hue = lum >= 0 ? vec3(1, 0.5, 0) : vec3(0, 0.5, 1);
+    outColor = vec4(hue * aces(vec3(abs(lum))), 0);
+  }
+
+  if (costBlend > 0) {
+    // The cost is traced at the render resolution, which may be below the window's.
+    ivec2 costCoords = pixelCoords * imageSize(costImg) / imageSize(img);
+    vec4 cost = imageLoad(costImg, costCoords);
+    vec3 heat = heatmap(cost.x / max(cost.w, 1) / costScale);
+    outColor.xyz = mix(outColor.xyz, heat, costBlend);
+  }
+
+  if (showHud != 0) {
+    // Sample count on the first line, FPS on the second.
+    ivec2 hudPos = pixelCoords - ivec2(2 * hudScale);
+    if (hudNumber(hudPos, frameCounter) ||
+        hudNumber(hudPos - ivec2(0, 6 * hudScale), uint(fps))) {
+      outColor = vec4(1);
+    } else if (hudPos.x >= -hudScale && hudPos.y >= -hudScale &&
+               hudPos.x < 40 * hudScale && hudPos.y < 12 * hudScale) {
+      outColor.xyz *= 0.25;
+    }
+  }
 }
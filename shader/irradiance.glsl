@@ -0,0 +1,57 @@
+#version 450 core
+
+// Convolves the environment cubemap into a diffuse irradiance cubemap:
+// each texel holds the cosine-weighted average radiance around its direction.
+
+layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+layout(binding = 0) uniform samplerCube envMap;
+layout(binding = 1, rgba16f) uniform writeonly image2DArray irradiance;
+
+// Number of directions sampled for each texel.
+const uint sampleCount = 1024;
+
+// Direction through the center of a cubemap texel, in the cubemap's own space.
+vec3 texelDirection(uvec3 texel, uint size) {
+  vec2 st = (vec2(texel.xy) + 0.5) / float(size) * 2 - 1;
+  switch (texel.z) {
+  case 0:
+    return normalize(vec3(1, -st.y, -st.x));
+  case 1:
+    return normalize(vec3(-1, -st.y, st.x));
+  case 2:
+    return normalize(vec3(st.x, 1, st.y));
+  case 3:
+    return normalize(vec3(st.x, -1, -st.y));
+  case 4:
+    return normalize(vec3(st.x, -st.y, 1));
+  default:
+    return normalize(vec3(-st.x, -st.y, -1));
+  }
+}
+
+void main() {
+  uint size = imageSize(irradiance).x;
+  if (gl_GlobalInvocationID.x >= size || gl_GlobalInvocationID.y >= size) {
+    return;
+  }
+  vec3 normal = texelDirection(gl_GlobalInvocationID, size);
+  vec3 tangent =
+      normalize(cross(abs(normal.x) > 0.9 ? vec3(0, 1, 0) : vec3(1, 0, 0), normal));
+  vec3 bitangent = cross(normal, tangent);
+
+  // Cosine-weighted directions from a Fibonacci spiral over the hemisphere.
+  vec3 sum = vec3(0);
+  for (uint i = 0; i < sampleCount; i++) {
+    float u = (float(i) + 0.5) / float(sampleCount);
+    float phi = 2.399963229728653 * float(i);
+    float sinTheta = sqrt(u);
+    float cosTheta = sqrt(1 - u);
+    vec3 dir = tangent * (sinTheta * cos(phi)) +
+               bitangent * (sinTheta * sin(phi)) + normal * cosTheta;
+    sum += textureLod(envMap, dir, 0).rgb;
+  }
+
+  imageStore(irradiance, ivec3(gl_GlobalInvocationID),
+             vec4(sum / float(sampleCount), 1));
+}
@@ -0,0 +1,49 @@
+#version 450 core
+
+// Format of the ray-tracing images; the 16-bit fallback is compiled with -DRT_FORMAT=rgba16f.
+#ifndef RT_FORMAT
+#define RT_FORMAT rgba32f
+#endif
+
+// Reduces the accumulated image to luminance statistics for auto exposure and firefly detection.
+// Each workgroup reduces a 16x16 tile, one pixel per invocation, to a partial result;
+// the partials are combined on the CPU.
+
+layout(local_size_x = 16, local_size_y = 16, local_size_z = 1) in;
+
+// Accumulated samples, with the sample count in the fourth channel.
+layout(binding = 0, RT_FORMAT) uniform readonly image2D img;
+// Per workgroup, numbered row by row: the sum of the log luminance, the number of pixels,
+// the sum of the luminance and the maximum luminance.
+layout(binding = 1, std430) buffer ResultBuffer { vec4 partials[]; };
+
+// Luminance below which pixels count as black, keeping the logarithm finite.
+const float minLuminance = 1e-4;
+
+shared vec4 partial[256];
+
+void main() {
+  ivec2 pos = ivec2(gl_GlobalInvocationID.xy);
+  vec4 stats = vec4(0);
+  if (all(lessThan(pos, imageSize(img)))) {
+    vec4 texel = imageLoad(img, pos);
+    if (texel.w > 0) {
+      float lum = max(dot(texel.xyz / texel.w, vec3(0.2126, 0.7152, 0.0722)), 0);
+      stats = vec4(log(max(lum, minLuminance)), 1, lum, lum);
+    }
+  }
+
+  uint index = gl_LocalInvocationIndex;
+  partial[index] = stats;
+  for (uint offset = 128; offset > 0; offset /= 2) {
+    barrier();
+    if (index < offset) {
+      vec4 other = partial[index + offset];
+      partial[index].xyz += other.xyz;
+      partial[index].w = max(partial[index].w, other.w);
+    }
+  }
+  if (index == 0) {
+    partials[gl_WorkGroupID.y * gl_NumWorkGroups.x + gl_WorkGroupID.x] = partial[0];
+  }
+}
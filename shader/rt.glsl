@@ -1,5 +1,10 @@
 #version 450 core
 
+// Format of the ray-tracing images; the 16-bit fallback is compiled with -DRT_FORMAT=rgba16f.
+#ifndef RT_FORMAT
+#define RT_FORMAT rgba32f
+#endif
+
 /* ==== BUFFER DEFINITIONS ==== */
 
 struct Transform {
@@ -11,9 +16,23 @@ struct PhysProp {
   float ior;
   float opacity;
   float roughness;
-  uint _padding0;
+  // Checkerboard cell size, or 0 if there is no checkerboard.
+  float checkerScale;
   vec4 color;
+  // Emission color, with the strength in the fourth component.
   vec4 emission;
+  // Color of the alternate checkerboard cells.
+  vec4 checkerColor;
+  // Subsurface scattering color, with the mean free path in the fourth component; 0 disables it.
+  vec4 scatter;
+  // Alpha below which rays pass through; 0 disables alpha testing.
+  float alphaCutoff;
+  // Index of the alpha texture, or uint(-1) if there is none.
+  uint alphaTexture;
+  // Whether the object only lights the scene and is invisible to camera rays and reflections.
+  uint lightOnly;
+  // Whether emission leaves both sides of the surface, or only the side the normal points to.
+  uint emitBothSides;
 };
 
 struct Object {
@@ -21,8 +40,12 @@ struct Object {
   PhysProp physProp;
   uint type;
   uint mesh;
-  uint _padding1;
-  uint _padding2;
+  uint materialOffset;
+  uint materialCount;
+  // Whether rays ignore triangles facing away from them.
+  uint cullBackfaces;
+  // Kinds of rays that see the object, see the `ray*` constants.
+  uint visibilityMask;
 };
 
 struct Mesh {
@@ -33,15 +56,27 @@ struct Mesh {
   uint normOffset;
   uint vcolOffset;
   uint uvOffset;
+  uint triMatOffset;
+  // Index of the next coarser LOD, or uint(-1) if none.
+  uint lodNext;
+};
+
+struct Sun {
+  vec4 color;
+  // Unit vector pointing at the sun, with the dot product threshold in w.
+  vec4 direction;
 };
 
+// Must match `Skybox::MAX_SUNS`.
+const uint maxSuns = 4;
+
 struct Skybox {
   vec4 groundColor;
   vec4 horizonColor;
   vec4 skyColor;
-  vec4 sunColor;
-  vec4 sunDirection;
-  float sunRadius;
+  Sun suns[maxSuns];
+  uint sunCount;
+  uint useCubemap;
 };
 
 struct Bvh {
@@ -80,76 +115,181 @@ struct HitInfo {
   uint obj;
   PhysProp physProp;
   bool isEntry;
+  // Whether the ray hit the side the geometric normal points to.
+  bool frontFace;
+  // Hit position in object space.
+  vec3 localPos;
+  // Texture coordinates.
+  vec2 uv;
+  // Index into the object's material table, or uint(-1) if it uses its own material.
+  uint material;
+  // World-space distance to the closest edge of the mesh triangle hit; only set for the wireframe.
+  float edgeDist;
+  // World-space gradients of `uv.x` and `uv.y` along the surface, for texture LOD; zero if unknown.
+  vec3 uvGradU;
+  vec3 uvGradV;
 };
 
 /* ==== LAYOUT DEFINITIONS ==== */
 
 layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
 
-layout(binding = 0, rgba32f) uniform image2D img;
+layout(binding = 0, RT_FORMAT) uniform image2D img;
 layout(binding = 1, std430) buffer SkyboxBuffer { Skybox skybox; };
+layout(binding = 15) uniform samplerCube skyCubemap;
+// Cosine-weighted average of `skyCubemap` around each direction.
+layout(binding = 18) uniform samplerCube skyIrradiance;
 layout(binding = 2, std430) buffer ObjectBuffer { Object objects[]; };
 layout(binding = 3, std430) buffer MeshBuffer { Mesh meshes[]; };
 layout(binding = 4, std430) buffer TriBuffer { uint tris[]; };
 layout(binding = 5, std430) buffer VertBuffer { vec3 verts[]; };
 layout(binding = 6, std430) buffer NormBuffer { vec3 norms[]; };
 layout(binding = 7, std430) buffer VcolBuffer { vec4 vcols[]; };
-// layout(binding = 8, std430) buffer UvBuffer { vec2 uvs[]; };
+layout(binding = 8, std430) buffer UvBuffer { vec2 uvs[]; };
 layout(binding = 9, std430) buffer BvhBuffer { Bvh bvh[]; };
+layout(binding = 10, RT_FORMAT) uniform image2D varImg;
+layout(binding = 11, std430) buffer TriMaterialBuffer { uint triMaterials[]; };
+layout(binding = 12, std430) buffer MaterialBuffer { PhysProp materials[]; };
+layout(binding = 13, std430) buffer TlasBuffer { Bvh tlas[]; };
+layout(binding = 14, std430) buffer InstanceBuffer { uint instances[]; };
+// Texture count, texture offsets, then per texture its size and alpha values packed four per word.
+layout(binding = 16, std430) buffer AlphaMaskBuffer { uint alphaMasks[]; };
+// Sum of the traversal cost per sample in the first channel and the sample count in the fourth.
+layout(binding = 17, RT_FORMAT) uniform image2D costImg;
+// Light deposited on each pixel by caustics photons since its last sample, as fixed-point RGB.
+layout(binding = 19, std430) buffer CausticBuffer { uint caustics[]; };
+
+// Must match `MAX_CLIP_PLANES`.
+const uint maxClipPlanes = 3;
+
+// Mixed into the random numbers, so renders with different seeds can be merged; set with `--seed`.
+layout(constant_id = 0) const uint rngSeed = 0;
 
 layout(push_constant, std430) uniform ParamPC {
+  // Region of the image to render.
+  uvec2 regionOffset;
+  uvec2 regionSize;
   mat4 camMatrix;
   float camVFov;
   uint frameCounter;
   uint maxBounce;
   uint rayCount;
+  float varianceThreshold;
+  // Whether to add to the previous samples or only keep the latest one.
+  uint accumulate;
+  // Factor applied to the previous samples before adding new ones.
+  float historyScale;
+  // Minimum weight of a new sample for exponential moving averaging; 0 disables it.
+  float historyAlpha;
+  // Width / height of the view; 0 uses the image's aspect ratio.
+  float aspect;
+  // Eye separation for side-by-side stereo; 0 renders a single view.
+  float stereo;
+  // Camera distance at which meshes switch to their first LOD, doubling for each further LOD; 0 disables LODs.
+  float lodDistance;
+  uint clipPlaneCount;
+  // Camera rays ignore geometry where `dot(plane.xyz, pos) + plane.w < 0`.
+  vec4 clipPlanes[maxClipPlanes];
+  // Channel to write instead of a sample, see `Aov`; 0 renders the beauty pass.
+  uint aov;
+  // Whether to draw the edges of mesh triangles seen by camera rays.
+  uint wireframe;
+  // Whether the render region is projected as a view of its own instead of its part of the image.
+  uint regionIsView;
+  // Whether photons traced from the suns deliver the caustics, instead of camera paths finding the sun.
+  uint caustics;
+  // Wireframe color, with its opacity over the shaded surface in w.
+  vec4 wireframeColor;
+  // Density of the fog scattering sunlight into camera rays; 0 disables god rays.
+  float godRayDensity;
+  // Number of shadow-tested steps along each camera ray segment.
+  uint godRaySteps;
+  // Maximum luminance of light picked up after the first bounce; 0 disables clamping.
+  float bounceClamp;
+  // Factor the clamp is multiplied by for each further bounce.
+  float bounceClampFalloff;
   uint objectCount;
+  // Whether to render objects as their world-space bounding boxes.
+  uint boundsPreview;
+  // Whether to write the ID of the object hit by the primary ray instead of a sample.
+  uint objectIds;
+  // Bitmask of object types that rays don't intersect, for profiling.
+  uint disabledTypes;
+  // Whether to record the traversal cost of each pixel.
+  uint traceCost;
+  // Whether negative emission subtracts light instead of being clamped to 0, for debugging.
+  uint negativeEmission;
+  // Index of the only object rays intersect, or uint(-1) to intersect all of them.
+  uint soloObject;
+  // Whether the workgroups are numbered along a Z-order curve over the tiles instead of one per tile.
+  uint mortonOrder;
 };
 
+// Number of BVH nodes visited and primitives tested by the current invocation.
+uint traversalCost = 0;
+
+// Change of the camera ray's direction from one pixel to the next along x and y, for texture LOD.
+vec3 cameraDdx = vec3(0);
+vec3 cameraDdy = vec3(0);
+
+// Number of samples every pixel gets before adaptive sampling may skip it.
+const uint adaptiveWarmup = 16;
+
 /* ==== RAY INTERSECTION TESTS ==== */
 
+// Transform an object-space normal to world space.
+// Normals transform by the inverse transpose so they stay perpendicular under non-uniform scale.
+vec3 normalToWorld(uint obj, vec3 normal) {
+  return normalize(transpose(mat3(objects[obj].transform.invMatrix)) * normal);
+}
+
 HitInfo rayTestSphere(Ray ray, uint obj) {
   Ray globalRay = ray;
   ray.pos = (objects[obj].transform.invMatrix * vec4(ray.pos, 1)).xyz;
   ray.normal =
       normalize((objects[obj].transform.invMatrix * vec4(ray.normal, 0)).xyz);
 
+  // Numerically stable quadratic; see `Ray::intersect_unit_sphere`.
+  // The discriminant is taken from the point closest to the center instead of
+  // `a * a - raySqrMag + 1`, which cancels badly for distant rays.
   float a = -dot(ray.normal, ray.pos);
   float raySqrMag = dot(ray.pos, ray.pos);
-  float b = a * a - raySqrMag + 1;
+  vec3 closest = ray.pos + a * ray.normal;
+  float b = 1 - dot(closest, closest);
   HitInfo hit;
   hit.obj = obj;
   hit.physProp = objects[obj].physProp;
+  hit.material = uint(-1);
 
-  if (b < 0.0) {
+  // The larger root is computed without cancellation, the other from the product of the roots.
+  float q = a < 0 ? a - sqrt(max(b, 0)) : a + sqrt(max(b, 0));
+  if (b < 0.0 || q == 0.0) {
     hit.dist = 1.0 / 0.0;
     return hit;
   }
-
-  if (b < 0.00001) {
-    if (a > 0.000001) {
-      hit.dist = a;
-    } else {
-      hit.dist = 1.0 / 0.0;
-      return hit;
-    }
+  float dist0 = q;
+  float dist1 = (raySqrMag - 1) / q;
+  float nearDist = min(dist0, dist1);
+  float farDist = max(dist0, dist1);
+  if (nearDist > 0.00001) {
+    hit.dist = nearDist;
+  } else if (farDist > 0.00001) {
+    hit.dist = farDist;
   } else {
-    float dist0 = a + sqrt(b);
-    float dist1 = a - sqrt(b);
-    if ((dist1 <= 0.00001 || dist0 < dist1) && dist0 > 0.00001) {
-      hit.dist = dist0;
-    } else if (dist1 > 0.00001) {
-      hit.dist = dist1;
-    } else {
-      hit.dist = 1.0 / 0.0;
-      return hit;
-    }
+    hit.dist = 1.0 / 0.0;
+    return hit;
   }
 
   vec3 pos = ray.pos + ray.normal * hit.dist;
+  hit.localPos = pos;
+  hit.uv = vec2(atan(pos.y, pos.x) / 6.283185307179586 + 0.5,
+                acos(clamp(pos.z, -1, 1)) / 3.141592653589793);
+  hit.uvGradU = vec3(0);
+  hit.uvGradV = vec3(0);
   hit.pos = (objects[obj].transform.matrix * vec4(pos, 1)).xyz;
-  hit.normal = normalize((objects[obj].transform.matrix * vec4(pos, 0)).xyz);
+  hit.normal = normalToWorld(obj, pos);
   hit.isEntry = raySqrMag > 1;
+  hit.frontFace = hit.isEntry;
   hit.dist = length(globalRay.pos - hit.pos);
 
   return hit;
@@ -164,6 +304,7 @@ HitInfo rayTestPlane(Ray ray, uint obj) {
   HitInfo hit;
   hit.obj = obj;
   hit.physProp = objects[obj].physProp;
+  hit.material = uint(-1);
   hit.dist = -ray.pos.z / ray.normal.z;
 
   if (abs(ray.normal.z) < 0.00001 || hit.dist < 0.00001) {
@@ -176,30 +317,40 @@ HitInfo rayTestPlane(Ray ray, uint obj) {
     return hit;
   }
 
+  // The hit lies exactly on the plane, so avoid Z jitter in the pattern.
+  hit.localPos = vec3(pos.xy, 0);
+  hit.uv = pos.xy * 0.5 + 0.5;
+  // The UVs are linear in object space, so their gradients are rows of the inverse transform.
+  mat3 uvToWorld = transpose(mat3(objects[obj].transform.invMatrix));
+  hit.uvGradU = uvToWorld * vec3(0.5, 0, 0);
+  hit.uvGradV = uvToWorld * vec3(0, 0.5, 0);
   hit.pos = (objects[obj].transform.matrix * vec4(pos, 1)).xyz;
-  hit.normal = normalize(
-      (objects[obj].transform.matrix * vec4(0, 0, sign(ray.pos.z), 1)).xyz);
+  hit.normal = normalToWorld(obj, vec3(0, 0, sign(ray.pos.z)));
   hit.isEntry = true;
+  // Planes face +Z, whichever side the shading normal is flipped to.
+  hit.frontFace = ray.pos.z > 0;
   hit.dist = length(globalRay.pos - hit.pos);
 
   return hit;
 }
 
-TriHitInfo rayTestTri(Ray ray, uint tri) {
+TriHitInfo rayTestTri(Ray ray, uint tri, uint vertOffset, bool cullBackfaces) {
+  traversalCost++;
   TriHitInfo hit;
   hit.tri = tri;
   hit.dist = 1.0 / 0.0;
 
-  vec3 a = verts[tris[tri * 3]];
-  vec3 b = verts[tris[tri * 3 + 1]];
-  vec3 c = verts[tris[tri * 3 + 2]];
+  vec3 a = verts[vertOffset + tris[tri * 3]];
+  vec3 b = verts[vertOffset + tris[tri * 3 + 1]];
+  vec3 c = verts[vertOffset + tris[tri * 3 + 2]];
 
   vec3 ab = b - a;
   vec3 ac = c - a;
   vec3 pvec = cross(ray.normal, ac);
   float det = dot(ab, pvec);
 
-  if (abs(det) < 0.00001) {
+  // The determinant is negative for triangles facing away from the ray.
+  if ((cullBackfaces ? det : abs(det)) < 0.00001) {
     return hit;
   }
 
@@ -241,7 +392,7 @@ float rayTestCuboid(Ray ray, vec3 minPos, vec3 maxPos) {
   return dst;
 }
 
-TriHitInfo rayTestBvh(Ray ray, uint bvhOffset) {
+TriHitInfo rayTestBvh(Ray ray, uint bvhOffset, uint vertOffset, bool cullBackfaces) {
   TriHitInfo bestHit;
   bestHit.dist = 1.0 / 0.0;
 
@@ -251,12 +402,14 @@ TriHitInfo rayTestBvh(Ray ray, uint bvhOffset) {
 
   while (stackLen > 0) {
     stackLen--;
+    traversalCost++;
     Bvh node = bvh[stack[stackLen]];
 
     if (node.triCount != 0) {
       // Leaf node; test all triangles.
       for (uint i = 0; i < node.triCount; i++) {
-        TriHitInfo hit = rayTestTri(ray, node.children + i);
+        TriHitInfo hit =
+            rayTestTri(ray, node.children + i, vertOffset, cullBackfaces);
         if (hit.dist < bestHit.dist) {
           bestHit = hit;
         }
@@ -296,21 +449,38 @@ TriHitInfo rayTestBvh(Ray ray, uint bvhOffset) {
   return bestHit;
 }
 
+// Pick the mesh LOD of an object by its distance from the camera.
+// Every ray uses the same LOD for an instance, so bounces never see a different surface.
+uint selectLod(uint obj) {
+  uint index = objects[obj].mesh;
+  if (lodDistance <= 0) {
+    return index;
+  }
+  float dist = distance(camMatrix[3].xyz, objects[obj].transform.matrix[3].xyz);
+  for (float threshold = lodDistance;
+       dist >= threshold && meshes[index].lodNext != uint(-1); threshold *= 2) {
+    index = meshes[index].lodNext;
+  }
+  return index;
+}
+
 HitInfo rayTestMesh(Ray ray, uint obj) {
   Ray globalRay = ray;
   ray.pos = (objects[obj].transform.invMatrix * vec4(ray.pos, 1)).xyz;
   ray.normal =
       normalize((objects[obj].transform.invMatrix * vec4(ray.normal, 0)).xyz);
 
-  Mesh mesh = meshes[objects[obj].mesh];
+  Mesh mesh = meshes[selectLod(obj)];
+  bool cullBackfaces = objects[obj].cullBackfaces != 0;
 
   TriHitInfo bestHit;
   if (mesh.bvhOffset != uint(-1)) {
-    bestHit = rayTestBvh(ray, mesh.bvhOffset);
+    bestHit = rayTestBvh(ray, mesh.bvhOffset, mesh.vertOffset, cullBackfaces);
   } else {
     bestHit.dist = 1.0 / 0.0;
     for (uint i = 0; i < mesh.numTris; i++) {
-      TriHitInfo hit = rayTestTri(ray, mesh.triOffset + i);
+      TriHitInfo hit =
+          rayTestTri(ray, mesh.triOffset + i, mesh.vertOffset, cullBackfaces);
       if (hit.dist < bestHit.dist) {
         bestHit = hit;
       }
@@ -323,38 +493,92 @@ HitInfo rayTestMesh(Ray ray, uint obj) {
     return hit;
   }
   hit.pos = ray.pos + bestHit.dist * ray.normal;
+  hit.localPos = hit.pos;
+  hit.obj = obj;
   hit.physProp = objects[obj].physProp;
+  hit.material = uint(-1);
+
+  // Look up the triangle's material in the object's material table.
+  if (mesh.triMatOffset != uint(-1)) {
+    uint material = triMaterials[mesh.triMatOffset + bestHit.tri - mesh.triOffset];
+    if (material < objects[obj].materialCount) {
+      hit.physProp = materials[objects[obj].materialOffset + material];
+      hit.material = material;
+    }
+  }
 
   uint a = tris[bestHit.tri * 3];
   uint b = tris[bestHit.tri * 3 + 1];
   uint c = tris[bestHit.tri * 3 + 2];
 
   // Normalization happens later; doing it here is redundant.
+  vec3 vertA = verts[mesh.vertOffset + a];
+  vec3 faceNormal = cross(verts[mesh.vertOffset + b] - vertA,
+                          verts[mesh.vertOffset + c] - vertA);
+  hit.frontFace = dot(ray.normal, faceNormal) < 0;
   if (mesh.normOffset == uint(-1)) {
-    hit.normal = cross(verts[b] - verts[a], verts[c] - verts[a]);
+    hit.normal = faceNormal;
   } else {
-    hit.normal = (1 - bestHit.u - bestHit.v) * norms[a];
-    hit.normal += bestHit.u * norms[b];
-    hit.normal += bestHit.v * norms[c];
+    hit.normal = (1 - bestHit.u - bestHit.v) * norms[mesh.normOffset + a];
+    hit.normal += bestHit.u * norms[mesh.normOffset + b];
+    hit.normal += bestHit.v * norms[mesh.normOffset + c];
   }
   hit.isEntry = dot(ray.normal, hit.normal) < 0;
 
+  hit.uvGradU = vec3(0);
+  hit.uvGradV = vec3(0);
+  if (mesh.uvOffset != uint(-1)) {
+    vec2 uvA = uvs[mesh.uvOffset + a];
+    vec2 uvB = uvs[mesh.uvOffset + b];
+    vec2 uvC = uvs[mesh.uvOffset + c];
+    hit.uv = (1 - bestHit.u - bestHit.v) * uvA + bestHit.u * uvB + bestHit.v * uvC;
+
+    // Gradients of the interpolated UVs across the world-space triangle.
+    mat4 matrix = objects[obj].transform.matrix;
+    vec3 edge1 = (matrix * vec4(verts[mesh.vertOffset + b] - vertA, 0)).xyz;
+    vec3 edge2 = (matrix * vec4(verts[mesh.vertOffset + c] - vertA, 0)).xyz;
+    vec3 worldNormal = cross(edge1, edge2);
+    float sqrArea = dot(worldNormal, worldNormal);
+    if (sqrArea > 0) {
+      vec3 grad1 = cross(edge2, worldNormal) / sqrArea;
+      vec3 grad2 = cross(worldNormal, edge1) / sqrArea;
+      hit.uvGradU = (uvB.x - uvA.x) * grad1 + (uvC.x - uvA.x) * grad2;
+      hit.uvGradV = (uvB.y - uvA.y) * grad1 + (uvC.y - uvA.y) * grad2;
+    }
+  } else {
+    hit.uv = vec2(0);
+  }
+
   if (mesh.vcolOffset != uint(-1)) {
     vec4 vcol;
-    vcol = (1 - bestHit.u - bestHit.v) * vcols[a];
-    vcol += bestHit.u * vcols[b];
-    vcol += bestHit.v * vcols[c];
+    vcol = (1 - bestHit.u - bestHit.v) * vcols[mesh.vcolOffset + a];
+    vcol += bestHit.u * vcols[mesh.vcolOffset + b];
+    vcol += bestHit.v * vcols[mesh.vcolOffset + c];
     hit.physProp.color *= vcol;
   }
 
   hit.pos = (objects[obj].transform.matrix * vec4(hit.pos, 1)).xyz;
-  hit.normal = (objects[obj].transform.matrix * vec4(hit.normal, 0)).xyz;
-  hit.normal = normalize(hit.normal);
+  hit.normal = normalToWorld(obj, hit.normal);
   hit.dist = length(globalRay.pos - hit.pos);
+
+  // Each barycentric coordinate times the height over the opposite edge is the distance to it.
+  if (wireframe != 0) {
+    mat4 matrix = objects[obj].transform.matrix;
+    vec3 worldA = (matrix * vec4(verts[mesh.vertOffset + a], 1)).xyz;
+    vec3 worldB = (matrix * vec4(verts[mesh.vertOffset + b], 1)).xyz;
+    vec3 worldC = (matrix * vec4(verts[mesh.vertOffset + c], 1)).xyz;
+    float twiceArea = length(cross(worldB - worldA, worldC - worldA));
+    vec3 edgeDists = vec3(1 - bestHit.u - bestHit.v, bestHit.u, bestHit.v) *
+                     twiceArea /
+                     vec3(length(worldC - worldB), length(worldC - worldA),
+                          length(worldB - worldA));
+    hit.edgeDist = min(min(edgeDists.x, edgeDists.y), edgeDists.z);
+  }
   return hit;
 }
 
 HitInfo rayTestObject(Ray ray, uint obj) {
+  traversalCost++;
   switch (objects[obj].type) {
   case 0:
     return rayTestSphere(ray, obj);
@@ -365,14 +589,121 @@ HitInfo rayTestObject(Ray ray, uint obj) {
   }
 }
 
+// Intersect an object's world-space bounding box, for the bounding-box preview.
+HitInfo rayTestBounds(Ray ray, vec3 minPos, vec3 maxPos, uint obj) {
+  vec3 invDir = 1 / ray.normal;
+  vec3 tMin = (minPos - ray.pos) * invDir;
+  vec3 tMax = (maxPos - ray.pos) * invDir;
+  vec3 t1 = min(tMin, tMax);
+  vec3 t2 = max(tMin, tMax);
+  float tNear = max(max(t1.x, t1.y), t1.z);
+  float tFar = min(min(t2.x, t2.y), t2.z);
+
+  HitInfo hit;
+  hit.obj = obj;
+  hit.physProp = objects[obj].physProp;
+  hit.material = uint(-1);
+  hit.isEntry = tNear > 0.00001;
+  hit.frontFace = hit.isEntry;
+  hit.dist = hit.isEntry ? tNear : tFar;
+  if (tFar < tNear || hit.dist <= 0.00001) {
+    hit.dist = 1.0 / 0.0;
+    return hit;
+  }
+
+  // The face that was hit is on the axis that determined the distance.
+  vec3 t = hit.isEntry ? t1 : t2;
+  vec3 axis = vec3(equal(t, vec3(hit.dist)));
+  hit.normal = normalize(axis * sign(ray.normal) * (hit.isEntry ? -1 : 1));
+  hit.pos = ray.pos + ray.normal * hit.dist;
+  hit.localPos = hit.pos;
+  hit.uv = vec2(0);
+  hit.uvGradU = vec3(0);
+  hit.uvGradV = vec3(0);
+  return hit;
+}
+
+HitInfo rayTest(Ray ray);
+
+// Like `rayTest`, but ignoring geometry on the negative side of the clip planes.
+// The ray is clipped to the part on the positive side of all planes, so the
+// closest hit within that part is found even if nearer geometry was cut away.
+HitInfo rayTestClipped(Ray ray) {
+  HitInfo miss;
+  miss.dist = 1.0 / 0.0;
+  float tMin = 0;
+  float tMax = 1.0 / 0.0;
+  for (uint i = 0; i < min(clipPlaneCount, maxClipPlanes); i++) {
+    float side = dot(clipPlanes[i].xyz, ray.pos) + clipPlanes[i].w;
+    float speed = dot(clipPlanes[i].xyz, ray.normal);
+    if (side < 0) {
+      if (speed <= 0) {
+        return miss;
+      }
+      tMin = max(tMin, -side / speed);
+    } else if (speed < 0) {
+      tMax = min(tMax, -side / speed);
+    }
+  }
+  if (tMin > tMax) {
+    return miss;
+  }
+
+  Ray clipped = ray;
+  clipped.pos += ray.normal * tMin;
+  HitInfo hit = rayTest(clipped);
+  if (hit.dist + tMin > tMax) {
+    return miss;
+  }
+  hit.dist += tMin;
+  return hit;
+}
+
 HitInfo rayTest(Ray ray) {
   HitInfo bestHit;
   bestHit.dist = 1.0 / 0.0;
+  // Empty scenes only show the skybox; their buffers hold placeholders.
+  if (objectCount == 0) {
+    return bestHit;
+  }
+
+  // Traverse the top-level BVH; objects transform the ray into their own space.
+  uint stack[32];
+  uint stackLen = 1;
+  stack[0] = 0;
 
-  for (uint i = 0; i < objectCount; i++) {
-    HitInfo hit = rayTestObject(ray, i);
-    if (hit.dist < bestHit.dist) {
-      bestHit = hit;
+  while (stackLen > 0) {
+    stackLen--;
+    traversalCost++;
+    Bvh node = tlas[stack[stackLen]];
+
+    // Skip nodes that are missed or further away than the best hit so far.
+    float dist = rayTestCuboid(ray, node.minPos.xyz, node.maxPos.xyz);
+    if (isinf(dist) || dist > bestHit.dist) {
+      continue;
+    }
+
+    if (node.triCount != 0) {
+      // Leaf node; test all objects.
+      for (uint i = 0; i < node.triCount; i++) {
+        uint obj = instances[node.children + i];
+        if ((disabledTypes & (1u << objects[obj].type)) != 0 ||
+            (soloObject != uint(-1) && obj != soloObject)) {
+          continue;
+        }
+        HitInfo hit =
+            boundsPreview != 0
+                ? rayTestBounds(ray, node.minPos.xyz, node.maxPos.xyz, obj)
+                : rayTestObject(ray, obj);
+        if (hit.dist < bestHit.dist) {
+          bestHit = hit;
+        }
+      }
+    } else {
+      stack[stackLen] = node.children;
+      stackLen++;
+      stack[stackLen] = node.children + 1;
+      stackLen++;
     }
   }
 
@@ -392,13 +723,15 @@ uint splitmix32(inout uint state) {
   return z;
 }
 
+// Uniform random float in [0, 1); the top 24 bits fit a float exactly.
 float randFloat(inout uint rngState) {
-  return splitmix32(rngState) / float(uint(-1));
+  return float(splitmix32(rngState) >> 8) / 16777216.0;
 }
 
 float randNormal(inout uint rngState) {
   float t = 6.283185307179586 * randFloat(rngState);
-  float r = sqrt(log(randFloat(rngState)) * -2);
+  // 1 - randFloat is in (0, 1], so the log stays finite.
+  float r = sqrt(log(1 - randFloat(rngState)) * -2);
   return r * cos(t);
 }
 
@@ -416,58 +749,399 @@ vec3 randHemisphereVec(inout uint rngState, vec3 relativeTo) {
   }
 }
 
+// Sample a GGX-distributed microfacet normal around `normal`.
+vec3 randGgxNormal(inout uint rngState, vec3 normal, float roughness) {
+  float alpha = roughness * roughness;
+  float u = randFloat(rngState);
+  float phi = 6.283185307179586 * randFloat(rngState);
+  float cosTheta = sqrt((1 - u) / (1 + (alpha * alpha - 1) * u));
+  float sinTheta = sqrt(1 - cosTheta * cosTheta);
+
+  // Orthonormal basis around the normal.
+  vec3 up = abs(normal.x) > 0.5 ? vec3(0, 1, 0) : vec3(1, 0, 0);
+  vec3 tangent = normalize(cross(normal, up));
+  vec3 bitangent = cross(normal, tangent);
+  return normalize(tangent * (sinTheta * cos(phi)) +
+                   bitangent * (sinTheta * sin(phi)) + normal * cosTheta);
+}
+
+// Look up the alpha of a texture with nearest filtering, repeating it outside [0, 1].
+// `uvDx` and `uvDy` are the UV footprint of a pixel, selecting the nearest mip level; zero uses the full resolution.
+float alphaMaskLookup(uint tex, vec2 uv, vec2 uvDx, vec2 uvDy) {
+  if (tex >= alphaMasks[0]) {
+    return 1;
+  }
+  uint offset = alphaMasks[1 + tex];
+  vec2 fullSize = vec2(alphaMasks[offset], alphaMasks[offset + 1]);
+  float footprint = max(length(uvDx * fullSize), length(uvDy * fullSize));
+  uint level = min(uint(round(log2(max(footprint, 1)))), alphaMasks[offset + 2] - 1);
+  uvec2 size = max(uvec2(fullSize) >> level, uvec2(1));
+  uvec2 texel = min(uvec2(fract(uv) * vec2(size)), size - 1);
+  uint index = texel.y * size.x + texel.x;
+  uint word = alphaMasks[alphaMasks[offset + 3 + level] + index / 4];
+  return float((word >> (8 * (index % 4))) & 0xff) / 255.0;
+}
+
+// Kinds of rays for `Object.visibilityMask`; must match `RayVisibility`.
+const uint rayCamera = 1;
+const uint rayReflection = 2;
+const uint rayRefraction = 4;
+const uint rayDiffuse = 8;
+
+// Maximum number of cutout or hidden surfaces a ray may pass through per bounce.
+const uint maxPassThroughs = 32;
+
+// Evaluate the checkerboard pattern into the hit's color.
+void applyChecker(inout HitInfo hit) {
+  if (hit.physProp.checkerScale > 0) {
+    ivec3 cell = ivec3(floor(hit.localPos / hit.physProp.checkerScale));
+    if (((cell.x + cell.y + cell.z) & 1) != 0) {
+      hit.physProp.color = hit.physProp.checkerColor;
+    }
+  }
+}
+
+// Scale down light a path picks up after `depth` bounces whose luminance exceeds the clamp for that depth.
+vec3 clampBounce(vec3 light, uint depth) {
+  if (bounceClamp <= 0 || depth == 0) {
+    return light;
+  }
+  float maxLum = bounceClamp * pow(bounceClampFalloff, float(depth - 1));
+  float lum = dot(light, vec3(0.2126, 0.7152, 0.0722));
+  return lum > maxLum ? light * (maxLum / lum) : light;
+}
+
+// Move a ray differential of the position `dP` and direction `dD` along the ray to a surface `dist` away,
+// keeping it in the surface's plane (Igehy, "Tracing Ray Differentials").
+vec3 transferDifferential(vec3 dP, vec3 dD, vec3 dir, float dist, vec3 normal) {
+  dP += dist * dD;
+  float denom = dot(dir, normal);
+  // Grazing hits would have an unbounded footprint; keep the unprojected one.
+  if (abs(denom) < 1e-6) {
+    return dP;
+  }
+  return dP - dot(dP, normal) / denom * dir;
+}
+
+// Transmittance of the fog at which god rays stop marching, limiting rays that hit nothing.
+const float godRayCutoff = 0.01;
+
+// March `dist` along a camera ray through the god-ray fog, shadow testing each sun at every step.
+// Returns the scattered sunlight and multiplies `transmittance` by the fraction of light passing through.
+vec3 godRays(Ray ray, float dist, inout uint rngState, inout float transmittance) {
+  float marchDist = min(dist, -log(godRayCutoff) / godRayDensity);
+  float stepSize = marchDist / float(godRaySteps);
+  float stepTransmittance = exp(-godRayDensity * stepSize);
+  float jitter = randFloat(rngState);
+  vec3 light = vec3(0);
+  for (uint i = 0; i < godRaySteps; i++) {
+    Ray shadowRay;
+    shadowRay.pos = ray.pos + ray.normal * ((float(i) + jitter) * stepSize);
+    for (uint j = 0; j < min(skybox.sunCount, maxSuns); j++) {
+      shadowRay.normal = skybox.suns[j].direction.xyz;
+      if (isinf(rayTest(shadowRay).dist)) {
+        light += transmittance * godRayDensity * stepSize * skybox.suns[j].color.xyz;
+      }
+    }
+    transmittance *= stepTransmittance;
+  }
+  return light;
+}
+
+// Fixed-point scale of the caustics buffer, which photons add to atomically.
+const float causticScale = 65536.0;
+
+// Whether caustics come from photons, which only supports a single view of the whole image.
+bool photonCaustics() {
+  return caustics != 0 && stereo == 0 && regionIsView == 0;
+}
+
+// Whether a surface is a fully diffuse, opaque receiver of caustics photons.
+bool isCausticReceiver(PhysProp prop) {
+  return prop.roughness >= 1 && prop.opacity >= 1;
+}
+
+// Add the light a photon of `power` scatters off a diffuse surface to the pixel the camera sees it in.
+void depositCaustic(vec3 pos, vec3 normal, vec3 power, inout uint rngState) {
+  vec3 camPos = (camMatrix * vec4(0, 0, 0, 1)).xyz;
+  vec3 toCam = camPos - pos;
+  float dist = length(toCam);
+  float cosSurface = dot(normal, toCam) / dist;
+  vec3 view = (inverse(camMatrix) * vec4(-toCam, 0)).xyz;
+  if (cosSurface <= 0 || view.z <= 0) {
+    return;
+  }
+
+  // Project into the image like `main` generates the camera rays.
+  ivec2 imgSize = imageSize(img);
+  vec2 halfSize = 0.5 * vec2(imgSize);
+  float aspectRatio = aspect > 0 ? aspect : halfSize.x / halfSize.y;
+  vec2 viewPos = view.xy / view.z / (vec2(aspectRatio, 1) * camVFov);
+  ivec2 pixel = ivec2(floor(viewPos * halfSize + halfSize));
+  if (any(lessThan(pixel, ivec2(0))) || any(greaterThanEqual(pixel, imgSize))) {
+    return;
+  }
+
+  // The camera must see this very point, respecting the clip planes.
+  Ray camRay;
+  camRay.pos = camPos;
+  camRay.normal = -toCam / dist;
+  if (abs(rayTestClipped(camRay).dist - dist) > 0.001 * dist) {
+    return;
+  }
+
+  // Convert the scattered power to the pixel's mean radiance through the camera's importance.
+  float cosCam = view.z / length(view);
+  float pixelArea = aspectRatio * camVFov * camVFov / (halfSize.x * halfSize.y);
+  vec3 radiance = power * cosSurface / (pixelArea * cosCam * cosCam * cosCam * dist * dist);
+  uint index = 3 * uint(pixel.y * imgSize.x + pixel.x);
+  for (uint i = 0; i < 3; i++) {
+    // Random rounding keeps faint contributions on average; the limit keeps the sums from overflowing.
+    float value = radiance[i] * causticScale + randFloat(rngState);
+    atomicAdd(caustics[index + i], uint(min(value, 1e9)));
+  }
+}
+
+// Trace a photon from a random sun through mirror-like and transparent surfaces, depositing it on
+// the first diffuse surface it reaches after at least one of them. `photonCount` photons are traced per frame.
+void traceCausticPhoton(inout uint rngState, float photonCount) {
+  uint sunCount = min(skybox.sunCount, maxSuns);
+  if (sunCount == 0 || objectCount == 0) {
+    return;
+  }
+  Sun sun = skybox.suns[min(uint(randFloat(rngState) * float(sunCount)), sunCount - 1)];
+
+  // Emit from a disc facing the sun that covers the scene's bounds.
+  vec3 center = 0.5 * (tlas[0].minPos.xyz + tlas[0].maxPos.xyz);
+  float radius = 0.5 * length(tlas[0].maxPos.xyz - tlas[0].minPos.xyz);
+  vec3 tangent = normalize(cross(sun.direction.xyz,
+                                 abs(sun.direction.y) < 0.9 ? vec3(0, 1, 0) : vec3(1, 0, 0)));
+  vec3 bitangent = cross(sun.direction.xyz, tangent);
+  float discRadius = radius * sqrt(randFloat(rngState));
+  float angle = 6.283185307179586 * randFloat(rngState);
+  Ray ray;
+  ray.pos = center + sun.direction.xyz * 2 * radius +
+            (tangent * cos(angle) + bitangent * sin(angle)) * discRadius;
+  ray.normal = -sun.direction.xyz;
+
+  // The skybox fades each sun out linearly towards the edge of its disc, halving its mean radiance.
+  vec3 irradiance = sun.color.xyz * 3.141592653589793 * (1 - sun.direction.w);
+  vec3 power = irradiance * 3.141592653589793 * radius * radius * float(sunCount) / photonCount;
+
+  bool specular = false;
+  for (uint bounce = 0; bounce < maxBounce; bounce++) {
+    HitInfo hit = rayTest(ray);
+    if (isinf(hit.dist)) {
+      return;
+    }
+    applyChecker(hit);
+    vec3 normal = hit.isEntry ? hit.normal : -hit.normal;
+    if (isCausticReceiver(hit.physProp)) {
+      if (specular) {
+        depositCaustic(hit.pos, normal, power * hit.physProp.color.xyz / 3.141592653589793, rngState);
+      }
+      return;
+    }
+    // Glossy surfaces spread the light out too much to focus it; camera paths handle them.
+    if (hit.physProp.roughness >= 0.1) {
+      return;
+    }
+    specular = true;
+    power *= hit.physProp.color.xyz;
+
+    // Refract or reflect like `rayTrace` does off a smooth surface.
+    ray.pos = hit.pos;
+    vec3 reflected = ray.normal - 2 * dot(ray.normal, normal) * normal;
+    if (randFloat(rngState) >= hit.physProp.opacity) {
+      float ratio = hit.isEntry ? 1.0 / hit.physProp.ior : hit.physProp.ior;
+      float inDot = clamp(-dot(ray.normal, normal), -1, 1);
+      float det = 1 - ratio * ratio * (1 - inDot * inDot);
+      ray.normal = det >= 0
+          ? normalize(ray.normal * ratio + normal * (ratio * inDot - sqrt(det)))
+          : normalize(reflected);
+    } else {
+      ray.normal = normalize(reflected);
+    }
+  }
+}
+
 vec4 rayTrace(Ray ray, inout uint rngState) {
   uint bounceLeft = maxBounce;
+  uint passThroughs = 0;
+  // Whether the path so far only consists of the camera ray and mirror-like bounces.
+  bool specularPath = true;
+  // Whether the ray still comes straight from the camera, only passing through cutouts.
+  bool cameraRay = true;
+  // Kind of the current ray, for the objects' visibility masks.
+  uint rayType = rayCamera;
+  // Surface normal if the last bounce was fully diffuse, zero otherwise.
+  vec3 diffuseNormal = vec3(0);
+  // Scattering medium the ray is inside of, like `PhysProp.scatter`; 0 in the fourth component is none.
+  vec4 medium = vec4(0);
+  // Footprint of the pixel where the camera ray is, as the offsets to the neighboring pixels' rays.
+  vec3 dPdx = vec3(0);
+  vec3 dPdy = vec3(0);
+  // Mirror-like bounces since the camera ray hit a caustics receiver, or -1 if the path is not like that.
+  // Photons already deliver the sunlight at the end of such paths.
+  int causticBounces = -1;
 
   vec4 colMask = vec4(1);
   vec4 color = vec4(0);
 
   while (bounceLeft > 0 && colMask.x + colMask.y + colMask.z > 0.001) {
-    HitInfo hit = rayTest(ray);
+    HitInfo hit = cameraRay ? rayTestClipped(ray) : rayTest(ray);
     bounceLeft--;
 
+    // Sunlight scattered by the fog along the camera ray, up to whatever it hits.
+    if (cameraRay && godRayDensity > 0) {
+      float transmittance = 1;
+      color.xyz += colMask.xyz * godRays(ray, hit.dist, rngState, transmittance);
+      colMask.xyz *= transmittance;
+    }
+
+    // Random walk subsurface scattering: inside a scattering medium, the ray may scatter
+    // into a random direction before it reaches the surface it's heading for.
+    if (medium.w > 0) {
+      float scatterDist = -log(max(randFloat(rngState), 1e-7)) * medium.w;
+      if (scatterDist < hit.dist) {
+        ray.pos += ray.normal * scatterDist;
+        ray.normal = randUnitVec(rngState);
+        colMask.xyz *= medium.xyz;
+        causticBounces = -1;
+        continue;
+      }
+    }
+
     if (!isinf(hit.dist)) {
-      color += colMask * objects[hit.obj].physProp.emission;
-      colMask *= objects[hit.obj].physProp.color;
+      // Alpha-tested cutouts let the ray continue as if nothing was hit, and so do
+      // light-only objects for camera rays and their mirror-like reflections.
+      // There is no explicit light sampling, so diffuse bounces still pick up their light.
+      // Camera rays select the texture LOD by their ray differentials; other rays use the full resolution.
+      vec2 uvDx = vec2(0);
+      vec2 uvDy = vec2(0);
+      if (cameraRay) {
+        dPdx = transferDifferential(dPdx, cameraDdx, ray.normal, hit.dist, hit.normal);
+        dPdy = transferDifferential(dPdy, cameraDdy, ray.normal, hit.dist, hit.normal);
+        uvDx = vec2(dot(hit.uvGradU, dPdx), dot(hit.uvGradV, dPdx));
+        uvDy = vec2(dot(hit.uvGradU, dPdy), dot(hit.uvGradV, dPdy));
+      }
+      bool cutout = hit.physProp.alphaTexture != uint(-1) &&
+                    alphaMaskLookup(hit.physProp.alphaTexture, hit.uv, uvDx, uvDy) <
+                        hit.physProp.alphaCutoff;
+      bool hidden = (hit.physProp.lightOnly != 0 && specularPath) ||
+                    (objects[hit.obj].visibilityMask & rayType) == 0;
+      if ((cutout || hidden) && passThroughs < maxPassThroughs) {
+        ray.pos = hit.pos;
+        passThroughs++;
+        bounceLeft++;
+        continue;
+      }
+      passThroughs = 0;
+
+      // Blend the wireframe over mesh edges less than a pixel wide from the hit.
+      if (cameraRay && wireframe != 0 && boundsPreview == 0 &&
+          objects[hit.obj].type == 2) {
+        float pixelSize = hit.dist * 2 * camVFov / float(imageSize(img).y);
+        if (hit.edgeDist < pixelSize) {
+          color += colMask * vec4(wireframeColor.xyz * wireframeColor.w, 0);
+          colMask *= 1 - wireframeColor.w;
+        }
+      }
+      if (cameraRay) {
+        causticBounces = isCausticReceiver(hit.physProp) ? 0 : -1;
+      } else if (causticBounces >= 0) {
+        causticBounces = hit.physProp.roughness < 0.1 ? causticBounces + 1 : -1;
+      }
+      cameraRay = false;
+      specularPath = specularPath && hit.physProp.roughness < 0.1;
+
+      applyChecker(hit);
+
+      vec3 emission = hit.physProp.emission.xyz * hit.physProp.emission.w;
+      if (negativeEmission == 0) {
+        emission = max(emission, 0);
+      }
+      if (hit.physProp.emitBothSides == 0 && !hit.frontFace) {
+        emission = vec3(0);
+      }
+      color.xyz += clampBounce(colMask.xyz * emission, maxBounce - bounceLeft - 1);
+      colMask *= hit.physProp.color;
       bool doReflect = true;
       vec3 normal = hit.isEntry ? hit.normal : -hit.normal;
+      // GGX microfacet normal of rough dielectrics, shared by refraction and reflection.
+      vec3 microNormal = vec3(0);
 
-      if (randFloat(rngState) >= objects[hit.obj].physProp.opacity) {
+      if (randFloat(rngState) >= hit.physProp.opacity) {
         // Get normal and IORs.
         float ratio;
         if (hit.isEntry) {
-          ratio = 1.0 / objects[hit.obj].physProp.ior;
+          ratio = 1.0 / hit.physProp.ior;
         } else {
-          ratio = objects[hit.obj].physProp.ior;
+          ratio = hit.physProp.ior;
         }
 
+        // Rough dielectrics reflect and refract off a GGX microfacet normal.
+        microNormal = randGgxNormal(rngState, normal, hit.physProp.roughness);
+
         // Determine refraction angle.
-        float inDot = clamp(-dot(ray.normal, normal), -1, 1);
+        float inDot = clamp(-dot(ray.normal, microNormal), -1, 1);
         float det = 1 - ratio * ratio * (1 - inDot * inDot);
+        vec3 outNormal;
         if (det >= 0) {
+          outNormal = ray.normal * ratio +
+                      microNormal * (ratio * inDot - sqrt(det));
+        } else {
+          // Total internal reflection.
+          outNormal = ray.normal + 2 * inDot * microNormal;
+        }
+        // Keep the ray on the side of the surface the microfacet sent it to.
+        if ((dot(outNormal, normal) < 0) == (det >= 0)) {
           ray.pos = hit.pos;
-          ray.normal =
-              ray.normal * ratio + normal * (ratio * inDot - sqrt(det));
-          ray.normal = normalize(ray.normal);
+          ray.normal = normalize(outNormal);
           doReflect = false;
+          // Entering an object starts scattering in it, leaving one stops.
+          medium = hit.isEntry ? hit.physProp.scatter : vec4(0);
+          diffuseNormal = vec3(0);
+          rayType = rayRefraction;
         }
       }
       if (doReflect) {
         // Do reflection.
-        vec3 diffNormal = normalize(randUnitVec(rngState) + normal);
-        vec3 specNormal =
-            normalize(ray.normal - 2 * dot(ray.normal, hit.normal) * normal);
-
         ray.pos = hit.pos;
-        ray.normal = specNormal + (diffNormal - specNormal) *
-                                      objects[hit.obj].physProp.roughness;
+        if (hit.physProp.opacity < 1) {
+          if (microNormal == vec3(0)) {
+            microNormal =
+                randGgxNormal(rngState, normal, hit.physProp.roughness);
+          }
+          // Reflections into the surface are mirrored back out.
+          ray.normal = reflect(ray.normal, microNormal);
+          if (dot(ray.normal, normal) < 0) {
+            ray.normal = reflect(ray.normal, normal);
+          }
+        } else {
+          vec3 diffNormal = normalize(randUnitVec(rngState) + normal);
+          vec3 specNormal =
+              normalize(ray.normal - 2 * dot(ray.normal, normal) * normal);
+          ray.normal = specNormal + (diffNormal - specNormal) *
+                                        hit.physProp.roughness;
+        }
         ray.normal = normalize(ray.normal);
+        diffuseNormal = hit.physProp.roughness >= 1 ? normal : vec3(0);
+        // Like `specularPath`, only nearly smooth surfaces count as mirror-like.
+        rayType = hit.physProp.roughness < 0.1 ? rayReflection : rayDiffuse;
       }
     } else {
       // No hit; sample skybox color.
       float coeff = clamp(ray.normal.y * 4, -1, 1);
       vec4 base;
-      if (coeff >= 0) {
+      if (skybox.useCubemap != 0 && diffuseNormal != vec3(0)) {
+        // A ray escaping after a fully diffuse bounce would on average see the irradiance
+        // around the surface normal, which is much less noisy than a single direction.
+        base = textureLod(skyIrradiance, diffuseNormal * vec3(1, -1, 1), 0);
+      } else if (skybox.useCubemap != 0) {
+        // The scene's Y axis points down, cubemap faces expect it to point up.
+        base = textureLod(skyCubemap, ray.normal * vec3(1, -1, 1), 0);
+      } else if (coeff >= 0) {
         base = skybox.horizonColor +
                (skybox.groundColor - skybox.horizonColor) * coeff;
       } else {
@@ -475,13 +1149,19 @@ vec4 rayTrace(Ray ray, inout uint rngState) {
                (skybox.skyColor - skybox.horizonColor) * -coeff;
       }
 
-      float sunDot = dot(ray.normal, skybox.sunDirection.xyz);
-      if (sunDot >= skybox.sunRadius) {
-        float sunCoeff = (sunDot - skybox.sunRadius) / (1.0 - skybox.sunRadius);
-        color += colMask * (base + (skybox.sunColor - base) * sunCoeff);
-      } else {
-        color += colMask * base;
+      // Blend towards each sun's color near its direction.
+      uint sunCount = photonCaustics() && causticBounces > 0 ? 0 : min(skybox.sunCount, maxSuns);
+      for (uint i = 0; i < sunCount; i++) {
+        Sun sun = skybox.suns[i];
+        float sunDot = dot(ray.normal, sun.direction.xyz);
+        if (sunDot >= sun.direction.w) {
+          float sunCoeff = (sunDot - sun.direction.w) / (1.0 - sun.direction.w);
+          base += (sun.color - base) * sunCoeff;
+        }
       }
+      vec4 light = colMask * base;
+      light.xyz = clampBounce(light.xyz, maxBounce - bounceLeft - 1);
+      color += light;
 
       return color;
     }
@@ -490,36 +1170,176 @@ vec4 rayTrace(Ray ray, inout uint rngState) {
   return color;
 }
 
+// Gather the even bits of `index`; the inverse of spreading a coordinate for a Morton index.
+uint mortonCompact(uint index) {
+  index &= 0x55555555u;
+  index = (index | (index >> 1)) & 0x33333333u;
+  index = (index | (index >> 2)) & 0x0f0f0f0fu;
+  index = (index | (index >> 4)) & 0x00ff00ffu;
+  return (index | (index >> 8)) & 0x0000ffffu;
+}
+
+// Get the position of this invocation within the render region.
+uvec2 invocationRegionPos() {
+  if (mortonOrder == 0) {
+    return gl_GlobalInvocationID.xy;
+  }
+  // See `morton_dispatch`; workgroups past the region land outside it and are skipped.
+  uint index = gl_WorkGroupID.y * gl_NumWorkGroups.x + gl_WorkGroupID.x;
+  uvec2 tile = uvec2(mortonCompact(index), mortonCompact(index >> 1));
+  return tile * gl_WorkGroupSize.xy + gl_LocalInvocationID.xy;
+}
+
 void main() {
   ivec2 imgSize = imageSize(img);
-  ivec2 pixelCoords = ivec2(gl_GlobalInvocationID.xy);
+  uvec2 regionPos = invocationRegionPos();
+  if (any(greaterThanEqual(regionPos, regionSize))) {
+    return;
+  }
+  ivec2 pixelCoords = ivec2(regionPos + regionOffset);
   if (pixelCoords.x >= imgSize.x || pixelCoords.y >= imgSize.y) {
     return;
   }
-  vec4 prevColor = frameCounter > 1 ? imageLoad(img, pixelCoords) : vec4(0.0);
+  bool usePrev = accumulate != 0 && frameCounter > 1;
+  // Scaling the sample count along with the sums keeps the mean but reduces its weight.
+  vec4 prevColor = usePrev ? imageLoad(img, pixelCoords) * historyScale : vec4(0.0);
+  vec4 prevSqr = usePrev ? imageLoad(varImg, pixelCoords) * historyScale : vec4(0.0);
+  // Cap the history's weight so the result becomes an exponential moving average.
+  if (historyAlpha > 0 && prevColor.w > 0) {
+    float scale = min((1 / historyAlpha - 1) / prevColor.w, 1);
+    prevColor *= scale;
+    prevSqr *= scale;
+  }
+
+  // Every invocation traces a caustics photon, and collects what photons deposited on its pixel since its last sample.
+  vec3 caustic = vec3(0);
+  if (photonCaustics() && aov == 0 && objectIds == 0) {
+    uint photonRng = ~(frameCounter * (1 + pixelCoords.x + pixelCoords.y * imgSize.x) +
+                       rngSeed * 0x9e3779b9u);
+    splitmix32(photonRng);
+    splitmix32(photonRng);
+    traceCausticPhoton(photonRng, float(regionSize.x * regionSize.y));
+    uint index = 3 * uint(pixelCoords.y * imgSize.x + pixelCoords.x);
+    for (uint i = 0; i < 3; i++) {
+      caustic[i] = float(atomicExchange(caustics[index + i], 0)) / causticScale;
+    }
+    // Anything deposited before accumulation restarted belongs to the old view.
+    if (frameCounter == 1) {
+      caustic = vec3(0);
+    }
+  }
+
+  // Skip pixels whose estimate has converged.
+  if (varianceThreshold > 0 && prevColor.w >= adaptiveWarmup) {
+    vec3 mean = prevColor.xyz / prevColor.w;
+    vec3 variance = max(prevSqr.xyz / prevColor.w - mean * mean, 0) / prevColor.w;
+    if (dot(variance, vec3(0.2126, 0.7152, 0.0722)) < varianceThreshold) {
+      return;
+    }
+  }
 
   // Create primitive RNG seed.
   uint rngState =
-      frameCounter * (1 + pixelCoords.x + pixelCoords.y * imgSize.x);
+      frameCounter * (1 + pixelCoords.x + pixelCoords.y * imgSize.x) +
+      rngSeed * 0x9e3779b9u;
   splitmix32(rngState);
   splitmix32(rngState);
 
-  float dist = float(imgSize.y) * 0.5 / camVFov;
   Ray ray;
   ray.pos = (camMatrix * vec4(0, 0, 0, 1)).xyz;
   vec2 randOff = vec2(randFloat(rngState), randFloat(rngState)) - 0.5;
-  vec2 pixelCoordsf = vec2(pixelCoords) + randOff;
-  ray.normal = normalize(
-      (camMatrix * (vec4(pixelCoordsf, dist, 0) - 0.5 * vec4(imgSize, 0, 0)))
-          .xyz);
+  if (objectIds != 0) {
+    randOff = vec2(0);
+  }
+  // A region rendered as its own view, like a half of split view, is projected like a whole image.
+  ivec2 viewOffset = regionIsView != 0 ? ivec2(regionOffset) : ivec2(0);
+  ivec2 viewSize = regionIsView != 0 ? ivec2(regionSize) : imgSize;
+  vec2 pixelCoordsf = vec2(pixelCoords - viewOffset) + randOff;
+  vec2 halfSize = 0.5 * vec2(viewSize);
+  // For stereo, each half of the view is an eye, offset along the camera's right axis.
+  if (stereo > 0) {
+    bool rightEye = pixelCoords.x - viewOffset.x >= viewSize.x / 2;
+    halfSize.x = 0.5 * float(viewSize.x / 2);
+    if (rightEye) {
+      pixelCoordsf.x -= float(viewSize.x / 2);
+    }
+    ray.pos += camMatrix[0].xyz * stereo * (rightEye ? 0.5 : -0.5);
+  }
+  // Map the image to [-1, 1] on both axes, then scale by the view's extent.
+  vec2 viewPos = (pixelCoordsf - halfSize) / halfSize;
+  float aspectRatio = aspect > 0 ? aspect : halfSize.x / halfSize.y;
+  vec3 viewDir = (camMatrix * vec4(viewPos * vec2(aspectRatio, 1) * camVFov, 1, 0)).xyz;
+  ray.normal = normalize(viewDir);
+
+  // Differentiate the normalized direction by the pixel coordinates.
+  vec3 viewDx = (camMatrix * vec4(aspectRatio * camVFov / halfSize.x, 0, 0, 0)).xyz;
+  vec3 viewDy = (camMatrix * vec4(0, camVFov / halfSize.y, 0, 0)).xyz;
+  float viewSqr = dot(viewDir, viewDir);
+  cameraDdx = (viewSqr * viewDx - dot(viewDir, viewDx) * viewDir) / (viewSqr * sqrt(viewSqr));
+  cameraDdy = (viewSqr * viewDy - dot(viewDir, viewDy) * viewDir) / (viewSqr * sqrt(viewSqr));
+
+  // Write a single unjittered value for the selected output variable instead of accumulating.
+  if (aov != 0) {
+    HitInfo hit = rayTestClipped(ray);
+    vec4 value = vec4(0, 0, 0, 1);
+    if (!isinf(hit.dist)) {
+      switch (aov) {
+      case 1: // World normal, facing the camera.
+        value.xyz = faceforward(normalize(hit.normal), ray.normal, hit.normal);
+        break;
+      case 2: // View depth.
+        value.xyz = vec3(dot(hit.pos - ray.pos, normalize(camMatrix[2].xyz)));
+        break;
+      case 3: // UV coordinates.
+        value.xy = hit.uv;
+        break;
+      case 4: // Object ID plus one, and distance, like `objectIds`.
+        value.xy = vec2(float(hit.obj + 1), hit.dist);
+        break;
+      case 5: // Material table index plus one, or 0 for the object's own material.
+        value.x = float(hit.material + 1);
+        break;
+      case 6: // Albedo.
+        applyChecker(hit);
+        value.xyz = hit.physProp.color.xyz;
+        break;
+      }
+    } else if (aov == 2) {
+      value.xyz = vec3(1.0 / 0.0);
+    }
+    imageStore(img, pixelCoords, value);
+    return;
+  }
+
+  // Write the index of the object hit, plus one, for comparison against the CPU.
+  if (objectIds != 0) {
+    HitInfo hit = rayTestClipped(ray);
+    float id = isinf(hit.dist) ? 0 : float(hit.obj + 1);
+    imageStore(img, pixelCoords, vec4(id, hit.dist, 0, 1));
+    return;
+  }
 
   vec4 color = vec4(0);
   for (uint i = 0; i < rayCount; i++) {
     color += vec4(rayTrace(ray, rngState));
   }
   color /= float(rayCount);
+  color.xyz += caustic;
+
+  if (traceCost != 0) {
+    vec4 prevCost = usePrev ? imageLoad(costImg, pixelCoords) : vec4(0);
+    float cost = float(traversalCost) / float(rayCount);
+    imageStore(costImg, pixelCoords, prevCost + vec4(cost, 0, 0, 1));
+  }
+
+  // Drop non-finite samples; once added they would corrupt the pixel for good.
+  if (any(isnan(color.xyz)) || any(isinf(color.xyz))) {
+    imageStore(img, pixelCoords, prevColor);
+    imageStore(varImg, pixelCoords, prevSqr);
+    return;
+  }
 
-  // The fourth channel is for debug info and is not accumulated.
-  prevColor.w = 0;
-  imageStore(img, pixelCoords, prevColor + color);
+  // The fourth channel counts the samples taken for this pixel.
+  imageStore(img, pixelCoords, vec4(prevColor.xyz + color.xyz, prevColor.w + 1));
+  imageStore(varImg, pixelCoords, prevSqr + color * color);
 }
@@ -0,0 +1,67 @@
+#version 450 core
+
+// Format of the ray-tracing images; the 16-bit fallback is compiled with -DRT_FORMAT=rgba16f.
+#ifndef RT_FORMAT
+#define RT_FORMAT rgba32f
+#endif
+
+// Joint-bilateral upscale of the low-resolution accumulated samples to the window resolution.
+// The guides hold the object ID and depth of each pixel's primary ray, as written by the
+// ray tracer's object ID mode, so low-resolution samples only bleed onto pixels of the
+// same surface.
+
+layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+// Accumulated samples, with the sample count in the fourth channel.
+layout(binding = 0, RT_FORMAT) uniform readonly image2D lowImg;
+// Object ID plus one in the first channel and depth in the second, at the render resolution.
+layout(binding = 1, RT_FORMAT) uniform readonly image2D lowGuide;
+// Same as `lowGuide`, at the window resolution.
+layout(binding = 2, RT_FORMAT) uniform readonly image2D fullGuide;
+layout(binding = 3, RT_FORMAT) uniform writeonly image2D outImg;
+
+// Relative depth difference at which a sample's weight falls to 1/e.
+const float depthSigma = 0.05;
+// Weight of samples from a different object, so pixels with no matching sample still get a color.
+const float mismatchWeight = 0.001;
+
+void main() {
+  ivec2 fullSize = imageSize(outImg);
+  ivec2 lowSize = imageSize(lowImg);
+  ivec2 pixelCoords = ivec2(gl_GlobalInvocationID.xy);
+  if (pixelCoords.x >= fullSize.x || pixelCoords.y >= fullSize.y) {
+    return;
+  }
+  vec4 guide = imageLoad(fullGuide, pixelCoords);
+
+  // Bilinear footprint in the low-resolution image.
+  vec2 lowPos = (vec2(pixelCoords) + 0.5) * vec2(lowSize) / vec2(fullSize) - 0.5;
+  ivec2 base = ivec2(floor(lowPos));
+  vec2 frac = lowPos - vec2(base);
+
+  vec3 sum = vec3(0);
+  float weightSum = 0;
+  for (int y = 0; y < 2; y++) {
+    for (int x = 0; x < 2; x++) {
+      ivec2 coords = clamp(base + ivec2(x, y), ivec2(0), lowSize - 1);
+      vec2 bilinear = mix(1 - frac, frac, vec2(x, y));
+      float weight = bilinear.x * bilinear.y;
+
+      vec4 sampleGuide = imageLoad(lowGuide, coords);
+      if (sampleGuide.x != guide.x) {
+        weight *= mismatchWeight;
+      } else if (guide.x != 0) {
+        // Misses have infinite depth, so only compare the depth of hits.
+        float depthDiff = abs(sampleGuide.y - guide.y) / max(guide.y, 1e-4);
+        weight *= exp(-depthDiff / depthSigma);
+      }
+
+      vec4 texel = imageLoad(lowImg, coords);
+      sum += weight * texel.xyz / max(texel.w, 1);
+      weightSum += weight;
+    }
+  }
+
+  // Store the average as a single sample so it displays like the accumulation image.
+  imageStore(outImg, pixelCoords, vec4(sum / max(weightSum, 1e-12), 1));
+}
@@ -0,0 +1,190 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use glam::Vec4;
+
+/// Kind of change that may invalidate accumulated samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SceneChange {
+    /// The camera moved or rotated.
+    Camera,
+    /// The field of view changed.
+    Fov,
+    /// The skybox or sun changed.
+    Skybox,
+    /// A material was edited.
+    Material,
+    /// An object's transform changed, e.g. by an animation.
+    Transform,
+}
+
+/// What to do with accumulated samples after a change.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AccumulationAction {
+    /// Discard all accumulated samples.
+    #[default]
+    Reset,
+    /// Keep this fraction of the accumulated samples' weight, blending the old image out over time.
+    Decay(f32),
+    /// Keep accumulating as if nothing changed.
+    Keep,
+}
+
+/// Controls which changes reset accumulation and which blend into it.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct AccumulationPolicy {
+    pub camera: AccumulationAction,
+    pub fov: AccumulationAction,
+    pub skybox: AccumulationAction,
+    pub material: AccumulationAction,
+    pub transform: AccumulationAction,
+}
+
+impl AccumulationPolicy {
+    /// Get the action to take for a change.
+    pub fn action(&self, change: SceneChange) -> AccumulationAction {
+        match change {
+            SceneChange::Camera => self.camera,
+            SceneChange::Fov => self.fov,
+            SceneChange::Skybox => self.skybox,
+            SceneChange::Material => self.material,
+            SceneChange::Transform => self.transform,
+        }
+    }
+}
+
+/// Raw accumulation state, which can be saved and resumed later or merged with
+/// buffers rendered elsewhere.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccumulationBuffer {
+    pub extent: [u32; 2],
+    /// Number of frames accumulated.
+    pub frame_counter: u32,
+    /// Seed of the random numbers the samples were rendered with, see `--seed`.
+    pub seed: u32,
+    /// Sum of the samples of each pixel; the fourth channel holds the sample count.
+    pub samples: Vec<Vec4>,
+    /// Sum of the squared samples of each pixel.
+    pub variance: Vec<Vec4>,
+}
+
+impl AccumulationBuffer {
+    const MAGIC: &'static [u8; 8] = b"RTACCUM1";
+
+    /// Add another buffer's samples to this one.
+    /// Both store sums weighted by their sample counts, so merging is a plain addition.
+    /// Buffers rendered with the same seed contain the same samples, so merging them is refused.
+    pub fn merge(&mut self, other: &AccumulationBuffer) -> io::Result<()> {
+        if self.seed == other.seed {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Can't merge accumulation buffers rendered with the same seed {}; \
+                     render them with different `--seed`s",
+                    self.seed
+                ),
+            ));
+        }
+        if self.extent != other.extent {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Can't merge a {}x{} accumulation buffer into a {}x{} one",
+                    other.extent[0], other.extent[1], self.extent[0], self.extent[1]
+                ),
+            ));
+        }
+        self.frame_counter += other.frame_counter;
+        for (a, b) in self.samples.iter_mut().zip(&other.samples) {
+            *a += *b;
+        }
+        for (a, b) in self.variance.iter_mut().zip(&other.variance) {
+            *a += *b;
+        }
+        Ok(())
+    }
+
+    /// Save as raw little-endian floats, preceded by a small header.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(Self::MAGIC)?;
+        for value in [
+            self.extent[0],
+            self.extent[1],
+            self.frame_counter,
+            self.seed,
+        ] {
+            out.write_all(&value.to_le_bytes())?;
+        }
+        for pixel in self.samples.iter().chain(&self.variance) {
+            for value in pixel.to_array() {
+                out.write_all(&value.to_le_bytes())?;
+            }
+        }
+        out.flush()
+    }
+
+    /// Load a buffer written by [`AccumulationBuffer::save`].
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let data = fs::read(path)?;
+        if data.len() < 24 || &data[..8] != Self::MAGIC {
+            return Err(invalid("not an accumulation buffer"));
+        }
+        let word = |i: usize| u32::from_le_bytes(data[8 + i * 4..12 + i * 4].try_into().unwrap());
+        let extent = [word(0), word(1)];
+        let frame_counter = word(2);
+        let seed = word(3);
+        let count = extent[0] as usize * extent[1] as usize;
+        if data.len() != 24 + count * 32 {
+            return Err(invalid("accumulation buffer has the wrong size"));
+        }
+        let mut pixels = data[24..].chunks_exact(16).map(|f| {
+            Vec4::from_array(std::array::from_fn(|i| {
+                f32::from_le_bytes(f[i * 4..i * 4 + 4].try_into().unwrap())
+            }))
+        });
+        Ok(Self {
+            extent,
+            frame_counter,
+            seed,
+            samples: pixels.by_ref().take(count).collect(),
+            variance: pixels.collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(seed: u32) -> AccumulationBuffer {
+        AccumulationBuffer {
+            extent: [2, 1],
+            frame_counter: 3,
+            seed,
+            samples: vec![Vec4::new(1.0, 2.0, 3.0, 3.0); 2],
+            variance: vec![Vec4::ONE; 2],
+        }
+    }
+
+    #[test]
+    fn merge_requires_different_seeds() {
+        let mut accum = buffer(1);
+        assert!(accum.merge(&buffer(1)).is_err());
+        assert_eq!(accum, buffer(1));
+        accum.merge(&buffer(2)).unwrap();
+        assert_eq!(accum.frame_counter, 6);
+        assert_eq!(accum.samples[0], Vec4::new(2.0, 4.0, 6.0, 6.0));
+    }
+
+    #[test]
+    fn seed_survives_save_and_load() {
+        let path = std::env::temp_dir().join(format!("hardrtx-test-{}.accum", std::process::id()));
+        buffer(7).save(&path).unwrap();
+        let loaded = AccumulationBuffer::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), buffer(7));
+    }
+}
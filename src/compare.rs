@@ -0,0 +1,227 @@
+use std::error::Error;
+use std::io;
+use std::path::Path;
+
+use glam::Vec3;
+
+use crate::accumulation::AccumulationBuffer;
+use crate::export::{read_png, srgb_to_linear};
+use crate::texture::Texture;
+
+/// Size of the square windows SSIM is computed over.
+const SSIM_WINDOW: usize = 8;
+/// Added to the squared reference in the relative MSE so black pixels don't dominate it.
+const REL_MSE_EPSILON: f32 = 0.01;
+
+/// Linear image loaded for comparison.
+struct Image {
+    width: u32,
+    height: u32,
+    pixels: Vec<Vec3>,
+}
+
+impl Image {
+    /// Load the mean of an accumulation buffer (`.accum`) or an sRGB PNG (`.png`) or PPM (`.ppm`).
+    /// PNGs are compared as stored, so they are tone mapped if they were exported by the renderer.
+    fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        match path.extension().and_then(|f| f.to_str()) {
+            Some("accum") => {
+                let accum = AccumulationBuffer::load(path)?;
+                Ok(Self {
+                    width: accum.extent[0],
+                    height: accum.extent[1],
+                    pixels: accum
+                        .samples
+                        .iter()
+                        .map(|f| {
+                            if f.w > 0.0 {
+                                f.truncate() / f.w
+                            } else {
+                                Vec3::ZERO
+                            }
+                        })
+                        .collect(),
+                })
+            }
+            Some("png") => {
+                let (width, height, pixels) = read_png(path)?;
+                Ok(Self {
+                    width,
+                    height,
+                    pixels: pixels
+                        .iter()
+                        .map(|f| f.truncate().to_array().map(srgb_to_linear).into())
+                        .collect(),
+                })
+            }
+            Some("ppm") => {
+                let texture = Texture::load_ppm(path)?;
+                Ok(Self {
+                    width: texture.width,
+                    height: texture.height,
+                    pixels: texture
+                        .pixels
+                        .iter()
+                        .map(|f| {
+                            Vec3::from_array([0, 1, 2].map(|i| srgb_to_linear(f[i] as f32 / 255.0)))
+                        })
+                        .collect(),
+                })
+            }
+            _ => Err(format!(
+                "Can't compare {}: only .accum, .png and .ppm are supported",
+                path.display()
+            )
+            .into()),
+        }
+    }
+}
+
+/// Luminance of a linear color.
+fn luminance(color: Vec3) -> f32 {
+    color.dot(Vec3::new(0.2126, 0.7152, 0.0722))
+}
+
+/// Mean structural similarity of the luminance over non-overlapping windows.
+fn ssim(a: &Image, b: &Image) -> f32 {
+    const C1: f32 = 0.01 * 0.01;
+    const C2: f32 = 0.03 * 0.03;
+    let (width, height) = (a.width as usize, a.height as usize);
+    let mut total = 0.0;
+    let mut windows = 0;
+    for y0 in (0..height).step_by(SSIM_WINDOW) {
+        for x0 in (0..width).step_by(SSIM_WINDOW) {
+            let coords = (y0..(y0 + SSIM_WINDOW).min(height))
+                .flat_map(|y| (x0..(x0 + SSIM_WINDOW).min(width)).map(move |x| y * width + x));
+            let values: Vec<_> = coords
+                .map(|i| (luminance(a.pixels[i]), luminance(b.pixels[i])))
+                .collect();
+            let n = values.len() as f32;
+            let mean_a = values.iter().map(|f| f.0).sum::<f32>() / n;
+            let mean_b = values.iter().map(|f| f.1).sum::<f32>() / n;
+            let (mut var_a, mut var_b, mut cov) = (0.0, 0.0, 0.0);
+            for (va, vb) in &values {
+                var_a += (va - mean_a) * (va - mean_a) / n;
+                var_b += (vb - mean_b) * (vb - mean_b) / n;
+                cov += (va - mean_a) * (vb - mean_b) / n;
+            }
+            total += (2.0 * mean_a * mean_b + C1) * (2.0 * cov + C2)
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+    total / windows as f32
+}
+
+/// Map `t` in [0, 1] from blue through green to red.
+fn false_color(t: f32) -> [u8; 4] {
+    let t = t.clamp(0.0, 1.0);
+    let color = if t < 0.5 {
+        Vec3::new(0.0, t * 2.0, 1.0 - t * 2.0)
+    } else {
+        Vec3::new(t * 2.0 - 1.0, 2.0 - t * 2.0, 0.0)
+    };
+    let [r, g, b] = color.to_array().map(|f| (f * 255.0 + 0.5) as u8);
+    [r, g, b, 255]
+}
+
+/// Compare a render against a reference, printing RMSE, relative MSE and SSIM,
+/// and write a false-color image of the per-pixel error to `diff_path`.
+/// The error image is scaled so the largest error is red.
+pub fn compare_images(
+    path: impl AsRef<Path>,
+    reference: impl AsRef<Path>,
+    diff_path: impl AsRef<Path>,
+) -> Result<(), Box<dyn Error>> {
+    let a = Image::load(path.as_ref())?;
+    let b = Image::load(reference.as_ref())?;
+    if (a.width, a.height) != (b.width, b.height) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Can't compare a {}x{} image to a {}x{} one",
+                a.width, a.height, b.width, b.height
+            ),
+        )
+        .into());
+    }
+
+    let count = (a.pixels.len() * 3) as f32;
+    let mut sqr_error = 0.0;
+    let mut rel_sqr_error = 0.0;
+    let errors: Vec<f32> = a
+        .pixels
+        .iter()
+        .zip(&b.pixels)
+        .map(|(a, b)| {
+            let diff = *a - *b;
+            sqr_error += diff.length_squared();
+            rel_sqr_error += (diff * diff / (*b * *b + REL_MSE_EPSILON)).element_sum();
+            diff.length()
+        })
+        .collect();
+    println!("RMSE: {:.6}", (sqr_error / count).sqrt());
+    println!("relative MSE: {:.6}", rel_sqr_error / count);
+    println!("SSIM: {:.6}", ssim(&a, &b));
+
+    let max_error = errors.iter().copied().fold(0.0, f32::max);
+    let diff = Texture {
+        width: a.width,
+        height: a.height,
+        pixels: errors
+            .iter()
+            .map(|f| false_color(if max_error > 0.0 { f / max_error } else { 0.0 }))
+            .collect(),
+        srgb: true,
+    };
+    diff.save_ppm(diff_path.as_ref())?;
+    println!(
+        "Wrote {}, red is an error of {:.6}",
+        diff_path.as_ref().display(),
+        max_error
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::{aces, write_png, AlphaMode};
+    use glam::Vec4;
+
+    #[test]
+    fn load_exported_png() {
+        let pixels: Vec<Vec4> = (0..6)
+            .map(|i| Vec4::new(i as f32 * 0.3, 0.05, 1.0 - i as f32 * 0.15, 1.0))
+            .collect();
+        for (bit_depth, tolerance) in [(8, 0.01), (16, 1e-4)] {
+            let path = std::env::temp_dir().join(format!(
+                "hardrtx-test-{}-{}.png",
+                std::process::id(),
+                bit_depth
+            ));
+            write_png(
+                &path,
+                3,
+                2,
+                &pixels,
+                AlphaMode::Straight,
+                false,
+                1.0,
+                bit_depth,
+            )
+            .unwrap();
+            let image = Image::load(&path);
+            std::fs::remove_file(&path).unwrap();
+            let image = image.unwrap();
+            assert_eq!((image.width, image.height), (3, 2));
+            for (loaded, pixel) in image.pixels.iter().zip(&pixels) {
+                let expected = aces(pixel.truncate());
+                assert!(
+                    (*loaded - expected).abs().max_element() < tolerance,
+                    "{bit_depth} bits: {loaded} != {expected}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,450 @@
+use std::thread;
+
+use glam::{Vec2, Vec3, Vec4};
+
+use crate::scene::{Model, Node, PhysProp, Ray, RayVisibility, Scene, Skybox, Transform};
+use crate::RtParams;
+
+/// Surfaces rougher than this count as diffuse, as in the shader.
+const SPECULAR_ROUGHNESS: f32 = 0.1;
+/// Maximum number of hidden surfaces a ray may pass through per bounce, as in the shader.
+const MAX_PASS_THROUGHS: u32 = 32;
+
+/// Random number generator matching the shader's `splitmix32`.
+struct Rng(u32);
+
+impl Rng {
+    fn next_u32(&mut self) -> u32 {
+        self.0 = self.0.wrapping_add(0x9e3779b9);
+        let mut z = self.0;
+        z = (z ^ (z >> 16)).wrapping_mul(0x21f0aaad);
+        z = (z ^ (z >> 15)).wrapping_mul(0x735a2d97);
+        z ^ (z >> 15)
+    }
+
+    /// Uniform random float in [0, 1).
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / 16777216.0
+    }
+
+    fn next_normal(&mut self) -> f32 {
+        let t = std::f32::consts::TAU * self.next_f32();
+        let r = ((1.0 - self.next_f32()).ln() * -2.0).sqrt();
+        r * t.cos()
+    }
+
+    fn next_unit_vec(&mut self) -> Vec3 {
+        Vec3::new(self.next_normal(), self.next_normal(), self.next_normal()).normalize_or(Vec3::X)
+    }
+
+    /// Sample a GGX-distributed microfacet normal around `normal`.
+    fn next_ggx_normal(&mut self, normal: Vec3, roughness: f32) -> Vec3 {
+        let alpha = roughness * roughness;
+        let u = self.next_f32();
+        let phi = std::f32::consts::TAU * self.next_f32();
+        let cos_theta = ((1.0 - u) / (1.0 + (alpha * alpha - 1.0) * u)).sqrt();
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let up = if normal.x.abs() > 0.5 {
+            Vec3::Y
+        } else {
+            Vec3::X
+        };
+        let tangent = normal.cross(up).normalize();
+        let bitangent = normal.cross(tangent);
+        (tangent * (sin_theta * phi.cos())
+            + bitangent * (sin_theta * phi.sin())
+            + normal * cos_theta)
+            .normalize()
+    }
+}
+
+/// Surface hit by a world-space ray.
+struct Hit {
+    /// Index of the object as in [`Scene::objects`].
+    obj: usize,
+    dist: f32,
+    pos: Vec3,
+    /// Object-space position, for the checkerboard pattern.
+    local_pos: Vec3,
+    /// World-space shading normal, facing out of the object.
+    normal: Vec3,
+    prop: PhysProp,
+    /// Whether the ray enters the object rather than leaving it.
+    is_entry: bool,
+    /// Whether the ray hits the side the geometry faces, for one-sided emission.
+    front_face: bool,
+}
+
+/// Transform an object-space normal to world space by the inverse transpose.
+fn normal_to_world(transform: &Transform, normal: Vec3) -> Vec3 {
+    transform
+        .inv_matrix()
+        .transpose()
+        .transform_vector3(normal)
+        .normalize()
+}
+
+/// Test a world-space ray against a single object.
+fn ray_test_object(obj: usize, transform: &Transform, node: &Node, ray: Ray) -> Option<Hit> {
+    let local = transform.ray_world_to_local(ray);
+    let local = Ray {
+        pos: local.pos,
+        normal: local.normal.normalize(),
+    };
+    let (dist, normal, prop, is_entry, front_face) = match &node.model {
+        Model::None => return None,
+        Model::Sphere => {
+            let dist = local.intersect_unit_sphere()?;
+            let is_entry = local.pos.length_squared() > 1.0;
+            let normal = local.pos + local.normal * dist;
+            (dist, normal, node.prop, is_entry, is_entry)
+        }
+        Model::Plane => {
+            let dist = local.intersect_unit_plane()?;
+            let normal = Vec3::new(0.0, 0.0, local.pos.z.signum());
+            (dist, normal, node.prop, true, local.pos.z > 0.0)
+        }
+        Model::Mesh(mesh) => {
+            let (tri, dist) = mesh.ray_hit(local.pos, local.normal)?;
+            let indices = mesh.tris[tri];
+            let [a, b, c] = indices.map(|f| mesh.verts[f]);
+            let face_normal = (b - a).cross(c - a);
+
+            // Barycentric coordinates of the hit, for interpolating vertex attributes.
+            let pos = local.pos + local.normal * dist;
+            let area = face_normal.length_squared().max(f32::MIN_POSITIVE);
+            let u = (pos - a).cross(c - a).dot(face_normal) / area;
+            let v = (b - a).cross(pos - a).dot(face_normal) / area;
+            let weights = [1.0 - u - v, u, v];
+            let interpolate =
+                |values: &[Vec3]| -> Vec3 { (0..3).map(|i| values[indices[i]] * weights[i]).sum() };
+
+            let mut prop = mesh
+                .tri_materials
+                .as_ref()
+                .and_then(|f| node.materials.get(f[tri] as usize))
+                .copied()
+                .unwrap_or(node.prop);
+            if let Some(vert_cols) = &mesh.vert_cols {
+                prop.color *= interpolate(vert_cols);
+            }
+            let normal = mesh.normals.as_deref().map_or(face_normal, interpolate);
+            let front_face = local.normal.dot(face_normal) < 0.0;
+            let is_entry = local.normal.dot(normal) < 0.0;
+            (dist, normal, prop, is_entry, front_face)
+        }
+    };
+    let local_pos = local.pos + local.normal * dist;
+    let pos = transform.local_to_world(local_pos);
+    Some(Hit {
+        obj,
+        dist: (pos - ray.pos).length(),
+        pos,
+        local_pos,
+        normal: normal_to_world(transform, normal),
+        prop: prop.sanitize(),
+        is_entry,
+        front_face,
+    })
+}
+
+/// Find the nearest surface hit by a world-space ray among `objects`.
+fn ray_test(objects: &[(Transform, &Node)], ray: Ray) -> Option<Hit> {
+    objects
+        .iter()
+        .enumerate()
+        .filter_map(|(i, (transform, node))| ray_test_object(i, transform, node, ray))
+        .min_by(|a, b| a.dist.total_cmp(&b.dist))
+}
+
+/// Color of the skybox gradient and suns in a direction.
+fn sky_color(skybox: &Skybox, dir: Vec3) -> Vec3 {
+    let coeff = (dir.y * 4.0).clamp(-1.0, 1.0);
+    let mut base = if coeff >= 0.0 {
+        skybox.horizon_color.lerp(skybox.ground_color, coeff)
+    } else {
+        skybox.horizon_color.lerp(skybox.skybox_color, -coeff)
+    };
+    for sun in skybox.suns.iter().take(Skybox::MAX_SUNS) {
+        let sun_dot = dir.dot(sun.direction.normalize_or_zero());
+        if sun_dot >= sun.radius {
+            base = base.lerp(sun.color, (sun_dot - sun.radius) / (1.0 - sun.radius));
+        }
+    }
+    base
+}
+
+/// Scale down light a path picks up after `depth` bounces, like the shader's `clampBounce`.
+fn clamp_bounce(params: &RtParams, light: Vec3, depth: u32) -> Vec3 {
+    if params.bounce_clamp <= 0.0 || depth == 0 {
+        return light;
+    }
+    let max_lum = params.bounce_clamp * params.bounce_clamp_falloff.powi(depth as i32 - 1);
+    let lum = light.dot(Vec3::new(0.2126, 0.7152, 0.0722));
+    if lum > max_lum {
+        light * (max_lum / lum)
+    } else {
+        light
+    }
+}
+
+/// Trace a single path, following the shader's `rayTrace` without the features listed in [`render`].
+fn trace(
+    objects: &[(Transform, &Node)],
+    skybox: &Skybox,
+    params: &RtParams,
+    mut ray: Ray,
+    rng: &mut Rng,
+) -> Vec3 {
+    let mut bounce_left = params.max_bounce;
+    let mut pass_throughs = 0;
+    let mut specular_path = true;
+    let mut ray_type = RayVisibility::CAMERA;
+    let mut col_mask = Vec3::ONE;
+    let mut color = Vec3::ZERO;
+
+    while bounce_left > 0 && col_mask.element_sum() > 0.001 {
+        bounce_left -= 1;
+        let depth = params.max_bounce - bounce_left - 1;
+        let Some(mut hit) = ray_test(objects, ray) else {
+            return color + clamp_bounce(params, col_mask * sky_color(skybox, ray.normal), depth);
+        };
+
+        // Light-only objects are hidden from camera rays and their mirror-like reflections.
+        let hidden = (hit.prop.light_only && specular_path)
+            || !objects[hit.obj].1.visibility.contains(ray_type);
+        if hidden && pass_throughs < MAX_PASS_THROUGHS {
+            ray.pos = hit.pos;
+            pass_throughs += 1;
+            bounce_left += 1;
+            continue;
+        }
+        pass_throughs = 0;
+        specular_path = specular_path && hit.prop.roughness < SPECULAR_ROUGHNESS;
+
+        if let Some(checker) = hit.prop.checker.filter(|f| f.scale > 0.0) {
+            let cell = (hit.local_pos / checker.scale).floor().as_ivec3();
+            if (cell.x + cell.y + cell.z) & 1 != 0 {
+                hit.prop.color = checker.color;
+            }
+        }
+
+        if hit.prop.emit_both_sides || hit.front_face {
+            color += clamp_bounce(
+                params,
+                col_mask * hit.prop.emission().max(Vec3::ZERO),
+                depth,
+            );
+        }
+        col_mask *= hit.prop.color;
+        let normal = if hit.is_entry {
+            hit.normal
+        } else {
+            -hit.normal
+        };
+
+        let mut do_reflect = true;
+        let mut micro_normal = None;
+        if rng.next_f32() >= hit.prop.opacity {
+            let ratio = if hit.is_entry {
+                1.0 / hit.prop.ior
+            } else {
+                hit.prop.ior
+            };
+            let micro_normal =
+                *micro_normal.insert(rng.next_ggx_normal(normal, hit.prop.roughness));
+            let in_dot = (-ray.normal.dot(micro_normal)).clamp(-1.0, 1.0);
+            let det = 1.0 - ratio * ratio * (1.0 - in_dot * in_dot);
+            let out_normal = if det >= 0.0 {
+                ray.normal * ratio + micro_normal * (ratio * in_dot - det.sqrt())
+            } else {
+                // Total internal reflection.
+                ray.normal + 2.0 * in_dot * micro_normal
+            };
+            if (out_normal.dot(normal) < 0.0) == (det >= 0.0) {
+                ray = Ray {
+                    pos: hit.pos,
+                    normal: out_normal.normalize(),
+                };
+                do_reflect = false;
+                ray_type = RayVisibility::REFRACTION;
+            }
+        }
+        if do_reflect {
+            let out_normal = if hit.prop.opacity < 1.0 {
+                let micro_normal =
+                    micro_normal.unwrap_or_else(|| rng.next_ggx_normal(normal, hit.prop.roughness));
+                // Reflections off a microfacet that would go into the surface are mirrored back out.
+                let out_normal = ray.normal.reflect(micro_normal);
+                if out_normal.dot(normal) < 0.0 {
+                    out_normal.reflect(normal)
+                } else {
+                    out_normal
+                }
+            } else {
+                let diff_normal = (rng.next_unit_vec() + normal).normalize_or(normal);
+                let spec_normal = ray.normal - 2.0 * ray.normal.dot(normal) * normal;
+                spec_normal.lerp(diff_normal, hit.prop.roughness)
+            };
+            ray = Ray {
+                pos: hit.pos,
+                normal: out_normal.normalize_or(normal),
+            };
+            ray_type = if hit.prop.roughness < SPECULAR_ROUGHNESS {
+                RayVisibility::REFLECTION
+            } else {
+                RayVisibility::DIFFUSE
+            };
+        }
+    }
+    color
+}
+
+/// Path trace `samples` samples of `params.ray_count` rays per pixel on the CPU, spread across all cores,
+/// returning the mean of each pixel with an alpha of 1.
+///
+/// This is a slow reference for machines without Vulkan. It covers spheres, planes and meshes,
+/// materials, checkerboards, vertex colors, visibility masks and the skybox gradient with its suns.
+/// Cubemaps, alpha masks, subsurface scattering, god rays, caustics, clip planes, LODs,
+/// output variables and the debug views are GPU-only.
+pub fn render(scene: &Scene, params: &RtParams, extent: [u32; 2], samples: u32) -> Vec<Vec4> {
+    let objects = scene.objects();
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let rows_per_thread = (extent[1] as usize).div_ceil(threads).max(1);
+    let mut pixels = vec![Vec4::ZERO; (extent[0] * extent[1]) as usize];
+    thread::scope(|scope| {
+        for (chunk_index, chunk) in pixels
+            .chunks_mut(rows_per_thread * extent[0] as usize)
+            .enumerate()
+        {
+            let objects = &objects;
+            scope.spawn(move || {
+                let first = chunk_index * rows_per_thread * extent[0] as usize;
+                for (i, pixel) in chunk.iter_mut().enumerate() {
+                    let index = (first + i) as u32;
+                    let coords = Vec2::new((index % extent[0]) as f32, (index / extent[0]) as f32);
+                    let mut rng = Rng(index.wrapping_mul(0x2545f491));
+                    let mut sum = Vec3::ZERO;
+                    for _ in 0..samples * params.ray_count.max(1) {
+                        let jitter = Vec2::new(rng.next_f32(), rng.next_f32());
+                        let ray = params.primary_ray(extent, coords + jitter);
+                        sum += trace(objects, &scene.skybox, params, ray, &mut rng);
+                    }
+                    *pixel = (sum / (samples * params.ray_count.max(1)) as f32).extend(1.0);
+                }
+            });
+        }
+    });
+    pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Mat4, Quat};
+
+    use super::*;
+    use crate::{Aov, GpuVec4, MAX_CLIP_PLANES};
+
+    fn params(max_bounce: u32) -> RtParams {
+        RtParams {
+            cam_matrix: Mat4::IDENTITY.to_cols_array(),
+            cam_v_fov: 1.0,
+            frame_counter: 1,
+            max_bounce,
+            ray_count: 1,
+            variance_threshold: 0.0,
+            accumulate: 1,
+            history_scale: 1.0,
+            history_alpha: 0.0,
+            aspect: 0.0,
+            stereo: 0.0,
+            lod_distance: 0.0,
+            clip_plane_count: 0,
+            clip_planes: [Vec4::ZERO.into(); MAX_CLIP_PLANES],
+            aov: Aov::Beauty as u32,
+            wireframe: 0,
+            region_is_view: 0,
+            caustics: 0,
+            wireframe_color: GpuVec4::from(Vec4::ONE),
+            god_ray_density: 0.0,
+            god_ray_steps: 0,
+            bounce_clamp: 0.0,
+            bounce_clamp_falloff: 1.0,
+        }
+    }
+
+    /// Diffuse plane at height `z`, large enough that bounces between two of them never escape.
+    fn wide_plane(z: f32, prop: PhysProp) -> Node {
+        Node {
+            model: Model::Plane,
+            transform: Mat4::from_scale_rotation_translation(
+                Vec3::new(1e4, 1e4, 1.0),
+                Quat::IDENTITY,
+                Vec3::new(0.0, 0.0, z),
+            )
+            .into(),
+            prop,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn empty_scene_shows_skybox() {
+        let skybox = Skybox {
+            suns: vec![],
+            ..Default::default()
+        };
+        let params = params(4);
+        let extent = [16, 12];
+        for y in 0..extent[1] {
+            for x in 0..extent[0] {
+                let ray = params.primary_ray(extent, Vec2::new(x as f32, y as f32) + 0.5);
+                let color = trace(&[], &skybox, &params, ray, &mut Rng(0));
+                assert_eq!(color, sky_color(&skybox, ray.normal), "pixel {x}, {y}");
+            }
+        }
+    }
+
+    #[test]
+    fn throughput_between_two_planes() {
+        // Every bounce from one plane hits the other, so the path alternates between them: the light
+        // gathered after `n` bounces is `E * (1 + ab + (ab)^2 + ...)` with one term per hit of the light.
+        let (albedo_light, albedo_wall, emission) = (0.5, 0.8, 2.0);
+        let light = PhysProp::from_emission(Vec3::splat(albedo_light), Vec3::ONE, emission);
+        let wall = PhysProp::from_color(Vec3::splat(albedo_wall));
+        let nodes = [wide_plane(0.0, light), wide_plane(1.0, wall)];
+        let objects: Vec<_> = nodes.iter().map(|f| (f.transform, f)).collect();
+        let ray = Ray {
+            pos: Vec3::new(0.0, 0.0, 0.5),
+            normal: Vec3::NEG_Z,
+        };
+
+        for max_bounce in [1u32, 2, 5, 8] {
+            let expected: f32 = (0..max_bounce.div_ceil(2))
+                .map(|f| emission * (albedo_light * albedo_wall).powi(f as i32))
+                .sum();
+            let mut rng = Rng(max_bounce);
+            for _ in 0..16 {
+                let color = trace(
+                    &objects,
+                    &Skybox::empty(),
+                    &params(max_bounce),
+                    ray,
+                    &mut rng,
+                );
+                assert!(
+                    (color - Vec3::splat(expected)).abs().max_element() < 1e-4,
+                    "{max_bounce} bounces: got {color}, expected {expected}"
+                );
+            }
+        }
+
+        // With enough bounces it converges to the geometric series' limit.
+        let limit = emission / (1.0 - albedo_light * albedo_wall);
+        let color = trace(&objects, &Skybox::empty(), &params(64), ray, &mut Rng(0));
+        assert!(
+            (color.x - limit).abs() < 0.01 * limit,
+            "got {color}, expected {limit}"
+        );
+    }
+}
@@ -0,0 +1,678 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use glam::{Mat3, Vec3, Vec4};
+
+/// How the alpha channel of an exported image relates to its color channels.
+/// The renderer produces linear color premultiplied by coverage `alpha`;
+/// the conversion happens on that linear color, before tone mapping and sRGB encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AlphaMode {
+    /// Color is independent of alpha: `rgb = premultiplied_rgb / alpha`, as PNG readers expect.
+    /// Fully transparent pixels are stored as black.
+    #[default]
+    Straight,
+    /// Color is already scaled by alpha: `rgb = premultiplied_rgb`.
+    /// Compositing with straight-alpha math then darkens edges, so only use this for tools that expect it.
+    Premultiplied,
+}
+
+impl AlphaMode {
+    /// Convert a linear premultiplied pixel to this alpha mode.
+    pub fn convert(self, pixel: Vec4) -> Vec4 {
+        match self {
+            Self::Premultiplied => pixel,
+            Self::Straight if pixel.w > 0.0 => (pixel.truncate() / pixel.w).extend(pixel.w),
+            Self::Straight => Vec4::ZERO,
+        }
+    }
+}
+
+/// ACES filmic tone mapping, matching the fragment shader.
+pub fn aces(x: Vec3) -> Vec3 {
+    let a = 2.51;
+    let b = 0.03;
+    let c = 2.43;
+    let d = 0.59;
+    let e = 0.14;
+    ((x * (a * x + b)) / (x * (c * x + d) + e)).clamp(Vec3::ZERO, Vec3::ONE)
+}
+
+/// Convert a linear value in [0, 1] to sRGB.
+pub fn linear_to_srgb(x: f32) -> f32 {
+    if x <= 0.0031308 {
+        x * 12.92
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Convert an sRGB value in [0, 1] to linear.
+pub fn srgb_to_linear(x: f32) -> f32 {
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Convert IEEE half-precision bits to a float.
+pub fn f16_to_f32(bits: u16) -> f32 {
+    let sign = ((bits >> 15) as u32) << 31;
+    let exp = ((bits >> 10) & 0x1f) as u32;
+    let mant = (bits & 0x3ff) as u32;
+    let magnitude = match exp {
+        0 => mant as f32 * 2f32.powi(-24),
+        0x1f if mant == 0 => f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => f32::from_bits(((exp + 112) << 23) | (mant << 13)),
+    };
+    f32::from_bits(magnitude.to_bits() | sign)
+}
+
+/// Convert a float to IEEE half-precision bits, rounding to nearest.
+/// Values too large for a half become infinity.
+pub fn f32_to_f16(x: f32) -> u16 {
+    let sign = ((x.to_bits() >> 16) & 0x8000) as u16;
+    let abs = x.abs();
+    if abs.is_nan() {
+        return sign | 0x7e00;
+    }
+    if abs >= 65520.0 {
+        return sign | 0x7c00;
+    }
+    if abs < 2f32.powi(-14) {
+        return sign | (abs * 2f32.powi(24)).round() as u16;
+    }
+    let exp = (abs.to_bits() >> 23) - 112;
+    let mant = abs.to_bits() & 0x7fffff;
+    // Rounding up may carry into the exponent, which is still correct.
+    sign | (((exp << 10) | (mant >> 13)) + ((mant >> 12) & 1)) as u16
+}
+
+/// Color space that colors are converted to for light transport.
+/// Materials, vertex colors, the skybox and textures are defined in linear sRGB and converted when
+/// they are uploaded; rendered images are converted back to linear sRGB for display and export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum ColorSpace {
+    /// Linear Rec. 709 / sRGB primaries.
+    #[default]
+    LinearSrgb = 0,
+    /// ACES AP1 primaries, as used by film color pipelines.
+    AcesCg = 1,
+}
+
+/// Linear sRGB to ACEScg, including the D65 to ACES white point adaptation.
+const SRGB_TO_ACESCG: Mat3 = Mat3::from_cols_array(&[
+    0.613132, 0.070124, 0.020588, //
+    0.339538, 0.916394, 0.109575, //
+    0.047417, 0.013452, 0.869785,
+]);
+
+/// ACEScg to linear sRGB; the inverse of [`SRGB_TO_ACESCG`].
+const ACESCG_TO_SRGB: Mat3 = Mat3::from_cols_array(&[
+    1.704859, -0.130077, -0.023964, //
+    -0.621716, 1.140736, -0.128976, //
+    -0.083299, -0.010560, 1.153014,
+]);
+
+/// Working color space shared by the whole program; see [`set_color_space`].
+static COLOR_SPACE: AtomicU8 = AtomicU8::new(ColorSpace::LinearSrgb as u8);
+
+impl ColorSpace {
+    /// Convert a linear sRGB color to this color space.
+    pub fn convert_from_linear_srgb(self, color: Vec3) -> Vec3 {
+        match self {
+            Self::LinearSrgb => color,
+            Self::AcesCg => SRGB_TO_ACESCG * color,
+        }
+    }
+
+    /// Convert a color in this color space to linear sRGB.
+    pub fn to_linear_srgb(self, color: Vec3) -> Vec3 {
+        match self {
+            Self::LinearSrgb => color,
+            Self::AcesCg => ACESCG_TO_SRGB * color,
+        }
+    }
+}
+
+/// Get the working color space.
+pub fn color_space() -> ColorSpace {
+    match COLOR_SPACE.load(Ordering::Relaxed) {
+        1 => ColorSpace::AcesCg,
+        _ => ColorSpace::LinearSrgb,
+    }
+}
+
+/// Set the working color space. This must happen before the scene is uploaded,
+/// since colors are converted on upload.
+pub fn set_color_space(space: ColorSpace) {
+    COLOR_SPACE.store(space as u8, Ordering::Relaxed);
+}
+
+/// CRC-32 as used by PNG chunks.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = u32::MAX;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Adler-32 as used by zlib streams.
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+/// Wrap data in a zlib stream of uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(65535).peekable();
+    if blocks.peek().is_none() {
+        out.extend([1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let len = block.len() as u16;
+        out.push(blocks.peek().is_none() as u8);
+        out.extend(len.to_le_bytes());
+        out.extend((!len).to_le_bytes());
+        out.extend(block);
+    }
+    out.extend(adler32(data).to_be_bytes());
+    out
+}
+
+/// Write a single PNG chunk.
+fn write_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    let mut crc_data = kind.to_vec();
+    crc_data.extend(data);
+    out.write_all(&crc_data)?;
+    out.write_all(&crc32(&crc_data).to_be_bytes())
+}
+
+/// Write linear premultiplied pixels to an RGBA PNG with `bit_depth` bits per channel, 8 or 16,
+/// storing alpha as `alpha_mode`. Colors are converted from the working color space, scaled by `exposure`,
+/// tone mapped like the on-screen image and encoded as sRGB.
+/// If `flip_y` is set, the rows are written bottom-to-top.
+#[allow(clippy::too_many_arguments)]
+pub fn write_png(
+    path: impl AsRef<Path>,
+    width: u32,
+    height: u32,
+    pixels: &[Vec4],
+    alpha_mode: AlphaMode,
+    flip_y: bool,
+    exposure: f32,
+    bit_depth: u8,
+) -> io::Result<()> {
+    assert_eq!(pixels.len(), (width * height) as usize);
+    if bit_depth != 8 && bit_depth != 16 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("PNG bit depth must be 8 or 16, not {}", bit_depth),
+        ));
+    }
+    let max = ((1u32 << bit_depth) - 1) as f32;
+    let bytes = bit_depth as u32 / 8;
+
+    // Each row starts with filter type 0 (none).
+    let mut raw = Vec::with_capacity(((width * 4 * bytes + 1) * height) as usize);
+    let rows: Box<dyn Iterator<Item = &[Vec4]>> = if flip_y {
+        Box::new(pixels.chunks(width as usize).rev())
+    } else {
+        Box::new(pixels.chunks(width as usize))
+    };
+    let space = color_space();
+    for row in rows {
+        raw.push(0);
+        for pixel in row {
+            let pixel = alpha_mode.convert(*pixel);
+            let color = aces(space.to_linear_srgb(pixel.truncate()) * exposure);
+            let alpha = pixel.w.clamp(0.0, 1.0);
+            for channel in [color.x, color.y, color.z]
+                .map(linear_to_srgb)
+                .into_iter()
+                .chain([alpha])
+            {
+                // Samples are stored big-endian.
+                let value = (channel * max + 0.5) as u16;
+                if bit_depth == 16 {
+                    raw.extend(value.to_be_bytes());
+                } else {
+                    raw.push(value as u8);
+                }
+            }
+        }
+    }
+
+    let mut header = vec![];
+    header.extend(width.to_be_bytes());
+    header.extend(height.to_be_bytes());
+    // RGBA, default compression, filtering and no interlacing.
+    header.extend([bit_depth, 6, 0, 0, 0]);
+
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(b"\x89PNG\r\n\x1a\n")?;
+    write_chunk(&mut out, b"IHDR", &header)?;
+    write_chunk(&mut out, b"IDAT", &zlib_stored(&raw))?;
+    write_chunk(&mut out, b"IEND", &[])?;
+    out.flush()
+}
+
+/// Reads the bits of a deflate stream, least significant bit first.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit: u32,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: u32) -> io::Result<u32> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = *self.data.get(self.pos).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "truncated deflate stream")
+            })?;
+            value |= ((byte >> self.bit) as u32 & 1) << i;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+        }
+        Ok(value)
+    }
+
+    /// Skip to the next byte boundary.
+    fn align(&mut self) {
+        if self.bit != 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+
+    /// Decode one symbol of a canonical Huffman code.
+    fn decode(&mut self, code: &Huffman) -> io::Result<u16> {
+        let (mut value, mut first, mut index) = (0, 0, 0);
+        for len in 1..16 {
+            value |= self.bits(1)? as i32;
+            let count = code.counts[len] as i32;
+            if value - count < first {
+                return Ok(code.symbols[(index + value - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            value <<= 1;
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid Huffman code",
+        ))
+    }
+}
+
+/// Canonical Huffman code: the number of codes of each length and the symbols in code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    /// Build the code from the code length of each symbol, 0 for unused symbols.
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0; 16];
+        for len in lengths {
+            counts[*len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, len) in lengths.iter().enumerate() {
+            if *len != 0 {
+                symbols[offsets[*len as usize] as usize] = symbol as u16;
+                offsets[*len as usize] += 1;
+            }
+        }
+        Self { counts, symbols }
+    }
+}
+
+/// Base match length and extra bits of length symbols 257 to 285.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+/// Base distance and extra bits of distance symbols.
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order the code lengths of the code length code are stored in.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Decompress a zlib stream.
+fn inflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    if data.len() < 2 || data[0] & 0x0f != 8 || data[1] & 0x20 != 0 {
+        return Err(invalid("unsupported zlib stream"));
+    }
+    let mut input = BitReader {
+        data: &data[2..],
+        pos: 0,
+        bit: 0,
+    };
+    let mut out = vec![];
+    loop {
+        let last = input.bits(1)? == 1;
+        let (lit_code, dist_code) = match input.bits(2)? {
+            0 => {
+                // Stored block.
+                input.align();
+                let header = input
+                    .data
+                    .get(input.pos..input.pos + 4)
+                    .ok_or_else(|| invalid("truncated deflate stream"))?;
+                let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+                if u16::from_le_bytes([header[2], header[3]]) != !(len as u16) {
+                    return Err(invalid("corrupt stored block"));
+                }
+                input.pos += 4;
+                out.extend(
+                    input
+                        .data
+                        .get(input.pos..input.pos + len)
+                        .ok_or_else(|| invalid("truncated deflate stream"))?,
+                );
+                input.pos += len;
+                if last {
+                    return Ok(out);
+                }
+                continue;
+            }
+            1 => {
+                let mut lengths = [8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+            }
+            2 => {
+                let lit_count = input.bits(5)? as usize + 257;
+                let dist_count = input.bits(5)? as usize + 1;
+                let code_len_count = input.bits(4)? as usize + 4;
+                let mut code_lengths = [0; 19];
+                for i in CODE_LENGTH_ORDER.iter().take(code_len_count) {
+                    code_lengths[*i] = input.bits(3)? as u8;
+                }
+                let code_length_code = Huffman::new(&code_lengths);
+                let mut lengths = vec![];
+                while lengths.len() < lit_count + dist_count {
+                    let (value, repeat) = match input.decode(&code_length_code)? {
+                        symbol @ 0..16 => (symbol as u8, 1),
+                        16 => (
+                            *lengths
+                                .last()
+                                .ok_or_else(|| invalid("invalid code lengths"))?,
+                            3 + input.bits(2)?,
+                        ),
+                        17 => (0, 3 + input.bits(3)?),
+                        _ => (0, 11 + input.bits(7)?),
+                    };
+                    lengths.extend(std::iter::repeat_n(value, repeat as usize));
+                }
+                if lengths.len() != lit_count + dist_count {
+                    return Err(invalid("invalid code lengths"));
+                }
+                (
+                    Huffman::new(&lengths[..lit_count]),
+                    Huffman::new(&lengths[lit_count..]),
+                )
+            }
+            _ => return Err(invalid("invalid deflate block type")),
+        };
+
+        loop {
+            let symbol = input.decode(&lit_code)? as usize;
+            if symbol < 256 {
+                out.push(symbol as u8);
+                continue;
+            } else if symbol == 256 {
+                break;
+            }
+            // Each length is followed by its extra bits, then the distance.
+            let symbol = symbol - 257;
+            if symbol >= LENGTH_BASE.len() {
+                return Err(invalid("invalid deflate length"));
+            }
+            let len =
+                LENGTH_BASE[symbol] as usize + input.bits(LENGTH_EXTRA[symbol] as u32)? as usize;
+            let symbol = input.decode(&dist_code)? as usize;
+            if symbol >= DIST_BASE.len() {
+                return Err(invalid("invalid deflate distance"));
+            }
+            let dist = DIST_BASE[symbol] as usize + input.bits(DIST_EXTRA[symbol] as u32)? as usize;
+            if dist > out.len() {
+                return Err(invalid("deflate distance too far back"));
+            }
+            // The match may overlap the bytes it produces.
+            let start = out.len() - dist;
+            for i in 0..len {
+                out.push(out[start + i]);
+            }
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+/// Predict a byte from its left, up and upper left neighbours with the Paeth filter.
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = (
+        (p - a as i16).abs(),
+        (p - b as i16).abs(),
+        (p - c as i16).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Read a non-interlaced grayscale, RGB or RGBA PNG with 8 or 16 bits per channel,
+/// like those [`write_png`] writes.
+/// Returns the size and the pixels with each channel scaled to [0, 1], still sRGB-encoded;
+/// alpha is 1 if the image has none.
+pub fn read_png(path: impl AsRef<Path>) -> io::Result<(u32, u32, Vec<Vec4>)> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let data = fs::read(path)?;
+    if !data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Err(invalid("not a PNG file"));
+    }
+
+    let mut pos = 8;
+    let mut header = None;
+    let mut compressed = vec![];
+    loop {
+        let chunk = data
+            .get(pos..pos + 8)
+            .ok_or_else(|| invalid("truncated PNG file"))?;
+        let len = u32::from_be_bytes(chunk[..4].try_into().unwrap()) as usize;
+        let body = data
+            .get(pos + 4..pos + 8 + len)
+            .ok_or_else(|| invalid("truncated PNG file"))?;
+        let crc = data
+            .get(pos + 8 + len..pos + 12 + len)
+            .ok_or_else(|| invalid("truncated PNG file"))?;
+        if crc32(body).to_be_bytes() != crc {
+            return Err(invalid("PNG chunk has the wrong CRC"));
+        }
+        let (kind, body) = body.split_at(4);
+        match kind {
+            b"IHDR" if body.len() == 13 => header = Some(body.to_vec()),
+            b"IDAT" => compressed.extend(body),
+            b"IEND" => break,
+            _ => (),
+        }
+        pos += 12 + len;
+    }
+
+    let header = header.ok_or_else(|| invalid("PNG file has no header"))?;
+    let width = u32::from_be_bytes(header[0..4].try_into().unwrap());
+    let height = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    let bit_depth = header[8];
+    let channels = match header[9] {
+        0 => 1,
+        2 => 3,
+        4 => 2,
+        6 => 4,
+        _ => return Err(invalid("unsupported PNG color type")),
+    };
+    if bit_depth != 8 && bit_depth != 16 {
+        return Err(invalid("unsupported PNG bit depth"));
+    }
+    if header[12] != 0 {
+        return Err(invalid("interlaced PNGs are not supported"));
+    }
+
+    let raw = inflate(&compressed)?;
+    let pixel_bytes = channels * bit_depth as usize / 8;
+    let row_bytes = width as usize * pixel_bytes;
+    if raw.len() != (row_bytes + 1) * height as usize {
+        return Err(invalid("PNG image data has the wrong size"));
+    }
+    let mut image = vec![0u8; row_bytes * height as usize];
+    for (y, row) in raw.chunks(row_bytes + 1).enumerate() {
+        let (filter, row) = (row[0], &row[1..]);
+        let (done, rest) = image.split_at_mut(y * row_bytes);
+        let prev = done.get(done.len().wrapping_sub(row_bytes)..);
+        let cur = &mut rest[..row_bytes];
+        for x in 0..row_bytes {
+            let left = if x >= pixel_bytes {
+                cur[x - pixel_bytes]
+            } else {
+                0
+            };
+            let up = prev.map_or(0, |f| f[x]);
+            let up_left = if x >= pixel_bytes {
+                prev.map_or(0, |f| f[x - pixel_bytes])
+            } else {
+                0
+            };
+            let predicted = match filter {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                4 => paeth(left, up, up_left),
+                _ => return Err(invalid("invalid PNG filter type")),
+            };
+            cur[x] = row[x].wrapping_add(predicted);
+        }
+    }
+
+    let max = ((1u32 << bit_depth) - 1) as f32;
+    let pixels = image
+        .chunks(pixel_bytes)
+        .map(|pixel| {
+            // Samples are stored big-endian.
+            let value = |i: usize| match bit_depth {
+                16 => u16::from_be_bytes([pixel[i * 2], pixel[i * 2 + 1]]) as f32 / max,
+                _ => pixel[i] as f32 / max,
+            };
+            match channels {
+                1 => Vec4::new(value(0), value(0), value(0), 1.0),
+                2 => Vec4::new(value(0), value(0), value(0), value(1)),
+                3 => Vec4::new(value(0), value(1), value(2), 1.0),
+                _ => Vec4::new(value(0), value(1), value(2), value(3)),
+            }
+        })
+        .collect();
+    Ok((width, height, pixels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inflate_huffman_blocks() {
+        // "hello hello hello hello" with fixed Huffman codes, as compressed by zlib.
+        let fixed = [
+            120, 218, 203, 72, 205, 201, 201, 87, 200, 64, 39, 1, 104, 3, 8, 177,
+        ];
+        assert_eq!(inflate(&fixed).unwrap(), b"hello hello hello hello");
+
+        // Runs of repeated letters with dynamic Huffman codes, as compressed by zlib.
+        let dynamic = [
+            120, 218, 189, 202, 65, 17, 0, 48, 12, 2, 48, 173, 20, 74, 241, 175, 96, 168, 216, 51,
+            119, 65, 114, 119, 222, 149, 196, 25, 0, 181, 237, 154, 100, 157, 4, 31, 207, 3, 10,
+            28, 56, 153,
+        ];
+        let runs: Vec<u8> = (0..18)
+            .flat_map(|i| vec![b'a' + (i * 7 % 8) as u8; 1 + i % 3])
+            .collect();
+        assert_eq!(inflate(&dynamic).unwrap(), runs.repeat(4));
+    }
+
+    #[test]
+    fn read_png_unfilters_rows() {
+        // 3x4 8-bit grayscale, each row with a different filter type.
+        let raw = [
+            1, 10, 190, 86, //
+            2, 10, 236, 30, //
+            3, 251, 158, 201, //
+            4, 2, 13, 248,
+        ];
+        let mut header = vec![];
+        header.extend(3u32.to_be_bytes());
+        header.extend(4u32.to_be_bytes());
+        header.extend([8, 0, 0, 0, 0]);
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        write_chunk(&mut data, b"IHDR", &header).unwrap();
+        write_chunk(&mut data, b"IDAT", &zlib_stored(&raw)).unwrap();
+        write_chunk(&mut data, b"IEND", &[]).unwrap();
+
+        let path = std::env::temp_dir().join(format!("hardrtx-test-{}.png", std::process::id()));
+        fs::write(&path, data).unwrap();
+        let loaded = read_png(&path);
+        fs::remove_file(&path).unwrap();
+        let (width, height, pixels) = loaded.unwrap();
+        assert_eq!((width, height), (3, 4));
+        let values: Vec<_> = pixels.iter().map(|f| (f.x * 255.0).round() as u8).collect();
+        assert_eq!(values, [10, 200, 30, 20, 180, 60, 5, 250, 100, 7, 7, 255]);
+        assert!(pixels.iter().all(|f| f.x == f.z && f.w == 1.0));
+    }
+}
@@ -1,15 +1,38 @@
 #![allow(dead_code)]
 
+mod accumulation;
+mod compare;
+mod cpu;
+mod export;
 mod mesh;
+mod ply;
+mod profile;
 mod scene;
 mod shader_buffer;
+mod texture;
 
-use glam::{Mat4, Quat, Vec3};
-use obj::Obj;
+use accumulation::{AccumulationAction, AccumulationBuffer, AccumulationPolicy, SceneChange};
+use export::AlphaMode;
+use glam::{Mat4, Quat, Vec2, Vec3, Vec4};
+use log::{debug, error, info, warn, Level, LevelFilter, Log, Metadata, Record};
+use mesh::BvhConfig;
+use profile::FrameProfiler;
 use scene::*;
-use shader_buffer::GpuScene;
+use shader_buffer::{GpuObjectType, GpuScene, GpuVec4};
 use smallvec::SmallVec;
-use std::{collections::HashSet, f32::consts::PI, ops::Range, process::Command, sync::Arc};
+use std::{
+    collections::HashSet,
+    error::Error,
+    f32::consts::PI,
+    fmt,
+    io::ErrorKind,
+    ops::Range,
+    path::Path,
+    process::Command,
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use texture::{GpuTexture, Texture};
 use vulkano::{
     buffer::*,
     command_buffer::{allocator::*, *},
@@ -32,14 +55,14 @@ use vulkano::{
     shader::*,
     swapchain::{self, *},
     sync::GpuFuture,
-    VulkanLibrary,
+    DeviceSize, Version, VulkanLibrary,
 };
 use winit::{
     application::ApplicationHandler,
     dpi::{PhysicalSize, Size},
-    event::WindowEvent,
+    event::{ElementState, MouseButton, MouseScrollDelta, WindowEvent},
     event_loop::{ActiveEventLoop, ControlFlow, EventLoop},
-    keyboard::KeyCode,
+    keyboard::{KeyCode, PhysicalKey},
     window::{Window, WindowAttributes},
 };
 
@@ -56,26 +79,167 @@ struct Context {
     render_pass: Option<Arc<RenderPass>>,
     gfx_pipeline: Option<Arc<GraphicsPipeline>>,
     rt_pipeline: Option<Arc<ComputePipeline>>,
+    /// Format of the ray-tracing images, see [`select_rt_format`].
+    rt_format: Format,
     rt_samples: Option<Arc<Image>>,
+    /// Per-pixel sum of squared samples, used to estimate variance for adaptive sampling.
+    rt_variance: Option<Arc<Image>>,
+    /// Per-pixel sum of the traversal cost of each sample, for the cost overlay.
+    rt_cost: Option<Arc<Image>>,
+    /// Per-pixel fixed-point RGB that caustics photons deposited since the pixel's last sample.
+    rt_caustics: Option<Subbuffer<[u32]>>,
+    /// Variance, cost and caustics buffers of the last caller-provided image ray traced into.
+    rt_scratch: Option<RtScratch>,
+    /// Cubemap sampled by escaped rays; a black placeholder if the skybox has none.
+    skybox_cubemap: Option<GpuTexture>,
+    /// Diffuse irradiance convolved from `skybox_cubemap`.
+    skybox_irradiance: Option<GpuTexture>,
+    /// Fraction of the window resolution that is ray traced; below 1 the result is upscaled.
+    render_scale: f32,
+    upscale_pipeline: Option<Arc<ComputePipeline>>,
+    /// Window-resolution upscale of `rt_samples`, shown instead of it when `render_scale` is below 1.
+    rt_upscaled: Option<Arc<Image>>,
+    /// Primary ray object IDs and depths at the window and render resolutions, guiding the upscale.
+    rt_guides: Option<[Arc<Image>; 2]>,
+    luminance_pipeline: Option<Arc<ComputePipeline>>,
+    /// Sum of the log luminance, pixel count, sum of the luminance and maximum luminance
+    /// of each 16x16 tile of `rt_samples`, written by the luminance reduction.
+    luminance: Option<Subbuffer<[[f32; 4]]>>,
     desc_alloc: Option<Arc<StandardDescriptorSetAllocator>>,
     cmd_alloc: Option<Arc<StandardCommandBufferAllocator>>,
 }
 
+/// Buffers the ray tracer needs besides the samples, for an image other than `rt_samples`,
+/// so ray tracing into it doesn't disturb the window's accumulation; see [`raytrace_into`].
+struct RtScratch {
+    /// Image these buffers belong to.
+    target: Arc<Image>,
+    variance: Arc<Image>,
+    cost: Arc<Image>,
+    caustics: Subbuffer<[u32]>,
+}
+
 /// Struct of push constants for the fragment shader.
 #[repr(C)]
 #[derive(Debug, Copy, Clone, BufferContents)]
 struct FragParams {
     frame_counter: u32,
+    /// Whether to draw the sample count / FPS overlay.
+    show_hud: u32,
+    /// Frames per second shown by the overlay.
+    fps: f32,
+    /// Opacity of the traversal cost overlay; 0 hides it.
+    cost_blend: f32,
+    /// Traversal cost shown as the hottest color.
+    cost_scale: f32,
+    /// Whether to show signed luminance in false color instead of the tone-mapped image.
+    signed_display: u32,
+    /// Working color space of the image, see [`export::ColorSpace`].
+    color_space: u32,
+    /// Factor applied to the image before tone mapping.
+    exposure: f32,
+    /// Output variable in the image, see [`Aov`]; anything but the beauty pass is shown without tone mapping.
+    aov: u32,
 }
 
+// The fields must be in the same order as the `pc` block in frag.frag.
+const _: () = assert!(std::mem::size_of::<FragParams>() == 36);
+const _: () = assert!(std::mem::offset_of!(FragParams, exposure) == 28);
+const _: () = assert!(std::mem::offset_of!(FragParams, aov) == 32);
+
 /// Push constants for the ray tracer.
 #[repr(C)]
 #[derive(Copy, Clone, BufferContents)]
 struct RtPushConst {
+    /// Top-left corner of the region to render.
+    region_offset: [u32; 2],
+    /// Size of the region to render.
+    region_size: [u32; 2],
+    // The parameters start with a matrix, which must stay 16-byte aligned.
     params: RtParams,
     object_count: u32,
+    /// Whether to render objects as their world-space bounding boxes.
+    bounds_preview: u32,
+    /// Whether to write the ID of the object hit by each pixel's primary ray instead of a sample.
+    object_ids: u32,
+    /// Bitmask of object types, indexed by [`GpuObjectType`], that rays don't intersect.
+    disabled_types: u32,
+    /// Whether to record the number of BVH nodes visited and primitives tested per pixel.
+    trace_cost: u32,
+    /// Whether negative emission subtracts light instead of being clamped to 0.
+    negative_emission: u32,
+    /// Index of the only object rays intersect, or `u32::MAX` to intersect all of them.
+    solo_object: u32,
+    /// Whether the workgroups are laid out as by [`morton_dispatch`] instead of one per tile.
+    morton_order: u32,
+}
+
+// Most devices allow 256 bytes of push constants, which the clamp fields fill exactly.
+const _: () = assert!(std::mem::size_of::<RtPushConst>() <= 256);
+
+/// Output variable the ray tracer writes instead of the accumulated image.
+/// Other than the beauty pass, each is written unjittered and without accumulation.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+enum Aov {
+    /// The path-traced image.
+    #[default]
+    Beauty,
+    /// World-space normal facing the camera.
+    Normal,
+    /// Distance along the camera's view direction.
+    Depth,
+    /// Texture coordinates.
+    Uv,
+    /// Index of the object hit plus one, and the distance, as with [`RtDebugOptions::object_ids`].
+    ObjectId,
+    /// Index into the object's material table plus one, or 0 for the object's own material.
+    MaterialId,
+    /// Surface color before lighting.
+    Albedo,
+}
+
+impl Aov {
+    /// Every output variable, in the order they are cycled through.
+    const ALL: [Aov; 7] = [
+        Aov::Beauty,
+        Aov::Normal,
+        Aov::Depth,
+        Aov::Uv,
+        Aov::ObjectId,
+        Aov::MaterialId,
+        Aov::Albedo,
+    ];
+
+    /// Get the next output variable, wrapping around to the beauty pass.
+    fn next(self) -> Aov {
+        Self::ALL[(self as usize + 1) % Self::ALL.len()]
+    }
 }
 
+/// Debugging options for the ray tracer.
+#[derive(Debug, Default, Copy, Clone)]
+struct RtDebugOptions {
+    /// Render objects as their world-space bounding boxes.
+    bounds_preview: bool,
+    /// Write `[object index + 1 or 0 on a miss, distance, 0, 1]` for each pixel's unjittered
+    /// primary ray instead of accumulating a sample.
+    object_ids: bool,
+    /// Bitmask of object types, indexed by [`GpuObjectType`], to skip; used to profile their cost.
+    disabled_types: u32,
+    /// Record the traversal cost of each pixel into `rt_cost`.
+    trace_cost: bool,
+    /// Let negative emission subtract light, to isolate a light's contribution.
+    negative_emission: bool,
+    /// Render only this object, as in [`Scene::objects`], and the skybox.
+    solo_object: Option<usize>,
+    /// Dispatch the 8x8 tiles in Morton order instead of raster order, to compare their cache behavior.
+    morton_order: bool,
+}
+
+/// Maximum number of clip planes; must match `maxClipPlanes` in the shader.
+const MAX_CLIP_PLANES: usize = 3;
+
 /// Parameters for the ray tracer.
 #[repr(C)]
 #[derive(Copy, Clone, BufferContents)]
@@ -90,6 +254,98 @@ struct RtParams {
     max_bounce: u32,
     /// How many rays to cast per sample.
     ray_count: u32,
+    /// Variance of a pixel's mean luminance below which it stops receiving samples; 0 disables adaptive sampling.
+    variance_threshold: f32,
+    /// Whether samples are accumulated (nonzero) or only the latest sample is shown (0).
+    accumulate: u32,
+    /// Factor applied to the accumulated samples before adding new ones; 1 keeps them all.
+    history_scale: f32,
+    /// Minimum weight of each new sample, turning accumulation into an exponential moving average; 0 disables it.
+    history_alpha: f32,
+    /// Width / height of the view; 0 uses the aspect ratio of the image being rendered.
+    aspect: f32,
+    /// Eye separation for side-by-side stereo, with the left eye in the left half of the image; 0 renders a single view.
+    stereo: f32,
+    /// Camera distance at which mesh instances switch to their first LOD, doubling for each further LOD;
+    /// 0 always uses the full mesh. CPU picking always uses the full mesh.
+    lod_distance: f32,
+    /// Number of entries of `clip_planes` in use.
+    clip_plane_count: u32,
+    /// Camera rays ignore geometry where `dot(plane.xyz, pos) + plane.w < 0`; bounces see everything.
+    /// CPU picking ignores the clip planes. This must stay 16-byte aligned within the push constants.
+    clip_planes: [GpuVec4; MAX_CLIP_PLANES],
+    /// Output variable to write instead of accumulating samples, see [`Aov`].
+    aov: u32,
+    /// Whether to draw the edges of mesh triangles seen by camera rays.
+    wireframe: u32,
+    /// Whether the render region is projected as a view of its own, as if it were the whole image,
+    /// instead of showing its part of the full view; see [`split_views`].
+    region_is_view: u32,
+    /// Whether photons traced from the suns through mirror-like and transparent surfaces deliver caustics
+    /// on fully diffuse surfaces, instead of relying on camera paths to find the sun. Single views only.
+    caustics: u32,
+    /// Color of the wireframe, with its opacity over the shaded surface in `w`.
+    wireframe_color: GpuVec4,
+    /// Density of the fog that scatters sunlight into camera rays, drawing shafts of light
+    /// behind occluders; 0 disables god rays. It also dims what the camera sees through it.
+    god_ray_density: f32,
+    /// Number of shadow-tested steps god rays take along each camera ray segment.
+    god_ray_steps: u32,
+    /// Maximum luminance of the light a path picks up after its first bounce; 0 disables clamping.
+    /// Light seen directly by camera rays is never clamped.
+    bounce_clamp: f32,
+    /// Factor the clamp is multiplied by for each further bounce, clamping the noisier deep bounces harder.
+    bounce_clamp_falloff: f32,
+}
+
+// Rust pads the struct to its 16-byte alignment, so the fields following it in the push constants
+// only line up with the shader's if there is no implicit padding; the clamp fields fill what used to be padding.
+const _: () = assert!(std::mem::size_of::<RtParams>().is_multiple_of(16));
+
+impl RtParams {
+    /// Place the camera; `transform` maps camera space to world space, e.g. [`Transform::look_at`].
+    fn set_camera(&mut self, transform: &Transform) {
+        self.cam_matrix = transform.matrix().to_cols_array();
+    }
+
+    /// Set the clip planes, keeping the first [`MAX_CLIP_PLANES`].
+    /// Each plane is its normal in `xyz` and offset in `w`; geometry on the normal's side is kept.
+    fn set_clip_planes(&mut self, planes: &[Vec4]) {
+        let count = planes.len().min(MAX_CLIP_PLANES);
+        for (i, plane) in self.clip_planes.iter_mut().enumerate() {
+            *plane = planes.get(i).copied().unwrap_or(Vec4::ZERO).into();
+        }
+        self.clip_plane_count = count as u32;
+    }
+
+    /// Get the unjittered world-space primary ray through pixel `coords` of an image of size `extent`,
+    /// the same as the shader generates.
+    fn primary_ray(&self, extent: [u32; 2], coords: Vec2) -> Ray {
+        let cam_matrix = Mat4::from_cols_array(&self.cam_matrix);
+        let mut pos = cam_matrix.transform_point3(Vec3::ZERO);
+        let mut coords = coords;
+        let mut half_size = Vec2::new(extent[0] as f32, extent[1] as f32) * 0.5;
+        if self.stereo > 0.0 {
+            let right_eye = coords.x >= (extent[0] / 2) as f32;
+            half_size.x = (extent[0] / 2) as f32 * 0.5;
+            if right_eye {
+                coords.x -= (extent[0] / 2) as f32;
+            }
+            pos += cam_matrix.x_axis.truncate() * self.stereo * if right_eye { 0.5 } else { -0.5 };
+        }
+        let aspect = if self.aspect > 0.0 {
+            self.aspect
+        } else {
+            half_size.x / half_size.y
+        };
+        let view_pos = (coords - half_size) / half_size * Vec2::new(aspect, 1.0) * self.cam_v_fov;
+        Ray {
+            pos,
+            normal: cam_matrix
+                .transform_vector3(view_pos.extend(1.0))
+                .normalize(),
+        }
+    }
 }
 
 /// Load a SPIR-V shader from a file.
@@ -114,6 +370,108 @@ fn load_shader(
     unsafe { ShaderModule::new(device, ShaderModuleCreateInfo::new(buf.as_slice())) }
 }
 
+/// Pick the format of the ray-tracing images: 32-bit float if the device can use it for storage images,
+/// or 16-bit float otherwise, which loses precision once many samples are accumulated.
+fn select_rt_format(physical_device: &PhysicalDevice) -> Format {
+    let required =
+        FormatFeatures::STORAGE_IMAGE | FormatFeatures::TRANSFER_SRC | FormatFeatures::TRANSFER_DST;
+    let format = [Format::R32G32B32A32_SFLOAT, Format::R16G16B16A16_SFLOAT]
+        .into_iter()
+        .find(|format| {
+            physical_device
+                .format_properties(*format)
+                .is_ok_and(|f| f.optimal_tiling_features.contains(required))
+        })
+        .expect("no supported storage image format for ray tracing");
+    if format == Format::R32G32B32A32_SFLOAT {
+        info!("Using {:?} for ray-tracing images", format);
+    } else {
+        warn!(
+            "R32G32B32A32_SFLOAT storage images are unsupported; falling back to {:?}",
+            format
+        );
+    }
+    format
+}
+
+/// Whether a Vulkan library is installed and reports at least one physical device.
+fn vulkan_available() -> bool {
+    VulkanLibrary::new()
+        .ok()
+        .and_then(|lib| Instance::new(lib, InstanceCreateInfo::default()).ok())
+        .and_then(|inst| inst.enumerate_physical_devices().ok())
+        .is_some_and(|mut f| f.next().is_some())
+}
+
+/// Read-only summary of the device the renderer runs on, for bug reports and enabling optional features.
+#[derive(Debug, Clone)]
+struct DeviceInfo {
+    name: String,
+    device_type: PhysicalDeviceType,
+    /// Driver version, encoded as the vendor chooses.
+    driver_version: u32,
+    /// Driver name and version string, if the device reports one.
+    driver_info: Option<String>,
+    api_version: Version,
+    /// Names of the enabled device extensions.
+    enabled_extensions: Vec<&'static str>,
+    /// Largest range of a storage buffer binding, in bytes.
+    max_storage_buffer_range: u32,
+    /// Largest size of a compute workgroup along each axis.
+    max_compute_work_group_size: [u32; 3],
+    /// Largest number of invocations in a compute workgroup.
+    max_compute_work_group_invocations: u32,
+    /// Format of the ray-tracing images, see [`select_rt_format`].
+    rt_format: Format,
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} ({:?})", self.name, self.device_type)?;
+        writeln!(
+            f,
+            "driver version {:#x}{}, Vulkan {}",
+            self.driver_version,
+            self.driver_info
+                .as_ref()
+                .map_or(String::new(), |f| format!(" ({})", f)),
+            self.api_version
+        )?;
+        writeln!(f, "extensions: {}", self.enabled_extensions.join(", "))?;
+        writeln!(
+            f,
+            "max storage buffer range {}, max workgroup size {:?} with {} invocations",
+            self.max_storage_buffer_range,
+            self.max_compute_work_group_size,
+            self.max_compute_work_group_invocations
+        )?;
+        write!(f, "ray-tracing images in {:?}", self.rt_format)
+    }
+}
+
+/// Describe the device `ctx` was created on.
+fn device_info(ctx: &Context) -> DeviceInfo {
+    let properties = ctx.device.physical_device().properties();
+    DeviceInfo {
+        name: properties.device_name.clone(),
+        device_type: properties.device_type,
+        driver_version: properties.driver_version,
+        driver_info: properties.driver_info.clone(),
+        api_version: ctx.device.api_version(),
+        enabled_extensions: ctx
+            .device
+            .enabled_extensions()
+            .into_iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| name)
+            .collect(),
+        max_storage_buffer_range: properties.max_storage_buffer_range,
+        max_compute_work_group_size: properties.max_compute_work_group_size,
+        max_compute_work_group_invocations: properties.max_compute_work_group_invocations,
+        rt_format: ctx.rt_format,
+    }
+}
+
 /// Select the most suitable physical device and queues.
 fn select_device(
     vlk_inst: &Arc<Instance>,
@@ -155,6 +513,11 @@ fn select_device(
         })
         .expect("no suitable physical device found");
 
+    let enabled_extensions = DeviceExtensions {
+        ext_memory_budget: shader_buffer::supports_memory_budget(&physical_device),
+        ..device_extensions
+    };
+
     Device::new(
         physical_device,
         DeviceCreateInfo {
@@ -162,7 +525,7 @@ fn select_device(
                 queue_family_index,
                 ..Default::default()
             }],
-            enabled_extensions: device_extensions,
+            enabled_extensions,
             ..Default::default()
         },
     )
@@ -262,7 +625,7 @@ fn recreate_swapchain(ctx: &mut Context, window_size: [u32; 2]) {
 }
 
 /// Draw a single frame.
-fn draw(ctx: &mut Context, frame_counter: u32) {
+fn draw(ctx: &mut Context, params: FragParams) {
     // Get an image to render to from the swapchain.
     let next_img = swapchain::acquire_next_image(ctx.swapchain.clone().unwrap(), None).unwrap();
     let index = next_img.0 as usize;
@@ -271,10 +634,17 @@ fn draw(ctx: &mut Context, frame_counter: u32) {
     let desc_set = DescriptorSet::new(
         ctx.desc_alloc.clone().unwrap(),
         ctx.gfx_pipeline.as_ref().unwrap().layout().set_layouts()[0].clone(),
-        [WriteDescriptorSet::image_view(
-            0,
-            ImageView::new_default(ctx.rt_samples.clone().unwrap()).unwrap(),
-        )],
+        [
+            WriteDescriptorSet::image_view(
+                0,
+                ImageView::new_default(ctx.rt_upscaled.clone().or(ctx.rt_samples.clone()).unwrap())
+                    .unwrap(),
+            ),
+            WriteDescriptorSet::image_view(
+                1,
+                ImageView::new_default(ctx.rt_cost.clone().unwrap()).unwrap(),
+            ),
+        ],
         [],
     )
     .unwrap();
@@ -307,7 +677,7 @@ fn draw(ctx: &mut Context, frame_counter: u32) {
         .push_constants(
             ctx.gfx_pipeline.as_ref().unwrap().layout().clone(),
             0,
-            FragParams { frame_counter },
+            params,
         )
         .unwrap()
         .set_viewport(
@@ -354,25 +724,623 @@ fn draw(ctx: &mut Context, frame_counter: u32) {
         .unwrap();
 }
 
-/// Create the ray-tracing samples buffer.
+/// Create an image to accumulate ray-tracing samples into.
+fn create_rt_image(ctx: &Context, extent: [u32; 2]) -> Arc<Image> {
+    Image::new(
+        ctx.allocator.clone(),
+        ImageCreateInfo {
+            extent: [extent[0], extent[1], 1],
+            format: ctx.rt_format,
+            usage: ImageUsage::STORAGE
+                | ImageUsage::INPUT_ATTACHMENT
+                | ImageUsage::TRANSFER_SRC
+                | ImageUsage::TRANSFER_DST,
+            ..Default::default()
+        },
+        AllocationCreateInfo::default(),
+    )
+    .unwrap()
+}
+
+/// Create the buffer caustics photons deposit into for an image of size `extent`.
+fn create_caustics_buffer(ctx: &Context, extent: [u32; 2]) -> Subbuffer<[u32]> {
+    Buffer::new_slice(
+        ctx.allocator.clone(),
+        BufferCreateInfo {
+            usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_DST,
+            ..Default::default()
+        },
+        AllocationCreateInfo {
+            memory_type_filter: MemoryTypeFilter::PREFER_DEVICE,
+            ..Default::default()
+        },
+        extent[0] as DeviceSize * extent[1] as DeviceSize * 3,
+    )
+    .unwrap()
+}
+
+/// Create the ray-tracing samples and variance buffers for a window of size `extent`,
+/// plus the upscaling images if rendering at a lower resolution.
 fn create_rt_samples(ctx: &mut Context, extent: [u32; 2]) {
-    ctx.rt_samples = Some(
-        Image::new(
+    let scaled = extent.map(|f| ((f as f32 * ctx.render_scale) as u32).max(1));
+    ctx.rt_samples = Some(create_rt_image(ctx, scaled));
+    ctx.rt_variance = Some(create_rt_image(ctx, scaled));
+    ctx.rt_cost = Some(create_rt_image(ctx, scaled));
+    ctx.rt_caustics = Some(create_caustics_buffer(ctx, scaled));
+    ctx.luminance = Some(
+        Buffer::new_slice(
             ctx.allocator.clone(),
-            ImageCreateInfo {
-                extent: [extent[0], extent[1], 1],
-                format: Format::R32G32B32A32_SFLOAT,
-                usage: ImageUsage::STORAGE | ImageUsage::INPUT_ATTACHMENT,
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
                 ..Default::default()
             },
-            AllocationCreateInfo::default(),
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                ..Default::default()
+            },
+            luminance_groups(scaled).into_iter().product::<u32>() as DeviceSize,
         )
         .unwrap(),
     );
+    if scaled != extent {
+        ctx.rt_upscaled = Some(create_rt_image(ctx, extent));
+        ctx.rt_guides = Some([create_rt_image(ctx, extent), create_rt_image(ctx, scaled)]);
+    } else {
+        ctx.rt_upscaled = None;
+        ctx.rt_guides = None;
+    }
+    clear_rt_samples(ctx).unwrap();
+}
+
+/// Get the parameters and `[x, y, w, h]` regions that render a split view into an image of size `extent`,
+/// with the first view in the left half. Both views accumulate along with `params`.
+fn split_views(
+    split_view: &(RtParams, RtParams),
+    params: &RtParams,
+    extent: [u32; 2],
+) -> [(RtParams, [u32; 4]); 2] {
+    let view = |view: &RtParams| RtParams {
+        frame_counter: params.frame_counter,
+        accumulate: params.accumulate,
+        history_scale: params.history_scale,
+        history_alpha: params.history_alpha,
+        region_is_view: 1,
+        ..*view
+    };
+    let half = extent[0] / 2;
+    [
+        (view(&split_view.0), [0, 0, half, extent[1]]),
+        (view(&split_view.1), [half, 0, extent[0] - half, extent[1]]),
+    ]
+}
+
+/// Trace the primary rays' object IDs and depths that guide the upscale, if rendering at a lower resolution.
+fn render_upscale_guides(
+    ctx: &mut Context,
+    params: &RtParams,
+    split_view: Option<&(RtParams, RtParams)>,
+    debug: RtDebugOptions,
+    scene: &GpuScene,
+) -> Result<(), Box<dyn Error>> {
+    let Some(guides) = ctx.rt_guides.clone() else {
+        return Ok(());
+    };
+    let params = RtParams {
+        frame_counter: 1,
+        aov: Aov::Beauty as u32,
+        ..*params
+    };
+    let debug = RtDebugOptions {
+        object_ids: true,
+        disabled_types: debug.disabled_types,
+        solo_object: debug.solo_object,
+        morton_order: debug.morton_order,
+        ..Default::default()
+    };
+    for guide in guides {
+        let Some(split_view) = split_view else {
+            raytrace_into(ctx, guide, &params, None, debug, scene)?;
+            continue;
+        };
+        let extent = [guide.extent()[0], guide.extent()[1]];
+        for (view, region) in split_views(split_view, &params, extent) {
+            let view = RtParams {
+                aov: Aov::Beauty as u32,
+                ..view
+            };
+            raytrace_into(ctx, guide.clone(), &view, Some(region), debug, scene)?;
+        }
+    }
+    Ok(())
+}
+
+/// Upscale `rt_samples` into `rt_upscaled` with a joint-bilateral filter, if rendering at a lower resolution.
+fn upscale(ctx: &Context) -> Result<(), Box<dyn Error>> {
+    let (Some(output), Some([full_guide, low_guide])) =
+        (ctx.rt_upscaled.clone(), ctx.rt_guides.clone())
+    else {
+        return Ok(());
+    };
+    let pipeline = ctx.upscale_pipeline.clone().unwrap();
+    let desc_set = DescriptorSet::new(
+        ctx.desc_alloc.clone().unwrap(),
+        pipeline.layout().set_layouts()[0].clone(),
+        [
+            WriteDescriptorSet::image_view(
+                0,
+                ImageView::new_default(ctx.rt_samples.clone().unwrap())?,
+            ),
+            WriteDescriptorSet::image_view(1, ImageView::new_default(low_guide)?),
+            WriteDescriptorSet::image_view(2, ImageView::new_default(full_guide)?),
+            WriteDescriptorSet::image_view(3, ImageView::new_default(output.clone())?),
+        ],
+        [],
+    )?;
+    let mut cmd_buf = AutoCommandBufferBuilder::primary(
+        ctx.cmd_alloc.clone().unwrap(),
+        ctx.queues[0].clone().queue_family_index(),
+        CommandBufferUsage::OneTimeSubmit,
+    )?;
+    cmd_buf
+        .bind_pipeline_compute(pipeline.clone())?
+        .bind_descriptor_sets(
+            PipelineBindPoint::Compute,
+            pipeline.layout().clone(),
+            0,
+            desc_set,
+        )?;
+    unsafe {
+        cmd_buf.dispatch([
+            output.extent()[0].div_ceil(8),
+            output.extent()[1].div_ceil(8),
+            1,
+        ])
+    }?;
+    cmd_buf
+        .build()?
+        .execute(ctx.queues[0].clone())?
+        .then_signal_fence_and_flush()?
+        .wait(None)?;
+    Ok(())
+}
+
+/// Zero the ray-tracing samples, variance, cost and caustics buffers, discarding all accumulated samples.
+fn clear_rt_samples(ctx: &Context) -> Result<(), Box<dyn Error>> {
+    let images = [
+        ctx.rt_samples.clone(),
+        ctx.rt_variance.clone(),
+        ctx.rt_cost.clone(),
+    ];
+    clear_rt_buffers(ctx, images.map(Option::unwrap), ctx.rt_caustics.clone())
+}
+
+/// Zero ray-tracing images and, if given, a caustics buffer.
+fn clear_rt_buffers(
+    ctx: &Context,
+    images: impl IntoIterator<Item = Arc<Image>>,
+    caustics: Option<Subbuffer<[u32]>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut cmd_buf = AutoCommandBufferBuilder::primary(
+        ctx.cmd_alloc.clone().unwrap(),
+        ctx.queues[0].clone().queue_family_index(),
+        CommandBufferUsage::OneTimeSubmit,
+    )?;
+    for image in images {
+        cmd_buf.clear_color_image(ClearColorImageInfo {
+            clear_value: ClearColorValue::Float([0.0; 4]),
+            ..ClearColorImageInfo::image(image)
+        })?;
+    }
+    if let Some(caustics) = caustics {
+        cmd_buf.fill_buffer(caustics, 0)?;
+    }
+    cmd_buf
+        .build()?
+        .execute(ctx.queues[0].clone())?
+        .then_signal_fence_and_flush()?
+        .wait(None)?;
+    Ok(())
+}
+
+/// Read back the raw contents of a ray-tracing image.
+/// Returns the image size and the pixels.
+fn read_rt_image(
+    ctx: &Context,
+    image: Arc<Image>,
+) -> Result<([u32; 2], Vec<Vec4>), Box<dyn Error>> {
+    let extent = [image.extent()[0], image.extent()[1]];
+    let buffer = Buffer::new_slice::<u8>(
+        ctx.allocator.clone(),
+        BufferCreateInfo {
+            usage: BufferUsage::TRANSFER_DST,
+            ..Default::default()
+        },
+        AllocationCreateInfo {
+            memory_type_filter: MemoryTypeFilter::HOST_RANDOM_ACCESS,
+            ..Default::default()
+        },
+        extent[0] as DeviceSize * extent[1] as DeviceSize * image.format().block_size(),
+    )?;
+    let half = image.format() == Format::R16G16B16A16_SFLOAT;
+
+    let mut cmd_buf = AutoCommandBufferBuilder::primary(
+        ctx.cmd_alloc.clone().unwrap(),
+        ctx.queues[0].clone().queue_family_index(),
+        CommandBufferUsage::OneTimeSubmit,
+    )?;
+    cmd_buf.copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(image, buffer.clone()))?;
+    cmd_buf
+        .build()?
+        .execute(ctx.queues[0].clone())?
+        .then_signal_fence_and_flush()?
+        .wait(None)?;
+
+    let data = buffer.read()?;
+    let channels: Vec<f32> = if half {
+        data.chunks(2)
+            .map(|f| export::f16_to_f32(u16::from_ne_bytes([f[0], f[1]])))
+            .collect()
+    } else {
+        data.chunks(4)
+            .map(|f| f32::from_ne_bytes([f[0], f[1], f[2], f[3]]))
+            .collect()
+    };
+    let pixels = channels.chunks(4).map(Vec4::from_slice).collect();
+    Ok((extent, pixels))
+}
+
+/// Overwrite the contents of a ray-tracing image.
+fn write_rt_image(ctx: &Context, image: Arc<Image>, pixels: &[Vec4]) -> Result<(), Box<dyn Error>> {
+    let half = image.format() == Format::R16G16B16A16_SFLOAT;
+    let data: Vec<u8> = pixels
+        .iter()
+        .flat_map(|f| f.to_array())
+        .flat_map(|f| {
+            if half {
+                export::f32_to_f16(f).to_ne_bytes().to_vec()
+            } else {
+                f.to_ne_bytes().to_vec()
+            }
+        })
+        .collect();
+    let buffer = Buffer::from_iter(
+        ctx.allocator.clone(),
+        BufferCreateInfo {
+            usage: BufferUsage::TRANSFER_SRC,
+            ..Default::default()
+        },
+        AllocationCreateInfo {
+            memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+            ..Default::default()
+        },
+        data,
+    )?;
+
+    let mut cmd_buf = AutoCommandBufferBuilder::primary(
+        ctx.cmd_alloc.clone().unwrap(),
+        ctx.queues[0].clone().queue_family_index(),
+        CommandBufferUsage::OneTimeSubmit,
+    )?;
+    cmd_buf.copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(buffer, image))?;
+    cmd_buf
+        .build()?
+        .execute(ctx.queues[0].clone())?
+        .then_signal_fence_and_flush()?
+        .wait(None)?;
+    Ok(())
+}
+
+/// Read back the raw accumulation state, so it can be saved and resumed or merged later.
+fn export_accumulation(
+    ctx: &Context,
+    frame_counter: u32,
+    seed: u32,
+) -> Result<AccumulationBuffer, Box<dyn Error>> {
+    let (extent, samples) = read_rt_image(ctx, ctx.rt_samples.clone().unwrap())?;
+    let (_, variance) = read_rt_image(ctx, ctx.rt_variance.clone().unwrap())?;
+    Ok(AccumulationBuffer {
+        extent,
+        frame_counter,
+        seed,
+        samples,
+        variance,
+    })
+}
+
+/// Replace the accumulation state; returns the frame counter to continue from.
+/// The buffer must be the same size as the ray-tracing images.
+fn import_accumulation(ctx: &Context, accum: &AccumulationBuffer) -> Result<u32, Box<dyn Error>> {
+    let image = ctx.rt_samples.clone().unwrap();
+    if accum.extent != [image.extent()[0], image.extent()[1]] {
+        return Err(format!(
+            "Accumulation buffer is {}x{}, but the image is {}x{}",
+            accum.extent[0],
+            accum.extent[1],
+            image.extent()[0],
+            image.extent()[1]
+        )
+        .into());
+    }
+    write_rt_image(ctx, image, &accum.samples)?;
+    write_rt_image(ctx, ctx.rt_variance.clone().unwrap(), &accum.variance)?;
+    Ok(accum.frame_counter)
+}
+
+/// Load one or more saved accumulation buffers, merge them and resume accumulating from the result.
+fn resume_accumulation(ctx: &Context, paths: &[String]) -> Result<u32, Box<dyn Error>> {
+    let mut accum = AccumulationBuffer::load(&paths[0])?;
+    for path in &paths[1..] {
+        accum.merge(&AccumulationBuffer::load(path)?)?;
+    }
+    import_accumulation(ctx, &accum)
+}
+
+/// Read back the accumulated ray-tracing samples, averaged per pixel.
+/// Returns the image size and linear premultiplied pixels, which are currently always opaque.
+fn read_rt_samples(ctx: &Context) -> Result<([u32; 2], Vec<Vec4>), Box<dyn Error>> {
+    let (extent, pixels) = read_rt_image(ctx, ctx.rt_samples.clone().unwrap())?;
+    // The fourth channel holds the number of samples for each pixel.
+    let pixels = pixels
+        .iter()
+        .map(|f| (f.truncate() / f.w.max(1.0)).extend(1.0))
+        .collect();
+    Ok((extent, pixels))
+}
+
+/// Fire one primary ray per pixel on both the GPU and the CPU and compare the objects they hit.
+/// Returns the fraction of pixels that disagree.
+fn self_test(
+    ctx: &mut Context,
+    params: &RtParams,
+    cpu_scene: &Scene,
+    gpu_scene: &GpuScene,
+) -> Result<f32, Box<dyn Error>> {
+    const EXTENT: [u32; 2] = [160, 120];
+    let image = create_rt_image(ctx, EXTENT);
+    let params = RtParams {
+        frame_counter: 1,
+        // The CPU intersects the full meshes without clipping.
+        lod_distance: 0.0,
+        clip_plane_count: 0,
+        aov: Aov::Beauty as u32,
+        ..*params
+    };
+    let debug = RtDebugOptions {
+        object_ids: true,
+        ..Default::default()
+    };
+    raytrace_into(ctx, image.clone(), &params, None, debug, gpu_scene)?;
+    let (_, pixels) = read_rt_image(ctx, image)?;
+
+    let mut mismatches = 0;
+    for (i, pixel) in pixels.iter().enumerate() {
+        let coords = Vec2::new((i as u32 % EXTENT[0]) as f32, (i as u32 / EXTENT[0]) as f32);
+        let ray = params.primary_ray(EXTENT, coords);
+        let cpu_id = cpu_scene.ray_test(ray).map_or(0, |(index, _)| index + 1);
+        let gpu_id = pixel.x as usize;
+        if cpu_id != gpu_id {
+            debug!(
+                "Self-test mismatch at {}: CPU hit {}, GPU hit {}",
+                coords, cpu_id, gpu_id
+            );
+            mismatches += 1;
+        }
+    }
+
+    Ok(mismatches as f32 / pixels.len() as f32)
+}
+
+/// Whether the display shows the ray-tracing image upside down.
+/// The fragment shader reads the image at `gl_FragCoord`, whose origin is the top-left
+/// in Vulkan regardless of the viewport, so the first row is always shown at the top.
+const DISPLAY_FLIP_Y: bool = false;
+
+/// Name for a new exported image with extension `ext`, timestamped so that it doesn't replace
+/// earlier exports or the `render.png` checked into the repository.
+fn export_path(ext: &str) -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |f| f.as_secs());
+    format!("render-{}.{}", secs, ext)
+}
+
+/// Save the accumulated image as a PNG with `bit_depth` bits per channel, 8 or 16.
+/// If `flip_y` is set, the image is flipped vertically; use [`DISPLAY_FLIP_Y`] to match the display.
+fn export_png(
+    ctx: &Context,
+    path: &str,
+    alpha_mode: AlphaMode,
+    flip_y: bool,
+    exposure: f32,
+    bit_depth: u8,
+) -> Result<(), Box<dyn Error>> {
+    let (extent, pixels) = read_rt_samples(ctx)?;
+    export::write_png(
+        path, extent[0], extent[1], &pixels, alpha_mode, flip_y, exposure, bit_depth,
+    )?;
+    Ok(())
+}
+
+/// Number of workgroups of the luminance reduction for an image of size `extent`, one per 16x16 tile.
+fn luminance_groups(extent: [u32; 2]) -> [u32; 2] {
+    extent.map(|f| f.div_ceil(16))
+}
+
+/// Reduce the accumulated image to the sum of its log luminance, its pixel count,
+/// the sum of its luminance and its maximum luminance, counting only pixels with samples.
+fn reduce_luminance(ctx: &Context) -> Result<[f32; 4], Box<dyn Error>> {
+    let pipeline = ctx.luminance_pipeline.clone().unwrap();
+    let result = ctx.luminance.clone().unwrap();
+    let desc_set = DescriptorSet::new(
+        ctx.desc_alloc.clone().unwrap(),
+        pipeline.layout().set_layouts()[0].clone(),
+        [
+            WriteDescriptorSet::image_view(
+                0,
+                ImageView::new_default(ctx.rt_samples.clone().unwrap())?,
+            ),
+            WriteDescriptorSet::buffer(1, result.clone()),
+        ],
+        [],
+    )?;
+    let mut cmd_buf = AutoCommandBufferBuilder::primary(
+        ctx.cmd_alloc.clone().unwrap(),
+        ctx.queues[0].clone().queue_family_index(),
+        CommandBufferUsage::OneTimeSubmit,
+    )?;
+    cmd_buf
+        .bind_pipeline_compute(pipeline.clone())?
+        .bind_descriptor_sets(
+            PipelineBindPoint::Compute,
+            pipeline.layout().clone(),
+            0,
+            desc_set,
+        )?;
+    let image = ctx.rt_samples.as_ref().unwrap();
+    let [x, y] = luminance_groups([image.extent()[0], image.extent()[1]]);
+    unsafe { cmd_buf.dispatch([x, y, 1]) }?;
+    cmd_buf
+        .build()?
+        .execute(ctx.queues[0].clone())?
+        .then_signal_fence_and_flush()?
+        .wait(None)?;
+
+    let stats = result
+        .read()?
+        .iter()
+        .fold([0.0; 4], |[log_sum, count, sum, max], f| {
+            [log_sum + f[0], count + f[1], sum + f[2], max.max(f[3])]
+        });
+    Ok(stats)
+}
+
+/// Get the log-average luminance of the accumulated image, or `None` if it has no samples yet.
+fn average_luminance(ctx: &Context) -> Result<Option<f32>, Box<dyn Error>> {
+    let [log_sum, count, _, _] = reduce_luminance(ctx)?;
+    Ok((count > 0.0).then(|| (log_sum / count).exp()))
+}
+
+/// Get the maximum and mean luminance of the accumulated image, or `None` if it has no samples yet.
+/// A maximum far above the mean points at fireflies.
+fn image_luminance_stats(ctx: &Context) -> Result<Option<(f32, f32)>, Box<dyn Error>> {
+    let [_, count, sum, max] = reduce_luminance(ctx)?;
+    Ok((count > 0.0).then(|| (max, sum / count)))
+}
+
+/// Number of workgroups per row of a Morton-order dispatch, which keeps large images within
+/// the guaranteed workgroup count limit of 65535.
+const MORTON_ROW: u32 = 1024;
+
+/// Interleave the bits of `x` and `y`, giving the position of a tile along the Z-order curve.
+fn morton_index(x: u32, y: u32) -> u32 {
+    let spread = |f: u32| {
+        let f = (f | (f << 8)) & 0x00ff00ff;
+        let f = (f | (f << 4)) & 0x0f0f0f0f;
+        let f = (f | (f << 2)) & 0x33333333;
+        (f | (f << 1)) & 0x55555555
+    };
+    spread(x & 0xffff) | (spread(y & 0xffff) << 1)
+}
+
+/// Get the workgroup counts that cover `tiles` along the Z-order curve.
+/// The shader numbers the workgroups row by row and decodes each number to a tile; the curve is
+/// monotonic in both axes, so it ends at the last tile, and tiles it visits outside the region exit early.
+fn morton_dispatch(tiles: [u32; 2]) -> [u32; 3] {
+    let count = morton_index(tiles[0] - 1, tiles[1] - 1) + 1;
+    [count.min(MORTON_ROW), count.div_ceil(MORTON_ROW), 1]
 }
 
 /// Tell the GPU to collect a single ray-trace sample.
-fn raytrace(ctx: &mut Context, params: &RtParams, scene: &GpuScene) {
+/// If `region` is specified as `[x, y, w, h]`, only that part of the image is rendered.
+fn raytrace(
+    ctx: &mut Context,
+    params: &RtParams,
+    region: Option<[u32; 4]>,
+    debug: RtDebugOptions,
+    scene: &GpuScene,
+) {
+    let image = ctx.rt_samples.clone().unwrap();
+    raytrace_into(ctx, image, params, region, debug, scene).unwrap();
+}
+
+/// Collect a single ray-trace sample into a caller-provided image instead of `rt_samples`.
+/// The image must be a 2D image in [`Context::rt_format`] with storage usage.
+/// It gets its own variance, cost and caustics buffers, which are cleared whenever the image changes.
+fn raytrace_into(
+    ctx: &mut Context,
+    image: Arc<Image>,
+    params: &RtParams,
+    region: Option<[u32; 4]>,
+    debug: RtDebugOptions,
+    scene: &GpuScene,
+) -> Result<(), Box<dyn Error>> {
+    if image.format() != ctx.rt_format {
+        return Err(format!(
+            "Ray trace target has format {:?}, expected {:?}",
+            image.format(),
+            ctx.rt_format
+        )
+        .into());
+    }
+    if !image.usage().contains(ImageUsage::STORAGE) {
+        return Err("Ray trace target lacks storage usage".into());
+    }
+    if image.image_type() != ImageType::Dim2d {
+        return Err("Ray trace target is not a 2D image".into());
+    }
+    // Object IDs don't touch the variance, cost and caustics buffers, so the window's may be bound
+    // whatever their size.
+    let own_target = ctx
+        .rt_samples
+        .as_ref()
+        .is_some_and(|f| Arc::ptr_eq(f, &image));
+    let (variance, cost, caustics) = if own_target || debug.object_ids {
+        (
+            ctx.rt_variance.clone().unwrap(),
+            ctx.rt_cost.clone().unwrap(),
+            ctx.rt_caustics.clone().unwrap(),
+        )
+    } else {
+        if !ctx
+            .rt_scratch
+            .as_ref()
+            .is_some_and(|f| Arc::ptr_eq(&f.target, &image))
+        {
+            let extent = [image.extent()[0], image.extent()[1]];
+            let scratch = RtScratch {
+                target: image.clone(),
+                variance: create_rt_image(ctx, extent),
+                cost: create_rt_image(ctx, extent),
+                caustics: create_caustics_buffer(ctx, extent),
+            };
+            clear_rt_buffers(
+                ctx,
+                [scratch.variance.clone(), scratch.cost.clone()],
+                Some(scratch.caustics.clone()),
+            )?;
+            ctx.rt_scratch = Some(scratch);
+        }
+        let scratch = ctx.rt_scratch.as_ref().unwrap();
+        (
+            scratch.variance.clone(),
+            scratch.cost.clone(),
+            scratch.caustics.clone(),
+        )
+    };
+
+    // Clamp the render region to the image; pixels outside it keep their previous contents.
+    let extent = [image.extent()[0], image.extent()[1]];
+    let [x, y, w, h] = region.unwrap_or([0, 0, extent[0], extent[1]]);
+    let region_offset = [x.min(extent[0]), y.min(extent[1])];
+    let region_size = [
+        w.min(extent[0] - region_offset[0]),
+        h.min(extent[1] - region_offset[1]),
+    ];
+    if region_size[0] == 0 || region_size[1] == 0 {
+        return Ok(());
+    }
+
     let mut cmd_buf = AutoCommandBufferBuilder::primary(
         ctx.cmd_alloc.clone().unwrap(),
         ctx.queues[0].clone().queue_family_index(),
@@ -384,10 +1352,7 @@ fn raytrace(ctx: &mut Context, params: &RtParams, scene: &GpuScene) {
         ctx.desc_alloc.clone().unwrap(),
         ctx.rt_pipeline.as_ref().unwrap().layout().set_layouts()[0].clone(),
         [
-            WriteDescriptorSet::image_view(
-                0,
-                ImageView::new_default(ctx.rt_samples.clone().unwrap()).unwrap(),
-            ),
+            WriteDescriptorSet::image_view(0, ImageView::new_default(image.clone())?),
             WriteDescriptorSet::buffer(1, scene.skybox.clone()),
             WriteDescriptorSet::buffer(2, scene.objects.clone()),
             WriteDescriptorSet::buffer(3, scene.meshes.clone()),
@@ -395,8 +1360,26 @@ fn raytrace(ctx: &mut Context, params: &RtParams, scene: &GpuScene) {
             WriteDescriptorSet::buffer(5, scene.verts.clone()),
             WriteDescriptorSet::buffer(6, scene.norms.clone()),
             WriteDescriptorSet::buffer(7, scene.vcols.clone()),
-            // WriteDescriptorSet::buffer(8, scene.uvs.clone()),
+            WriteDescriptorSet::buffer(8, scene.uvs.clone()),
             WriteDescriptorSet::buffer(9, scene.bvh.clone()),
+            WriteDescriptorSet::image_view(10, ImageView::new_default(variance)?),
+            WriteDescriptorSet::buffer(11, scene.tri_materials.clone()),
+            WriteDescriptorSet::buffer(12, scene.materials.clone()),
+            WriteDescriptorSet::buffer(13, scene.tlas.clone()),
+            WriteDescriptorSet::buffer(14, scene.instances.clone()),
+            WriteDescriptorSet::image_view_sampler(
+                15,
+                ctx.skybox_cubemap.as_ref().unwrap().view.clone(),
+                ctx.skybox_cubemap.as_ref().unwrap().sampler.clone(),
+            ),
+            WriteDescriptorSet::image_view_sampler(
+                18,
+                ctx.skybox_irradiance.as_ref().unwrap().view.clone(),
+                ctx.skybox_irradiance.as_ref().unwrap().sampler.clone(),
+            ),
+            WriteDescriptorSet::buffer(16, scene.alpha_masks.clone()),
+            WriteDescriptorSet::image_view(17, ImageView::new_default(cost)?),
+            WriteDescriptorSet::buffer(19, caustics),
         ],
         [],
     )
@@ -409,8 +1392,17 @@ fn raytrace(ctx: &mut Context, params: &RtParams, scene: &GpuScene) {
             ctx.rt_pipeline.as_ref().unwrap().layout().clone(),
             0,
             RtPushConst {
+                region_offset,
+                region_size,
                 params: *params,
                 object_count: scene.object_count,
+                bounds_preview: debug.bounds_preview as u32,
+                object_ids: debug.object_ids as u32,
+                disabled_types: debug.disabled_types,
+                trace_cost: debug.trace_cost as u32,
+                negative_emission: debug.negative_emission as u32,
+                solo_object: debug.solo_object.map_or(u32::MAX, |f| f as u32),
+                morton_order: debug.morton_order as u32,
             },
         )
         .unwrap()
@@ -422,57 +1414,387 @@ fn raytrace(ctx: &mut Context, params: &RtParams, scene: &GpuScene) {
         )
         .unwrap();
 
-    // The shader must run once per pixel; it is grouped into 8x8 tiles.
-    let groups = [
-        (ctx.swapchain.clone().unwrap().image_extent()[0] + 7) / 8,
-        (ctx.swapchain.clone().unwrap().image_extent()[1] + 7) / 8,
-        1,
-    ];
+    // The shader must run once per pixel in the region; it is grouped into 8x8 tiles.
+    let tiles = [region_size[0].div_ceil(8), region_size[1].div_ceil(8)];
+    let groups = if debug.morton_order {
+        morton_dispatch(tiles)
+    } else {
+        [tiles[0], tiles[1], 1]
+    };
     unsafe { cmd_buf.dispatch(groups) }.unwrap();
     let cmd_buf = cmd_buf.build().unwrap();
 
     // Run the commands.
     cmd_buf
-        .execute(ctx.queues[0].clone())
-        .unwrap()
-        .then_signal_fence_and_flush()
-        .unwrap()
-        .wait(None)
-        .unwrap();
-}
+        .execute(ctx.queues[0].clone())?
+        .then_signal_fence_and_flush()?
+        .wait(None)?;
 
-struct App {
-    ctx: Option<Context>,
-    window: Option<Arc<Window>>,
-    cpu_scene: Scene,
-    gpu_scene: Option<GpuScene>,
-    rt_params: RtParams,
-    running: bool,
+    Ok(())
 }
 
-impl ApplicationHandler for App {
-    fn resumed(&mut self, event_loop: &ActiveEventLoop) {
-        if self.window.is_some() {
-            return;
+/// Load the skybox's cubemap faces, or create a black placeholder if it has none.
+fn create_skybox_cubemap(ctx: &mut Context, skybox: &Skybox) -> Result<(), Box<dyn Error>> {
+    let faces = match &skybox.cubemap {
+        Some(paths) => {
+            let mut faces = vec![];
+            for path in paths {
+                let mut face = Texture::load_ppm(path)?;
+                face.convert_color_space(export::color_space());
+                faces.push(face);
+            }
+            faces.try_into().unwrap()
         }
+        None => std::array::from_fn(|_| Texture::solid(1, 1, [0, 0, 0, 255])),
+    };
+    ctx.skybox_cubemap = Some(Texture::upload_cubemap(
+        &faces,
+        ctx.allocator.clone(),
+        ctx.cmd_alloc.clone().unwrap(),
+        ctx.queues[0].clone(),
+    )?);
+    create_skybox_irradiance(ctx)?;
+    Ok(())
+}
 
-        let window = Arc::new(
-            event_loop
-                .create_window(
-                    WindowAttributes::default()
-                        .with_title("GPU Ray Tracer")
-                        .with_inner_size(Size::Physical(PhysicalSize::new(800, 600))),
-                )
-                .unwrap(),
-        );
-        self.window = Some(window.clone());
+/// Convolve the skybox cubemap into a low-resolution diffuse irradiance cubemap on the GPU.
+fn create_skybox_irradiance(ctx: &mut Context) -> Result<(), Box<dyn Error>> {
+    const SIZE: u32 = 32;
+    let shader = load_shader(ctx.device.clone(), "./shader/irradiance.spv")?;
+    let stage = PipelineShaderStageCreateInfo::new(shader.entry_point("main").unwrap());
+    let layout = PipelineLayout::new(
+        ctx.device.clone(),
+        PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+            .into_pipeline_layout_create_info(ctx.device.clone())?,
+    )?;
+    let pipeline = ComputePipeline::new(
+        ctx.device.clone(),
+        None,
+        ComputePipelineCreateInfo::stage_layout(stage, layout),
+    )?;
+
+    let image = Image::new(
+        ctx.allocator.clone(),
+        ImageCreateInfo {
+            flags: ImageCreateFlags::CUBE_COMPATIBLE,
+            image_type: ImageType::Dim2d,
+            format: Format::R16G16B16A16_SFLOAT,
+            extent: [SIZE, SIZE, 1],
+            array_layers: 6,
+            usage: ImageUsage::STORAGE | ImageUsage::SAMPLED,
+            ..Default::default()
+        },
+        AllocationCreateInfo::default(),
+    )?;
+    let storage_view = ImageView::new(
+        image.clone(),
+        ImageViewCreateInfo {
+            view_type: ImageViewType::Dim2dArray,
+            ..ImageViewCreateInfo::from_image(&image)
+        },
+    )?;
+    let cube_view = ImageView::new(
+        image.clone(),
+        ImageViewCreateInfo {
+            view_type: ImageViewType::Cube,
+            ..ImageViewCreateInfo::from_image(&image)
+        },
+    )?;
+
+    let env = ctx.skybox_cubemap.as_ref().unwrap();
+    let desc_set = DescriptorSet::new(
+        ctx.desc_alloc.clone().unwrap(),
+        pipeline.layout().set_layouts()[0].clone(),
+        [
+            WriteDescriptorSet::image_view_sampler(0, env.view.clone(), env.sampler.clone()),
+            WriteDescriptorSet::image_view(1, storage_view),
+        ],
+        [],
+    )?;
+    let mut cmd_buf = AutoCommandBufferBuilder::primary(
+        ctx.cmd_alloc.clone().unwrap(),
+        ctx.queues[0].clone().queue_family_index(),
+        CommandBufferUsage::OneTimeSubmit,
+    )?;
+    cmd_buf
+        .bind_pipeline_compute(pipeline.clone())?
+        .bind_descriptor_sets(
+            PipelineBindPoint::Compute,
+            pipeline.layout().clone(),
+            0,
+            desc_set,
+        )?;
+    unsafe { cmd_buf.dispatch([SIZE.div_ceil(8), SIZE.div_ceil(8), 6]) }?;
+    cmd_buf
+        .build()?
+        .execute(ctx.queues[0].clone())?
+        .then_signal_fence_and_flush()?
+        .wait(None)?;
+
+    ctx.skybox_irradiance = Some(GpuTexture {
+        view: cube_view,
+        sampler: env.sampler.clone(),
+    });
+    Ok(())
+}
+
+struct App {
+    ctx: Option<Context>,
+    window: Option<Arc<Window>>,
+    cpu_scene: Scene,
+    gpu_scene: Option<GpuScene>,
+    rt_params: RtParams,
+    /// Region of the image to render as `[x, y, w, h]`, or `None` for the whole image.
+    rt_region: Option<[u32; 4]>,
+    /// Parameters of the left and right halves of split view, or `None` to render a single view.
+    /// While split, the right view follows `rt_params` and `rt_region` is ignored.
+    split_view: Option<(RtParams, RtParams)>,
+    running: bool,
+    /// Whether the sample count / FPS overlay is shown.
+    show_hud: bool,
+    /// Time at which the previous frame was drawn.
+    last_frame: Option<Instant>,
+    /// Smoothed frames per second.
+    fps: f32,
+    /// Vertical field of view in radians.
+    fov: f32,
+    /// What happens to accumulated samples when the scene changes.
+    accumulation: AccumulationPolicy,
+    /// Policy used instead of `accumulation` while moving-average accumulation is toggled on,
+    /// so the configured policy is restored when it is toggled off.
+    accumulation_override: Option<AccumulationPolicy>,
+    /// Number of samples after which rendering stops, or `None` to render indefinitely.
+    target_samples: Option<u32>,
+    /// Title currently shown in the title bar.
+    title: &'static str,
+    /// Per-frame timing log, if enabled with `--profile <csv>`.
+    profiler: Option<FrameProfiler>,
+    /// Debugging options for the ray tracer.
+    rt_debug: RtDebugOptions,
+    /// Whether the window has focus; rendering pauses while it doesn't.
+    focused: bool,
+    /// If set, run the GPU / CPU self-test and exit, failing if more than this fraction of pixels differ.
+    self_test: Option<f32>,
+    /// Process exit code once the event loop ends.
+    exit_code: i32,
+    /// Saved accumulation buffers to merge and resume from at startup.
+    resume: Vec<String>,
+    /// Seed mixed into the shader's random numbers, so renders with different seeds can be merged.
+    seed: u32,
+    /// Last cursor position in pixels.
+    cursor: Vec2,
+    /// Index of the object selected for material editing, as in [`Scene::objects`].
+    selected: Option<usize>,
+    /// Alpha mode for exported images.
+    alpha_mode: AlphaMode,
+    /// Whether to flip exported images vertically.
+    flip_export: bool,
+    /// Bits per channel of exported PNGs, 8 or 16.
+    png_bit_depth: u8,
+    /// Opacity of the traversal cost overlay; 0 hides it.
+    cost_blend: f32,
+    /// Fraction of the window resolution that is ray traced, see [`Context::render_scale`].
+    render_scale: f32,
+    /// Factor applied to the image before tone mapping.
+    exposure: f32,
+    /// Whether `exposure` follows the image's average luminance.
+    auto_exposure: bool,
+    /// Least severe debug messenger messages that are logged.
+    vk_min_severity: DebugUtilsMessageSeverity,
+}
+
+impl App {
+    /// Smallest allowed vertical field of view.
+    const MIN_FOV: f32 = PI / 180.0;
+    /// Largest allowed vertical field of view.
+    const MAX_FOV: f32 = PI * 17.0 / 18.0;
+    /// Weight of new samples in moving-average mode.
+    const EMA_ALPHA: f32 = 0.1;
+    /// Traversal cost per sample shown as the hottest color of the cost overlay.
+    const COST_SCALE: f32 = 256.0;
+    /// Average luminance auto exposure maps the image to.
+    const MIDDLE_GRAY: f32 = 0.18;
+    /// Fraction of the way auto exposure moves towards its target each frame, in stops.
+    const EXPOSURE_ADAPTATION: f32 = 0.1;
+    /// Range of exposures auto exposure may pick, so black or blown-out images stay sane.
+    const EXPOSURE_RANGE: (f32, f32) = (1.0 / 1024.0, 1024.0);
+
+    /// Whether rendering is paused, either explicitly or because the window lost focus.
+    fn is_paused(&self) -> bool {
+        !self.running || !self.focused
+    }
+
+    /// Set whether the window has focus, pausing rendering while it doesn't.
+    fn set_focused(&mut self, focused: bool) {
+        self.focused = focused;
+        self.resume_if_running();
+    }
+
+    /// Request the next frame if rendering was resumed, without counting the pause as animation time.
+    fn resume_if_running(&mut self) {
+        self.update_title();
+        if !self.is_paused() {
+            self.last_frame = None;
+            self.window.as_ref().unwrap().request_redraw();
+        }
+    }
+
+    /// Whether the target number of samples has been reached.
+    fn is_done(&self) -> bool {
+        self.target_samples
+            .is_some_and(|target| self.rt_params.frame_counter >= target)
+    }
+
+    /// Show whether rendering is running, paused or done in the title bar.
+    fn update_title(&mut self) {
+        let title = if self.is_done() {
+            "GPU Ray Tracer (done)"
+        } else if !self.is_paused() {
+            "GPU Ray Tracer"
+        } else {
+            "GPU Ray Tracer (paused)"
+        };
+        if title != self.title {
+            self.window.as_ref().unwrap().set_title(title);
+            self.title = title;
+        }
+    }
+
+    /// Apply the accumulation policy for a change to the scene.
+    fn scene_changed(&mut self, change: SceneChange) {
+        let policy = self.accumulation_override.unwrap_or(self.accumulation);
+        match policy.action(change) {
+            AccumulationAction::Reset => self.rt_params.frame_counter = 0,
+            AccumulationAction::Decay(keep) => self.rt_params.history_scale *= keep.clamp(0.0, 1.0),
+            AccumulationAction::Keep => (),
+        }
+    }
+
+    /// Recreate the render targets for a new window size and restart accumulation.
+    /// The vertical field of view stays fixed and the horizontal extent follows the new aspect ratio,
+    /// so reshaping the window reveals more or less of the scene's sides instead of zooming.
+    /// An aspect ratio set explicitly in [`RtParams::aspect`] is kept.
+    fn resize(&mut self, window_size: [u32; 2]) {
+        let ctx = self.ctx.as_mut().unwrap();
+        recreate_swapchain(ctx, window_size);
+        create_rt_samples(ctx, window_size);
+        self.rt_params.cam_v_fov = (self.fov * 0.5).tan();
+        // The old samples are gone with the old image, whatever the accumulation policy says.
+        self.rt_params.frame_counter = 0;
+    }
+
+    /// Move the exposure towards mapping the image's average luminance to middle gray.
+    fn update_exposure(&mut self) {
+        let average = match average_luminance(self.ctx.as_ref().unwrap()) {
+            Ok(Some(average)) => average,
+            Ok(None) => return,
+            Err(err) => {
+                error!("Failed to measure luminance: {}", err);
+                return;
+            }
+        };
+        let (min, max) = Self::EXPOSURE_RANGE;
+        let target = (Self::MIDDLE_GRAY / average).clamp(min, max);
+        // Adapt in stops so brightening and darkening feel equally fast.
+        self.exposure = (self.exposure.log2()
+            + (target.log2() - self.exposure.log2()) * Self::EXPOSURE_ADAPTATION)
+            .exp2();
+    }
+
+    /// Select the object under the cursor for material editing, or deselect if there is none.
+    fn pick(&mut self) {
+        let extent = Into::<[u32; 2]>::into(self.window.as_ref().unwrap().inner_size());
+        let ray = self.rt_params.primary_ray(extent, self.cursor);
+        self.selected = self.cpu_scene.ray_test(ray).map(|(index, _)| index);
+        match self.selected {
+            Some(index) => {
+                let prop = self.cpu_scene.object_mut(index).unwrap().prop;
+                info!("Selected object {}: {:?}", index, prop);
+            }
+            None => info!("Deselected object"),
+        }
+    }
+
+    /// Edit the material of the selected object, if any, and upload it.
+    fn edit_selected(&mut self, edit: impl FnOnce(&mut PhysProp)) {
+        let Some(index) = self.selected else {
+            return;
+        };
+        let node = self.cpu_scene.object_mut(index).unwrap();
+        edit(&mut node.prop);
+        node.prop = node.prop.sanitize();
+        node.prop_is_default = false;
+        let prop = node.prop;
+        info!(
+            "Object {}: roughness {:.2}, IOR {:.2}, emission {} x {:.2}",
+            index, prop.roughness, prop.ior, prop.emission_color, prop.emission_strength
+        );
+        self.gpu_scene
+            .as_ref()
+            .unwrap()
+            .update_object(index, prop)
+            .unwrap();
+        self.scene_changed(SceneChange::Material);
+        self.window.as_ref().unwrap().request_redraw();
+    }
+
+    /// Set the vertical field of view, clamped to a sane range.
+    fn set_fov(&mut self, fov: f32) {
+        self.fov = fov.clamp(Self::MIN_FOV, Self::MAX_FOV);
+        self.rt_params.cam_v_fov = (self.fov * 0.5).tan();
+        self.scene_changed(SceneChange::Fov);
+        self.window.as_ref().unwrap().request_redraw();
+    }
+
+    /// Advance all animations by `dt` seconds.
+    fn update(&mut self, dt: f32) {
+        if self.cpu_scene.update(dt) {
+            self.gpu_scene
+                .as_ref()
+                .unwrap()
+                .update_transforms(&self.cpu_scene)
+                .unwrap();
+            self.scene_changed(SceneChange::Transform);
+        }
+    }
+}
+
+impl ApplicationHandler for App {
+    fn resumed(&mut self, event_loop: &ActiveEventLoop) {
+        if self.window.is_some() {
+            return;
+        }
+
+        let window = Arc::new(
+            event_loop
+                .create_window(
+                    WindowAttributes::default()
+                        .with_title("GPU Ray Tracer")
+                        .with_inner_size(Size::Physical(PhysicalSize::new(
+                            // Stereo pairs are side by side, so give each eye the default size.
+                            if self.rt_params.stereo > 0.0 {
+                                1600
+                            } else {
+                                800
+                            },
+                            600,
+                        ))),
+                )
+                .unwrap(),
+        );
+        self.window = Some(window.clone());
 
         let vlk_lib = VulkanLibrary::new().unwrap();
+        // Needed to query `VK_EXT_memory_budget` on Vulkan 1.0 devices.
+        let khr_get_physical_device_properties2 = vlk_lib
+            .supported_extensions()
+            .khr_get_physical_device_properties2;
         let vlk_inst = Instance::new(
             vlk_lib,
             InstanceCreateInfo {
                 enabled_extensions: InstanceExtensions {
                     ext_debug_utils: true,
+                    khr_get_physical_device_properties2,
                     ..Surface::required_extensions(window.as_ref()).unwrap()
                 },
                 ..Default::default()
@@ -482,6 +1804,7 @@ impl ApplicationHandler for App {
         let vlk_surface = Surface::from_window(vlk_inst.clone(), window.clone()).unwrap();
         let (vlk_device, vlk_queues) = select_device(&vlk_inst, &vlk_surface);
 
+        let rt_format = select_rt_format(vlk_device.physical_device());
         let mut ctx = Context {
             instance: vlk_inst,
             device: vlk_device.clone(),
@@ -494,31 +1817,68 @@ impl ApplicationHandler for App {
             render_pass: None,
             gfx_pipeline: None,
             rt_pipeline: None,
+            rt_format,
             rt_samples: None,
+            rt_variance: None,
+            rt_caustics: None,
+            rt_cost: None,
+            rt_scratch: None,
+            skybox_cubemap: None,
+            skybox_irradiance: None,
+            render_scale: self.render_scale,
+            upscale_pipeline: None,
+            rt_upscaled: None,
+            rt_guides: None,
+            luminance_pipeline: None,
+            luminance: None,
             desc_alloc: None,
             cmd_alloc: None,
         };
 
+        info!("Running on {}", device_info(&ctx));
+
         let _callback = DebugUtilsMessenger::new(
             ctx.instance.clone(),
-            DebugUtilsMessengerCreateInfo::user_callback(unsafe {
-                DebugUtilsMessengerCallback::new(|severity, _, callback_data| {
-                    let severity = match severity {
-                        DebugUtilsMessageSeverity::ERROR => "error",
-                        DebugUtilsMessageSeverity::WARNING => "warning",
-                        DebugUtilsMessageSeverity::INFO => "info",
-                        DebugUtilsMessageSeverity::VERBOSE => "verbose",
-                        _ => panic!(),
-                    };
-                    println!("[{}] {:?}", severity, callback_data.message);
+            DebugUtilsMessengerCreateInfo {
+                message_severity: severities_from(self.vk_min_severity),
+                ..DebugUtilsMessengerCreateInfo::user_callback(unsafe {
+                    DebugUtilsMessengerCallback::new(|severity, _, callback_data| {
+                        let level = match severity {
+                            DebugUtilsMessageSeverity::ERROR => Level::Error,
+                            DebugUtilsMessageSeverity::WARNING => Level::Warn,
+                            DebugUtilsMessageSeverity::INFO => Level::Info,
+                            DebugUtilsMessageSeverity::VERBOSE => Level::Trace,
+                            _ => panic!(),
+                        };
+                        log::log!(target: "vulkan", level, "{}", callback_data.message);
+                    })
                 })
-            }),
+            },
         )
         .unwrap();
 
-        let frag_shader = load_shader(ctx.device.clone(), "./shader/frag.spv").unwrap();
+        // Shaders that access the ray-tracing images need a variant matching their format.
+        let image_shaders = if rt_format == Format::R32G32B32A32_SFLOAT {
+            [
+                "./shader/frag.spv",
+                "./shader/rt.spv",
+                "./shader/upscale.spv",
+                "./shader/luminance.spv",
+            ]
+        } else {
+            compile_half_shaders();
+            [
+                "./shader/frag_f16.spv",
+                "./shader/rt_f16.spv",
+                "./shader/upscale_f16.spv",
+                "./shader/luminance_f16.spv",
+            ]
+        };
+        let frag_shader = load_shader(ctx.device.clone(), image_shaders[0]).unwrap();
         let vert_shader = load_shader(ctx.device.clone(), "./shader/vert.spv").unwrap();
-        let rt_shader = load_shader(ctx.device.clone(), "./shader/rt.spv").unwrap();
+        let rt_shader = load_shader(ctx.device.clone(), image_shaders[1]).unwrap();
+        let upscale_shader = load_shader(ctx.device.clone(), image_shaders[2]).unwrap();
+        let luminance_shader = load_shader(ctx.device.clone(), image_shaders[3]).unwrap();
 
         let dynamic_state = [DynamicState::Viewport, DynamicState::Scissor];
         let frag_shader_stage =
@@ -604,8 +1964,17 @@ impl ApplicationHandler for App {
         // - The inverse camera matrix
         // - The camera matrix
         // - The camera resolution as two ints
-        let rt_shader_stage =
-            PipelineShaderStageCreateInfo::new(rt_shader.entry_point("main").unwrap());
+        let rt_shader_stage = PipelineShaderStageCreateInfo::new(
+            rt_shader
+                .specialize(
+                    [(0, SpecializationConstant::U32(self.seed))]
+                        .into_iter()
+                        .collect(),
+                )
+                .unwrap()
+                .entry_point("main")
+                .unwrap(),
+        );
         let rt_pipeline_layout = PipelineLayout::new(
             ctx.device.clone(),
             PipelineDescriptorSetLayoutCreateInfo::from_stages([&rt_shader_stage])
@@ -626,6 +1995,42 @@ impl ApplicationHandler for App {
             .unwrap(),
         );
 
+        let upscale_stage =
+            PipelineShaderStageCreateInfo::new(upscale_shader.entry_point("main").unwrap());
+        let upscale_layout = PipelineLayout::new(
+            ctx.device.clone(),
+            PipelineDescriptorSetLayoutCreateInfo::from_stages([&upscale_stage])
+                .into_pipeline_layout_create_info(ctx.device.clone())
+                .unwrap(),
+        )
+        .unwrap();
+        ctx.upscale_pipeline = Some(
+            ComputePipeline::new(
+                ctx.device.clone(),
+                None,
+                ComputePipelineCreateInfo::stage_layout(upscale_stage, upscale_layout),
+            )
+            .unwrap(),
+        );
+
+        let luminance_stage =
+            PipelineShaderStageCreateInfo::new(luminance_shader.entry_point("main").unwrap());
+        let luminance_layout = PipelineLayout::new(
+            ctx.device.clone(),
+            PipelineDescriptorSetLayoutCreateInfo::from_stages([&luminance_stage])
+                .into_pipeline_layout_create_info(ctx.device.clone())
+                .unwrap(),
+        )
+        .unwrap();
+        ctx.luminance_pipeline = Some(
+            ComputePipeline::new(
+                ctx.device.clone(),
+                None,
+                ComputePipelineCreateInfo::stage_layout(luminance_stage, luminance_layout),
+            )
+            .unwrap(),
+        );
+
         ctx.cmd_alloc = Some(Arc::new(StandardCommandBufferAllocator::new(
             ctx.device.clone(),
             StandardCommandBufferAllocatorCreateInfo::default(),
@@ -636,14 +2041,55 @@ impl ApplicationHandler for App {
         create_swapchain(&mut ctx, window_size);
         create_rt_samples(&mut ctx, window_size);
         self.gpu_scene = Some(GpuScene::build(ctx.allocator.clone(), &self.cpu_scene).unwrap());
-        println!("{:#?}", self.gpu_scene);
+        if let Err(err) = create_skybox_cubemap(&mut ctx, &self.cpu_scene.skybox) {
+            error!("Failed to load skybox cubemap: {}", err);
+            // Fall back to the procedural skybox.
+            self.cpu_scene.skybox.cubemap = None;
+            self.gpu_scene
+                .as_ref()
+                .unwrap()
+                .update_skybox(&self.cpu_scene.skybox)
+                .unwrap();
+            create_skybox_cubemap(&mut ctx, &self.cpu_scene.skybox).unwrap();
+        }
+        debug!("{:#?}", self.gpu_scene);
+
+        if !self.resume.is_empty() {
+            match resume_accumulation(&ctx, &self.resume) {
+                Ok(frame_counter) => {
+                    info!("Resumed accumulation at {} frames", frame_counter);
+                    self.rt_params.frame_counter = frame_counter;
+                }
+                Err(err) => error!("Failed to resume accumulation: {}", err),
+            }
+        }
+
+        if let Some(threshold) = self.self_test {
+            let mismatch = self_test(
+                &mut ctx,
+                &self.rt_params,
+                &self.cpu_scene,
+                self.gpu_scene.as_ref().unwrap(),
+            )
+            .unwrap();
+            if mismatch > threshold {
+                error!(
+                    "Self-test failed: {:.2}% of pixels differ between GPU and CPU",
+                    mismatch * 100.0
+                );
+                self.exit_code = 1;
+            } else {
+                info!(
+                    "Self-test passed: {:.2}% of pixels differ between GPU and CPU",
+                    mismatch * 100.0
+                );
+            }
+            event_loop.exit();
+            return;
+        }
 
         self.ctx = Some(ctx);
-        self.window.as_ref().unwrap().set_title(if self.running {
-            "GPU Ray Tracer"
-        } else {
-            "GPU Ray Tracer (paused)"
-        });
+        self.update_title();
     }
 
     fn window_event(
@@ -654,22 +2100,210 @@ impl ApplicationHandler for App {
     ) {
         match event {
             WindowEvent::CloseRequested => {
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.flush().unwrap();
+                }
                 event_loop.exit();
             }
             WindowEvent::KeyboardInput { event, .. } => {
-                if event.physical_key == KeyCode::Space && event.state.is_pressed() && !event.repeat
-                {
+                if !event.state.is_pressed() || event.repeat {
+                    return;
+                }
+                if event.physical_key == KeyCode::Space {
                     self.running = !self.running;
-                    self.window.as_ref().unwrap().set_title(if self.running {
-                        "GPU Ray Tracer"
+                    self.resume_if_running();
+                } else if event.physical_key == KeyCode::F1 {
+                    self.show_hud = !self.show_hud;
+                    self.window.as_ref().unwrap().request_redraw();
+                } else if event.physical_key == KeyCode::F2 {
+                    // Toggle split view, freezing the current settings in the left half for comparison.
+                    self.split_view = match self.split_view {
+                        Some(_) => None,
+                        None => Some((self.rt_params, self.rt_params)),
+                    };
+                    self.rt_params.frame_counter = 0;
+                    self.window.as_ref().unwrap().request_redraw();
+                } else if event.physical_key == KeyCode::Equal
+                    || event.physical_key == KeyCode::NumpadAdd
+                {
+                    self.set_fov(self.fov * 0.9);
+                } else if event.physical_key == KeyCode::Minus
+                    || event.physical_key == KeyCode::NumpadSubtract
+                {
+                    self.set_fov(self.fov / 0.9);
+                } else if event.physical_key == KeyCode::KeyP {
+                    // Toggle between accumulating and previewing single samples.
+                    self.rt_params.accumulate ^= 1;
+                    self.rt_params.frame_counter = 0;
+                    self.window.as_ref().unwrap().request_redraw();
+                } else if event.physical_key == KeyCode::KeyE {
+                    // Toggle moving-average accumulation, which keeps accumulating through animation.
+                    if self.accumulation_override.take().is_some() {
+                        self.rt_params.history_alpha = 0.0;
                     } else {
-                        "GPU Ray Tracer (paused)"
+                        self.rt_params.history_alpha = Self::EMA_ALPHA;
+                        self.accumulation_override = Some(AccumulationPolicy {
+                            transform: AccumulationAction::Keep,
+                            ..self.accumulation
+                        });
+                    }
+                    self.window.as_ref().unwrap().request_redraw();
+                } else if event.physical_key == KeyCode::KeyR {
+                    // Restart rendering, e.g. after reaching the target sample count.
+                    self.rt_params.frame_counter = 0;
+                    self.window.as_ref().unwrap().request_redraw();
+                } else if event.physical_key == KeyCode::KeyB {
+                    // Toggle the bounding-box preview.
+                    self.rt_debug.bounds_preview = !self.rt_debug.bounds_preview;
+                    self.rt_params.frame_counter = 0;
+                    self.window.as_ref().unwrap().request_redraw();
+                } else if let Some(ty) = match event.physical_key {
+                    PhysicalKey::Code(KeyCode::Digit1) => Some(GpuObjectType::Sphere),
+                    PhysicalKey::Code(KeyCode::Digit2) => Some(GpuObjectType::Plane),
+                    PhysicalKey::Code(KeyCode::Digit3) => Some(GpuObjectType::Mesh),
+                    _ => None,
+                } {
+                    // Toggle intersection of an object type, to measure how much time it takes.
+                    self.rt_debug.disabled_types ^= 1 << ty as u32;
+                    info!(
+                        "{:?} objects {}",
+                        ty,
+                        if self.rt_debug.disabled_types & (1 << ty as u32) != 0 {
+                            "disabled"
+                        } else {
+                            "enabled"
+                        }
+                    );
+                    self.rt_params.frame_counter = 0;
+                    self.window.as_ref().unwrap().request_redraw();
+                } else if event.physical_key == KeyCode::KeyI {
+                    // Toggle rendering only the selected object.
+                    self.rt_debug.solo_object = match self.rt_debug.solo_object {
+                        Some(_) => None,
+                        None => self.selected,
+                    };
+                    match self.rt_debug.solo_object {
+                        Some(index) => info!("Rendering only object {}", index),
+                        None => info!("Rendering all objects"),
+                    }
+                    self.rt_params.frame_counter = 0;
+                    self.window.as_ref().unwrap().request_redraw();
+                } else if event.physical_key == KeyCode::BracketLeft {
+                    self.edit_selected(|f| f.roughness -= 0.05);
+                } else if event.physical_key == KeyCode::BracketRight {
+                    self.edit_selected(|f| f.roughness += 0.05);
+                } else if event.physical_key == KeyCode::Semicolon {
+                    self.edit_selected(|f| f.ior -= 0.05);
+                } else if event.physical_key == KeyCode::Quote {
+                    self.edit_selected(|f| f.ior += 0.05);
+                } else if event.physical_key == KeyCode::Comma {
+                    // Emission only goes negative while debugging negative emission.
+                    let min = if self.rt_debug.negative_emission {
+                        f32::NEG_INFINITY
+                    } else {
+                        0.0
+                    };
+                    self.edit_selected(|f| {
+                        f.emission_strength = (f.emission_strength - 0.25).max(min)
                     });
-                    if self.running {
-                        self.window.as_ref().unwrap().request_redraw();
+                } else if event.physical_key == KeyCode::Period {
+                    self.edit_selected(|f| {
+                        // Objects that don't emit yet start glowing in their own color.
+                        if f.emission_color == Vec3::ZERO {
+                            f.emission_color = f.color;
+                        }
+                        f.emission_strength += 0.25;
+                    });
+                } else if event.physical_key == KeyCode::KeyH {
+                    // Toggle the traversal cost overlay, restarting so the costs cover every sample.
+                    self.rt_debug.trace_cost = !self.rt_debug.trace_cost;
+                    self.cost_blend = if self.rt_debug.trace_cost { 0.5 } else { 0.0 };
+                    self.rt_params.frame_counter = 0;
+                    self.window.as_ref().unwrap().request_redraw();
+                } else if self.rt_debug.trace_cost
+                    && (event.physical_key == KeyCode::PageUp
+                        || event.physical_key == KeyCode::PageDown)
+                {
+                    let step = if event.physical_key == KeyCode::PageUp {
+                        0.1
+                    } else {
+                        -0.1
+                    };
+                    self.cost_blend = (self.cost_blend + step).clamp(0.0, 1.0);
+                    self.window.as_ref().unwrap().request_redraw();
+                } else if event.physical_key == KeyCode::KeyX {
+                    // Toggle auto exposure; turning it off keeps the current exposure.
+                    self.auto_exposure = !self.auto_exposure;
+                    self.window.as_ref().unwrap().request_redraw();
+                } else if event.physical_key == KeyCode::KeyW {
+                    // Toggle the wireframe overlay.
+                    self.rt_params.wireframe ^= 1;
+                    self.rt_params.frame_counter = 0;
+                    self.window.as_ref().unwrap().request_redraw();
+                } else if event.physical_key == KeyCode::KeyC {
+                    // Toggle photon-traced caustics.
+                    self.rt_params.caustics ^= 1;
+                    self.rt_params.frame_counter = 0;
+                    self.window.as_ref().unwrap().request_redraw();
+                } else if event.physical_key == KeyCode::KeyV {
+                    // Cycle through the output variables.
+                    let aov = Aov::ALL[self.rt_params.aov as usize].next();
+                    self.rt_params.aov = aov as u32;
+                    info!("Showing {:?}", aov);
+                    self.rt_params.frame_counter = 0;
+                    self.window.as_ref().unwrap().request_redraw();
+                } else if event.physical_key == KeyCode::KeyN {
+                    // Toggle negative emission along with a signed display to see where it subtracts light.
+                    self.rt_debug.negative_emission = !self.rt_debug.negative_emission;
+                    self.rt_params.frame_counter = 0;
+                    self.window.as_ref().unwrap().request_redraw();
+                } else if event.physical_key == KeyCode::F11 {
+                    match export_accumulation(
+                        self.ctx.as_ref().unwrap(),
+                        self.rt_params.frame_counter,
+                        self.seed,
+                    )
+                    .and_then(|f| Ok(f.save("render.accum")?))
+                    {
+                        Ok(()) => info!("Saved render.accum"),
+                        Err(err) => error!("Failed to save render.accum: {}", err),
+                    }
+                } else if event.physical_key == KeyCode::F12 {
+                    let path = export_path("png");
+                    match export_png(
+                        self.ctx.as_ref().unwrap(),
+                        &path,
+                        self.alpha_mode,
+                        self.flip_export,
+                        self.exposure,
+                        self.png_bit_depth,
+                    ) {
+                        Ok(()) => info!("Saved {}", path),
+                        Err(err) => error!("Failed to save {}: {}", path, err),
+                    }
+                    // A maximum far above the mean means the render has fireflies.
+                    if let Ok(Some((max, mean))) = image_luminance_stats(self.ctx.as_ref().unwrap())
+                    {
+                        info!("Luminance: max {:.3}, mean {:.3}", max, mean);
                     }
                 }
             }
+            WindowEvent::Focused(focused) => self.set_focused(focused),
+            WindowEvent::CursorMoved { position, .. } => {
+                self.cursor = Vec2::new(position.x as f32, position.y as f32);
+            }
+            WindowEvent::MouseInput {
+                state: ElementState::Pressed,
+                button: MouseButton::Left,
+                ..
+            } => self.pick(),
+            WindowEvent::MouseWheel { delta, .. } => {
+                let steps = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => y,
+                    MouseScrollDelta::PixelDelta(pos) => pos.y as f32 / 40.0,
+                };
+                self.set_fov(self.fov * 0.9f32.powf(steps));
+            }
             WindowEvent::RedrawRequested => {
                 if self
                     .ctx
@@ -683,18 +2317,102 @@ impl ApplicationHandler for App {
                 {
                     let window_size =
                         Into::<[u32; 2]>::into(self.window.as_ref().unwrap().inner_size());
-                    recreate_swapchain(self.ctx.as_mut().unwrap(), window_size);
-                    create_rt_samples(self.ctx.as_mut().unwrap(), window_size);
-                    self.rt_params.frame_counter = 0;
+                    // A minimized window has no area to render to.
+                    if window_size.contains(&0) {
+                        return;
+                    }
+                    self.resize(window_size);
+                }
+                let now = Instant::now();
+                if let Some(last_frame) = self.last_frame {
+                    let dt = (now - last_frame).as_secs_f32();
+                    self.fps += (1.0 / dt - self.fps) * 0.1;
+                    if !self.is_paused() {
+                        self.update(dt);
+                    }
                 }
-                self.rt_params.frame_counter += 1;
-                raytrace(
+                self.last_frame = Some(now);
+                let compute_start = Instant::now();
+                // While paused, only trace when the accumulation was reset and there is nothing to show.
+                if !self.is_done() && (!self.is_paused() || self.rt_params.frame_counter == 0) {
+                    if self.rt_params.frame_counter == 0 {
+                        // Accumulation was reset; start again from zeroed buffers.
+                        clear_rt_samples(self.ctx.as_ref().unwrap()).unwrap();
+                    }
+                    self.rt_params.frame_counter += 1;
+                    if let Some(split_view) = &mut self.split_view {
+                        // The right view follows the live settings.
+                        split_view.1 = self.rt_params;
+                        let extent = self
+                            .ctx
+                            .as_ref()
+                            .unwrap()
+                            .rt_samples
+                            .as_ref()
+                            .unwrap()
+                            .extent();
+                        for (params, region) in
+                            split_views(split_view, &self.rt_params, [extent[0], extent[1]])
+                        {
+                            raytrace(
+                                self.ctx.as_mut().unwrap(),
+                                &params,
+                                Some(region),
+                                self.rt_debug,
+                                self.gpu_scene.as_ref().unwrap(),
+                            );
+                        }
+                    } else {
+                        raytrace(
+                            self.ctx.as_mut().unwrap(),
+                            &self.rt_params,
+                            self.rt_region,
+                            self.rt_debug,
+                            &self.gpu_scene.as_ref().unwrap(),
+                        );
+                    }
+                    // Primary rays are cheap next to full samples, so the guides follow every
+                    // camera move and animation without tracking what changed.
+                    render_upscale_guides(
+                        self.ctx.as_mut().unwrap(),
+                        &self.rt_params,
+                        self.split_view.as_ref(),
+                        self.rt_debug,
+                        self.gpu_scene.as_ref().unwrap(),
+                    )
+                    .unwrap();
+                    upscale(self.ctx.as_ref().unwrap()).unwrap();
+                }
+                if self.auto_exposure {
+                    self.update_exposure();
+                }
+                let compute_time = compute_start.elapsed();
+                self.rt_params.history_scale = 1.0;
+                draw(
                     self.ctx.as_mut().unwrap(),
-                    &self.rt_params,
-                    &self.gpu_scene.as_ref().unwrap(),
+                    FragParams {
+                        frame_counter: self.rt_params.frame_counter,
+                        show_hud: self.show_hud as u32,
+                        fps: self.fps,
+                        cost_blend: self.cost_blend,
+                        cost_scale: Self::COST_SCALE,
+                        signed_display: self.rt_debug.negative_emission as u32,
+                        color_space: export::color_space() as u32,
+                        exposure: self.exposure,
+                        aov: self.rt_params.aov,
+                    },
                 );
-                draw(self.ctx.as_mut().unwrap(), self.rt_params.frame_counter);
-                if self.running {
+                if let Some(profiler) = &mut self.profiler {
+                    profiler
+                        .record(
+                            compute_time,
+                            compute_start.elapsed() - compute_time,
+                            self.rt_params.frame_counter,
+                        )
+                        .unwrap();
+                }
+                self.update_title();
+                if !self.is_paused() && !self.is_done() {
                     self.window.as_ref().unwrap().request_redraw();
                 }
             }
@@ -703,40 +2421,134 @@ impl ApplicationHandler for App {
     }
 }
 
-pub fn main() {
-    if !Command::new("glslc")
-        .args(&["shader/vert.vert", "-o", "shader/vert.spv"])
+/// Compile a shader with glslc, falling back to a prebuilt SPIR-V file if glslc is not installed.
+fn compile_shader(name: &str, args: &[&str], spv_path: &str) {
+    match Command::new("glslc")
+        .args(args)
+        .arg("-o")
+        .arg(spv_path)
         .status()
-        .expect("Can't run glslc")
-        .success()
     {
-        panic!("Failed to compile vertex shader");
-    }
-    if !Command::new("glslc")
-        .args(&["shader/frag.frag", "-o", "shader/frag.spv"])
-        .status()
-        .expect("Can't run glslc")
-        .success()
-    {
-        panic!("Failed to compile fragment shader");
+        Ok(status) if status.success() => (),
+        Ok(_) => panic!("Failed to compile {} shader", name),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            warn!("glslc was not found in PATH; install the Vulkan SDK or shaderc to compile shaders.");
+            warn!(
+                "See https://vulkan.lunarg.com/sdk/home or your distribution's `shaderc` package."
+            );
+            if !Path::new(spv_path).exists() {
+                panic!("No prebuilt {} shader at {}", name, spv_path);
+            }
+            warn!("Using prebuilt {} shader at {}", name, spv_path);
+        }
+        Err(err) => panic!("Can't run glslc: {}", err),
     }
-    if !Command::new("glslc")
-        .args(&[
+}
+
+/// Compile the variants of the shaders that access the ray-tracing images as `R16G16B16A16_SFLOAT`.
+fn compile_half_shaders() {
+    let define = "-DRT_FORMAT=rgba16f";
+    compile_shader(
+        "fragment (16-bit)",
+        &[define, "shader/frag.frag"],
+        "shader/frag_f16.spv",
+    );
+    compile_shader(
+        "upscale (16-bit)",
+        &[
+            define,
+            "-fshader-stage=comp",
+            "-std=450core",
+            "shader/upscale.glsl",
+        ],
+        "shader/upscale_f16.spv",
+    );
+    compile_shader(
+        "luminance (16-bit)",
+        &[
+            define,
+            "-fshader-stage=comp",
+            "-std=450core",
+            "shader/luminance.glsl",
+        ],
+        "shader/luminance_f16.spv",
+    );
+    compile_shader(
+        "ray tracing (16-bit)",
+        &[
+            define,
             "-fshader-stage=comp",
             "-std=450core",
             "shader/rt.glsl",
-            "-o",
-            "shader/rt.spv",
-        ])
-        .status()
-        .expect("Can't run glslc")
-        .success()
-    {
-        panic!("Failed to compile ray tracing shader");
+        ],
+        "shader/rt_f16.spv",
+    );
+}
+
+/// Debug messenger severities, from least to most severe.
+const SEVERITIES: [DebugUtilsMessageSeverity; 4] = [
+    DebugUtilsMessageSeverity::VERBOSE,
+    DebugUtilsMessageSeverity::INFO,
+    DebugUtilsMessageSeverity::WARNING,
+    DebugUtilsMessageSeverity::ERROR,
+];
+
+/// Get the debug messenger severities at least as severe as `min`.
+fn severities_from(min: DebugUtilsMessageSeverity) -> DebugUtilsMessageSeverity {
+    SEVERITIES
+        .iter()
+        .skip_while(|f| **f != min)
+        .fold(DebugUtilsMessageSeverity::empty(), |a, b| a | *b)
+}
+
+/// Minimal logger that prints to stderr, filtered by the `RUST_LOG` level (default `info`).
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
     }
-    println!("Shaders compiled successfully");
 
-    let scene = Scene {
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "[{}] {}: {}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn main() {
+    log::set_logger(&StderrLogger).unwrap();
+    log::set_max_level(
+        std::env::var("RUST_LOG")
+            .ok()
+            .and_then(|f| f.parse().ok())
+            .unwrap_or(LevelFilter::Info),
+    );
+
+    let args: Vec<String> = std::env::args().collect();
+
+    // `--compare <image> <reference> [diff]` prints difference metrics and writes a false-color
+    // difference image (`diff.ppm` by default) instead of rendering.
+    if let Some(i) = args.iter().position(|f| f == "--compare") {
+        let (Some(path), Some(reference)) = (args.get(i + 1), args.get(i + 2)) else {
+            error!("--compare needs an image and a reference");
+            return;
+        };
+        let diff = args.get(i + 3).map_or("diff.ppm", |f| f.as_str());
+        if let Err(err) = compare::compare_images(path, reference, diff) {
+            error!("Failed to compare images: {}", err);
+        }
+        return;
+    }
+
+    let mut scene = Scene {
         nodes: vec![
             Node {
                 model: Model::Sphere,
@@ -760,7 +2572,15 @@ pub fn main() {
                     opacity: 1.0,
                     ior: 1.0,
                     roughness: 0.0,
-                    emission: Vec3::ZERO,
+                    emission_color: Vec3::ZERO,
+                    emission_strength: 0.0,
+                    checker: None,
+                    scatter_color: Vec3::ONE,
+                    scatter_radius: 0.0,
+                    alpha_cutoff: 0.0,
+                    alpha_texture: None,
+                    light_only: false,
+                    emit_both_sides: true,
                 },
                 ..Default::default()
             },
@@ -770,7 +2590,11 @@ pub fn main() {
                     Quat::from_rotation_x(PI * 0.5),
                     Vec3::new(0.0, 0.5, 2.0),
                 )),
-                prop: PhysProp::from_color(Vec3::new(0.5, 0.5, 0.5)),
+                prop: PhysProp::from_checker(
+                    Vec3::new(0.5, 0.5, 0.5),
+                    Vec3::new(0.25, 0.25, 0.25),
+                    0.25,
+                ),
                 ..Default::default()
             },
             Node {
@@ -780,7 +2604,11 @@ pub fn main() {
                     Quat::IDENTITY,
                     Vec3::new(-0.5, 0.3, 1.5),
                 )),
-                prop: PhysProp::from_emission(Vec3::new(1.0, 1.0, 0.0), Vec3::new(1.0, 1.0, 0.0)),
+                prop: PhysProp::from_emission(
+                    Vec3::new(1.0, 1.0, 0.0),
+                    Vec3::new(1.0, 1.0, 0.0),
+                    1.0,
+                ),
                 ..Default::default()
             },
             Node {
@@ -795,7 +2623,15 @@ pub fn main() {
                     opacity: 0.0,
                     roughness: 0.0,
                     color: Vec3::new(1.0, 1.0, 1.0),
-                    emission: Vec3::ZERO,
+                    emission_color: Vec3::ZERO,
+                    emission_strength: 0.0,
+                    checker: None,
+                    scatter_color: Vec3::ONE,
+                    scatter_radius: 0.0,
+                    alpha_cutoff: 0.0,
+                    alpha_texture: None,
+                    light_only: false,
+                    emit_both_sides: true,
                 },
                 ..Default::default()
             },
@@ -811,32 +2647,363 @@ pub fn main() {
                     opacity: 1.0,
                     roughness: 0.5,
                     color: Vec3::new(0.8, 0.8, 0.8),
-                    emission: Vec3::ZERO,
+                    emission_color: Vec3::ZERO,
+                    emission_strength: 0.0,
+                    checker: None,
+                    scatter_color: Vec3::ONE,
+                    scatter_radius: 0.0,
+                    alpha_cutoff: 0.0,
+                    alpha_texture: None,
+                    light_only: false,
+                    emit_both_sides: true,
                 },
-                ..(&Obj::load("suzanne2.obj").unwrap()).into()
+                prop_is_default: false,
+                ..Node::load_obj("suzanne2.obj").unwrap()
             },
         ],
         skybox: Default::default(),
+        camera: None,
+        textures: vec![],
+        preprocess_threads: None,
+    };
+
+    // `--acescg` renders in the ACEScg color space instead of linear sRGB.
+    if args.iter().any(|f| f == "--acescg") {
+        export::set_color_space(export::ColorSpace::AcesCg);
+    }
+
+    // `--flatten` merges all static meshes into one.
+    if args.iter().any(|f| f == "--flatten") {
+        scene.flatten_static();
+    }
+
+    // `--bvh-f64` rebuilds the BVHs in double precision, for meshes with large or precise coordinates.
+    if args.iter().any(|f| f == "--bvh-f64") {
+        scene.build_all_bvhs(&BvhConfig {
+            double_precision: true,
+            ..Default::default()
+        });
+    }
+
+    // `--cull-backfaces` treats all meshes as closed, skipping triangles facing away from rays.
+    if args.iter().any(|f| f == "--cull-backfaces") {
+        scene.set_cull_backfaces(true);
+    }
+
+    // `--lod <distance>` gives meshes decimated LODs for instances further than `distance` away.
+    let lod_distance = args
+        .iter()
+        .position(|f| f == "--lod")
+        .and_then(|i| args.get(i + 1)?.parse().ok())
+        .unwrap_or(0.0);
+    if lod_distance > 0.0 {
+        scene.build_lods(4);
+    }
+
+    info!("Scene built");
+    if let Err(warnings) = scene.validate() {
+        for warning in warnings {
+            warn!("{:?}", warning);
+        }
+    }
+
+    // `--samples <n>` stops rendering after `n` samples.
+    let target_samples = args
+        .iter()
+        .position(|f| f == "--samples")
+        .and_then(|i| args.get(i + 1)?.parse().ok());
+
+    // `--profile <csv>` logs frame timings.
+    let profiler = args
+        .iter()
+        .position(|f| f == "--profile")
+        .and_then(|i| args.get(i + 1))
+        .map(|path| FrameProfiler::create(path).unwrap());
+
+    // `--self-test [threshold]` compares GPU and CPU intersections and exits,
+    // failing if more than `threshold` of the pixels (default 1%) differ.
+    let self_test = args
+        .iter()
+        .position(|f| f == "--self-test")
+        .map(|i| args.get(i + 1).and_then(|f| f.parse().ok()).unwrap_or(0.01));
+
+    // `--resume <file>` continues accumulating from a buffer saved with F11;
+    // repeat it to merge buffers rendered on different machines.
+    let resume = args
+        .iter()
+        .zip(args.iter().skip(1))
+        .filter(|(flag, _)| *flag == "--resume")
+        .map(|(_, path)| path.clone())
+        .collect();
+
+    // `--seed <n>` changes the random numbers the shader uses, so that accumulation buffers rendered
+    // on several machines with different seeds can be merged with `--resume`.
+    let seed = args
+        .iter()
+        .position(|f| f == "--seed")
+        .and_then(|i| args.get(i + 1)?.parse().ok())
+        .unwrap_or(0);
+
+    // `--premultiplied-alpha` exports premultiplied instead of straight alpha.
+    let alpha_mode = if args.iter().any(|f| f == "--premultiplied-alpha") {
+        AlphaMode::Premultiplied
+    } else {
+        AlphaMode::Straight
+    };
+
+    // Exports match the display unless `--flip-y` is given.
+    let flip_export = DISPLAY_FLIP_Y != args.iter().any(|f| f == "--flip-y");
+
+    // `--png-bit-depth <8|16>` sets the bits per channel of exported PNGs; 16 avoids banding in gradients.
+    let png_bit_depth = args
+        .iter()
+        .position(|f| f == "--png-bit-depth")
+        .and_then(|i| args.get(i + 1))
+        .map_or(8, |f| match f.parse() {
+            Ok(depth @ (8 | 16)) => depth,
+            _ => {
+                warn!("Ignoring PNG bit depth {}, expected 8 or 16", f);
+                8
+            }
+        });
+
+    // `--vk-log <error|warning|info|verbose>` sets the least severe Vulkan debug messages to log;
+    // verbose messages are logged at trace level, so they also need `RUST_LOG=trace`.
+    let vk_min_severity = args
+        .iter()
+        .position(|f| f == "--vk-log")
+        .and_then(|i| args.get(i + 1))
+        .map_or(DebugUtilsMessageSeverity::WARNING, |f| match f.as_str() {
+            "error" => DebugUtilsMessageSeverity::ERROR,
+            "warning" => DebugUtilsMessageSeverity::WARNING,
+            "info" => DebugUtilsMessageSeverity::INFO,
+            "verbose" => DebugUtilsMessageSeverity::VERBOSE,
+            _ => {
+                warn!(
+                    "Ignoring Vulkan log level {}, expected error, warning, info or verbose",
+                    f
+                );
+                DebugUtilsMessageSeverity::WARNING
+            }
+        });
+
+    // `--auto-exposure` starts with auto exposure enabled; X toggles it.
+    let auto_exposure = args.iter().any(|f| f == "--auto-exposure");
+
+    // `--render-scale <fraction>` traces fewer pixels and upscales them to the window.
+    let render_scale = args
+        .iter()
+        .position(|f| f == "--render-scale")
+        .and_then(|i| args.get(i + 1)?.parse::<f32>().ok())
+        .map_or(1.0, |f| f.clamp(0.05, 1.0));
+
+    // `--clip <nx,ny,nz,d>` cuts away geometry behind a plane for camera rays; repeat it for more planes.
+    let clip_planes: Vec<Vec4> = args
+        .iter()
+        .zip(args.iter().skip(1))
+        .filter(|(flag, _)| *flag == "--clip")
+        .filter_map(|(_, plane)| {
+            let values: Vec<f32> = plane.split(',').filter_map(|f| f.parse().ok()).collect();
+            let plane = <[f32; 4]>::try_from(values).ok().map(Vec4::from_array);
+            if plane.is_none() {
+                warn!("Ignoring malformed clip plane, expected `nx,ny,nz,d`");
+            }
+            plane
+        })
+        .collect();
+    if clip_planes.len() > MAX_CLIP_PLANES {
+        warn!("Only the first {} clip planes are used", MAX_CLIP_PLANES);
+    }
+
+    // `--stereo <eye separation>` renders side-by-side stereo pairs.
+    let stereo = args.iter().position(|f| f == "--stereo").map_or(0.0, |i| {
+        args.get(i + 1)
+            .and_then(|f| f.parse().ok())
+            .unwrap_or(0.064)
+    });
+
+    // `--caustics` traces photons from the suns for sharper caustics behind glass and mirrors; C toggles it.
+    let caustics = args.iter().any(|f| f == "--caustics");
+
+    // `--morton-order` dispatches the ray tracer's tiles along a Z-order curve instead of row by row;
+    // compare the two with `--profile`.
+    let morton_order = args.iter().any(|f| f == "--morton-order");
+
+    // `--god-rays <density> [steps]` scatters sunlight in a uniform fog, 16 steps per ray by default.
+    let (god_ray_density, god_ray_steps) =
+        args.iter()
+            .position(|f| f == "--god-rays")
+            .map_or((0.0, 16), |i| {
+                let density = args.get(i + 1).and_then(|f| f.parse().ok());
+                let steps = args.get(i + 2).and_then(|f| f.parse().ok());
+                (density.unwrap_or(0.05), steps.unwrap_or(16u32).max(1))
+            });
+
+    // `--bounce-clamp <max> [falloff]` clamps the luminance of light picked up after the first bounce to `max`,
+    // multiplied by `falloff` (0.5 by default) for each further bounce.
+    let (bounce_clamp, bounce_clamp_falloff) = args
+        .iter()
+        .position(|f| f == "--bounce-clamp")
+        .map_or((0.0, 0.5), |i| {
+            let max = args.get(i + 1).and_then(|f| f.parse().ok());
+            let falloff = args.get(i + 2).and_then(|f| f.parse().ok());
+            (
+                max.unwrap_or(10.0f32).max(0.0),
+                falloff.unwrap_or(0.5f32).max(0.0),
+            )
+        });
+
+    let camera = scene.camera.unwrap_or(Camera {
+        transform: Transform::default(),
+        fov: PI * 0.5,
+    });
+
+    let mut rt_params = RtParams {
+        cam_matrix: Mat4::IDENTITY.to_cols_array(),
+        cam_v_fov: (camera.fov * 0.5).tan(),
+        frame_counter: 0,
+        ray_count: 4,
+        max_bounce: 16,
+        variance_threshold: 0.0,
+        accumulate: 1,
+        history_scale: 1.0,
+        history_alpha: 0.0,
+        aspect: 0.0,
+        stereo,
+        lod_distance,
+        clip_plane_count: 0,
+        clip_planes: [Vec4::ZERO.into(); MAX_CLIP_PLANES],
+        aov: Aov::Beauty as u32,
+        wireframe: 0,
+        region_is_view: 0,
+        caustics: caustics as u32,
+        wireframe_color: Vec4::ONE.into(),
+        god_ray_density,
+        god_ray_steps,
+        bounce_clamp,
+        bounce_clamp_falloff,
     };
+    rt_params.set_camera(&camera.transform);
+    rt_params.set_clip_planes(&clip_planes);
 
-    println!("Scene built");
+    // `--cpu [png]` path traces `--samples` samples (16 by default) on the CPU and saves them
+    // (to `render-<timestamp>.png` by default) instead of opening a window; machines without Vulkan always do this.
+    let cpu = args.iter().position(|f| f == "--cpu");
+    if cpu.is_some() || !vulkan_available() {
+        if cpu.is_none() {
+            warn!("No Vulkan device available; rendering on the CPU");
+        }
+        let path = cpu
+            .and_then(|i| args.get(i + 1))
+            .filter(|f| !f.starts_with("--"))
+            .map_or_else(|| export_path("png"), |f| f.clone());
+        let extent = [if stereo > 0.0 { 1600 } else { 800 }, 600];
+        let samples = target_samples.unwrap_or(16);
+        let start = Instant::now();
+        let pixels = cpu::render(&scene, &rt_params, extent, samples);
+        info!(
+            "Rendered {} samples on the CPU in {:.1?}",
+            samples,
+            start.elapsed()
+        );
+        match export::write_png(
+            &path,
+            extent[0],
+            extent[1],
+            &pixels,
+            alpha_mode,
+            flip_export,
+            1.0,
+            png_bit_depth,
+        ) {
+            Ok(()) => info!("Saved {}", path),
+            Err(err) => {
+                error!("Failed to save {}: {}", path, err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    compile_shader("vertex", &["shader/vert.vert"], "shader/vert.spv");
+    compile_shader("fragment", &["shader/frag.frag"], "shader/frag.spv");
+    compile_shader(
+        "irradiance",
+        &[
+            "-fshader-stage=comp",
+            "-std=450core",
+            "shader/irradiance.glsl",
+        ],
+        "shader/irradiance.spv",
+    );
+    compile_shader(
+        "upscale",
+        &["-fshader-stage=comp", "-std=450core", "shader/upscale.glsl"],
+        "shader/upscale.spv",
+    );
+    compile_shader(
+        "luminance",
+        &[
+            "-fshader-stage=comp",
+            "-std=450core",
+            "shader/luminance.glsl",
+        ],
+        "shader/luminance.spv",
+    );
+    compile_shader(
+        "ray tracing",
+        &["-fshader-stage=comp", "-std=450core", "shader/rt.glsl"],
+        "shader/rt.spv",
+    );
+    info!("Shaders ready");
 
     let mut app = App {
         ctx: None,
         window: None,
-        rt_params: RtParams {
-            cam_matrix: Mat4::IDENTITY.to_cols_array(),
-            cam_v_fov: (PI * 0.25).tan(),
-            frame_counter: 0,
-            ray_count: 4,
-            max_bounce: 16,
-        },
+        rt_params,
+        rt_region: None,
+        split_view: None,
         cpu_scene: scene,
         gpu_scene: None,
         running: false,
+        show_hud: false,
+        last_frame: None,
+        fps: 0.0,
+        fov: camera.fov,
+        accumulation: AccumulationPolicy::default(),
+        accumulation_override: None,
+        target_samples,
+        title: "",
+        profiler,
+        rt_debug: RtDebugOptions {
+            morton_order,
+            ..Default::default()
+        },
+        self_test,
+        exit_code: 0,
+        resume,
+        seed,
+        cursor: Vec2::ZERO,
+        selected: None,
+        alpha_mode,
+        flip_export,
+        png_bit_depth,
+        cost_blend: 0.0,
+        focused: true,
+        render_scale,
+        exposure: 1.0,
+        auto_exposure,
+        vk_min_severity,
     };
+    // `--wait` sleeps until the next event whenever no frame was requested, e.g. once the target
+    // sample count is reached or while paused, instead of polling for events continuously.
     let event_loop = EventLoop::new().unwrap();
-    event_loop.set_control_flow(ControlFlow::Poll);
+    if args.iter().any(|f| f == "--wait") {
+        event_loop.set_control_flow(ControlFlow::Wait);
+    } else {
+        event_loop.set_control_flow(ControlFlow::Poll);
+    }
 
     event_loop.run_app(&mut app).unwrap();
+    std::process::exit(app.exit_code);
 }
@@ -1,8 +1,10 @@
 use core::f32;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead};
+use std::sync::Arc;
 
-use glam::{Vec2, Vec3};
+use glam::{DVec3, IVec3, Mat4, Vec2, Vec3};
 use obj::{Group, IndexTuple, Obj};
 
 /// Extra info to use while building the BVH.
@@ -10,8 +12,77 @@ use obj::{Group, IndexTuple, Obj};
 struct BvhTriAux {
     pub min: Vec3,
     pub max: Vec3,
-    pub center: Vec3,
-    pub area: f32,
+    /// Centroid, computed in double precision if [`BvhConfig::double_precision`] is set.
+    pub center: DVec3,
+}
+
+/// Strategy used to split BVH nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BvhBuildMode {
+    /// Surface area heuristic; slower to build but faster to traverse.
+    #[default]
+    Sah,
+    /// Split at the centroid median along the longest axis; fast to build.
+    Median,
+}
+
+/// Tuning parameters for building a BVH.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BvhConfig {
+    /// How nodes are split.
+    pub mode: BvhBuildMode,
+    /// Nodes with at most twice this many triangles are not split.
+    pub min_tri: usize,
+    /// Maximum depth of the tree; limited to [`Bvh::MAX_DEPTH`], the depth the shader can traverse.
+    pub max_depth: usize,
+    /// Number of candidate split positions per axis for the surface area heuristic.
+    pub max_slices: usize,
+    /// Relative cost of visiting a node for the surface area heuristic.
+    /// Nodes are only split if that is cheaper than testing all of their triangles.
+    pub traversal_cost: f32,
+    /// Relative cost of intersecting a triangle for the surface area heuristic.
+    pub intersection_cost: f32,
+    /// Meshes with at most this many triangles get no BVH, since testing them all is faster.
+    pub min_mesh_tris: usize,
+    /// Compute centroids, split positions and surface areas in `f64`, for better splits of meshes
+    /// with large or precise coordinates. The bounds are still stored as `f32`. Slower to build.
+    pub double_precision: bool,
+}
+
+impl Default for BvhConfig {
+    fn default() -> Self {
+        Self {
+            mode: BvhBuildMode::default(),
+            min_tri: Bvh::MIN_TRI,
+            max_depth: Bvh::MAX_DEPTH,
+            max_slices: Bvh::MAX_SLICES,
+            traversal_cost: 1.0,
+            intersection_cost: 1.0,
+            min_mesh_tris: Bvh::MIN_MESH_TRIS,
+            double_precision: false,
+        }
+    }
+}
+
+impl BvhConfig {
+    /// Get the centroid of a triangle at the configured precision.
+    fn centroid(&self, a: Vec3, b: Vec3, c: Vec3) -> DVec3 {
+        if self.double_precision {
+            (a.as_dvec3() + b.as_dvec3() + c.as_dvec3()) / 3.0
+        } else {
+            ((a + b + c) * 0.33333332).as_dvec3()
+        }
+    }
+
+    /// Get the surface area of a box at the configured precision.
+    fn surface_area(&self, min: Vec3, max: Vec3) -> f64 {
+        if self.double_precision {
+            let size = max.as_dvec3() - min.as_dvec3();
+            2.0 * (size.x * (size.y + size.z) + size.y * size.z)
+        } else {
+            Bvh::surface_area(min, max) as f64
+        }
+    }
 }
 
 /// Bounding-volume hierarchy.
@@ -26,6 +97,7 @@ impl Bvh {
     pub const MAX_DEPTH: usize = 31;
     pub const MIN_TRI: usize = 2;
     pub const MAX_SLICES: usize = 5;
+    pub const MIN_MESH_TRIS: usize = 16;
 
     /// Calculate [min, max] bounds for a range of triangles.
     fn calc_bounds(aux: &[BvhTriAux], begin: usize, end: usize) -> (Vec3, Vec3) {
@@ -37,48 +109,62 @@ impl Bvh {
             })
     }
 
-    /// Evaluate the surface area heuristic at a single point.
-    fn eval_sah(aux: &[BvhTriAux], axis: usize, pos: f32, before: bool) -> f32 {
+    /// Get the surface area of a box.
+    fn surface_area(min: Vec3, max: Vec3) -> f32 {
+        let size = max - min;
+        2.0 * (size.x * (size.y + size.z) + size.y * size.z)
+    }
+
+    /// Evaluate the surface area heuristic at a single point:
+    /// the cost of intersecting every triangle on one side, weighted by the area of their bounds.
+    /// Returns `f64::MAX` if there are no triangles on that side.
+    fn eval_sah(aux: &[BvhTriAux], axis: usize, pos: f64, before: bool, config: &BvhConfig) -> f64 {
         let mut min = Vec3::MAX;
         let mut max = Vec3::MIN;
-        let mut tri_area = 0.0;
+        let mut count = 0;
 
         for tri in aux {
             if (tri.center[axis] < pos) == before {
                 min = min.min(tri.min);
                 max = max.max(tri.max);
-                tri_area += tri.area;
+                count += 1;
             }
         }
 
-        let box_size = max - min;
-        let box_area = 2.0 * (box_size.x * (box_size.y + box_size.z) + box_size.y * box_size.z);
-
-        box_area / tri_area
+        // Splits leaving a side empty are never useful, and its bounds would be inverted.
+        if count == 0 {
+            return f64::MAX;
+        }
+        config.intersection_cost as f64 * count as f64 * config.surface_area(min, max)
     }
 
     /// Get heuristic cost for splitting along an axis.
-    fn eval_axis(&self, aux: &[BvhTriAux], axis: usize) -> (f32, f32, f32) {
+    fn eval_axis(&self, aux: &[BvhTriAux], axis: usize, config: &BvhConfig) -> (f64, f64, f64) {
+        let max_slices = config.max_slices;
         let range = self.content.as_leaf().unwrap();
         let slice_points: Vec<_> = {
-            if range.end - range.begin <= Self::MAX_SLICES {
+            if range.end - range.begin <= max_slices {
                 aux[range.begin..range.end]
                     .iter()
                     .map(|f| f.center[axis])
                     .collect()
+            } else if config.double_precision {
+                let scale = (self.max.as_dvec3() - self.min.as_dvec3())[axis] / max_slices as f64;
+                (0..max_slices).map(|f| (f as f64 + 0.5) * scale).collect()
             } else {
-                let scale = (self.max - self.min)[axis] / Self::MAX_SLICES as f32;
-                (0..Self::MAX_SLICES)
-                    .map(|f| (f as f32 + 0.5) * scale)
+                let scale = (self.max - self.min)[axis] / max_slices as f32;
+                (0..max_slices)
+                    .map(|f| ((f as f32 + 0.5) * scale) as f64)
                     .collect()
             }
         };
 
-        let mut best = (0f32, f32::MAX, f32::MAX);
+        let mut best = (0f64, f64::MAX, f64::MAX);
 
         for point in slice_points {
-            let cost0 = Self::eval_sah(&aux[range.begin..range.end], axis, point, true);
-            let cost1 = Self::eval_sah(&aux[range.begin..range.end], axis, point, false);
+            let tris = &aux[range.begin..range.end];
+            let cost0 = Self::eval_sah(tris, axis, point, true, config);
+            let cost1 = Self::eval_sah(tris, axis, point, false, config);
             if cost0 + cost1 < best.1 + best.2 {
                 best = (point, cost0, cost1);
             }
@@ -93,8 +179,8 @@ impl Bvh {
         mesh: &mut Mesh,
         aux: &mut [BvhTriAux],
         axis: usize,
-        pos: f32,
-        cost: (f32, f32),
+        pos: f64,
+        cost: (f64, f64),
     ) -> bool {
         if let BvhContent::Leaf(data) = &self.content {
             // Index of first triangle greater than the threshold.
@@ -108,7 +194,7 @@ impl Bvh {
                     }
                 } else if let Some(point) = midpoint {
                     // Triangle must be swapped to before the midpoint.
-                    (mesh.tris[point], mesh.tris[i]) = (mesh.tris[i], mesh.tris[point]);
+                    mesh.swap_tris(point, i);
                     (aux[point], aux[i]) = (aux[i], aux[point]);
                     midpoint = Some(point + 1);
                 }
@@ -152,25 +238,97 @@ impl Bvh {
         false
     }
 
+    /// Split along the longest axis of the triangle centers at the median triangle.
+    fn split_median(&mut self, mesh: &mut Mesh, aux: &mut [BvhTriAux], config: &BvhConfig) {
+        let range = self.content.as_leaf().unwrap();
+        let (begin, end) = (range.begin, range.end);
+
+        // Find the axis along which the triangle centers are spread out the most.
+        let (min, max) = aux[begin..end]
+            .iter()
+            .fold((DVec3::MAX, DVec3::MIN), |(min, max), f| {
+                (min.min(f.center), max.max(f.center))
+            });
+        let size = max - min;
+        let axis = if size.x >= size.y && size.x >= size.z {
+            0
+        } else if size.y >= size.z {
+            1
+        } else {
+            2
+        };
+
+        // Sort the triangles along that axis so the median splits them in half.
+        let mut order: Vec<usize> = (begin..end).collect();
+        order.sort_by(|a, b| aux[*a].center[axis].total_cmp(&aux[*b].center[axis]));
+        let sorted_tris: Vec<_> = order.iter().map(|f| mesh.tris[*f]).collect();
+        let sorted_aux: Vec<_> = order.iter().map(|f| aux[*f]).collect();
+        mesh.tris[begin..end].copy_from_slice(&sorted_tris);
+        aux[begin..end].copy_from_slice(&sorted_aux);
+        if let Some(tri_materials) = mesh.tri_materials.as_mut() {
+            let sorted_materials: Vec<_> = order.iter().map(|f| tri_materials[*f]).collect();
+            tri_materials[begin..end].copy_from_slice(&sorted_materials);
+        }
+
+        let midpoint = (begin + end) / 2;
+        let make_leaf = |begin: usize, end: usize| {
+            let (min, max) = Bvh::calc_bounds(aux, begin, end);
+            Box::new(Bvh {
+                min,
+                max,
+                content: BvhContent::Leaf(BvhLeaf {
+                    begin,
+                    end,
+                    cost: Bvh::eval_sah(&aux[begin..end], 0, f64::MAX, true, config),
+                }),
+            })
+        };
+        self.content = BvhContent::Node((make_leaf(begin, midpoint), make_leaf(midpoint, end)));
+    }
+
     /// Split a BVH node if possible.
-    fn build_impl(&mut self, mesh: &mut Mesh, aux: &mut [BvhTriAux], depth: usize) {
+    fn build_impl(
+        &mut self,
+        mesh: &mut Mesh,
+        aux: &mut [BvhTriAux],
+        depth: usize,
+        config: &BvhConfig,
+    ) {
         if let BvhContent::Leaf(data) = &self.content {
             // Limit condition.
-            if data.end - data.begin <= 2 * Bvh::MIN_TRI || depth >= Bvh::MAX_DEPTH {
+            if data.end - data.begin <= 2 * config.min_tri
+                || depth >= config.max_depth.min(Bvh::MAX_DEPTH)
+            {
                 return;
             }
         } else {
             return;
         }
 
+        if config.mode == BvhBuildMode::Median {
+            self.split_median(mesh, aux, config);
+            if let BvhContent::Node(node) = &mut self.content {
+                node.0.build_impl(mesh, aux, depth + 1, config);
+                node.1.build_impl(mesh, aux, depth + 1, config);
+            }
+            return;
+        }
+
         // Evaluate how good it would be to split along each axis.
-        let (x_pos, x_cost0, x_cost1) = self.eval_axis(aux, 0);
-        let (y_pos, y_cost0, y_cost1) = self.eval_axis(aux, 1);
-        let (z_pos, z_cost0, z_cost1) = self.eval_axis(aux, 2);
+        let (x_pos, x_cost0, x_cost1) = self.eval_axis(aux, 0, config);
+        let (y_pos, y_cost0, y_cost1) = self.eval_axis(aux, 1, config);
+        let (z_pos, z_cost0, z_cost1) = self.eval_axis(aux, 2, config);
         let x = x_cost0 + x_cost1;
         let y = y_cost0 + y_cost1;
         let z = z_cost0 + z_cost1;
 
+        // Keep the node as a leaf if visiting two children costs more than testing every triangle.
+        let leaf_cost = self.content.as_leaf().unwrap().cost;
+        let traversal = config.traversal_cost as f64 * config.surface_area(self.min, self.max);
+        if traversal + x.min(y).min(z) >= leaf_cost {
+            return;
+        }
+
         // Split along the axis with least cost.
         if x.is_finite() && x < y && x < z {
             self.try_split(mesh, aux, 0, x_pos, (x_cost0, x_cost1));
@@ -182,13 +340,13 @@ impl Bvh {
 
         // Recursively split child nodes.
         if let BvhContent::Node(node) = &mut self.content {
-            node.0.build_impl(mesh, aux, depth + 1);
-            node.1.build_impl(mesh, aux, depth + 1);
+            node.0.build_impl(mesh, aux, depth + 1, config);
+            node.1.build_impl(mesh, aux, depth + 1, config);
         }
     }
 
     /// Build a BVH for the mesh, potentially changing the order of the triangles.
-    fn build(mesh: &mut Mesh) -> Bvh {
+    fn build(mesh: &mut Mesh, config: &BvhConfig) -> Bvh {
         // Create auxiliary data.
         let mut aux: Box<[BvhTriAux]> = mesh
             .tris
@@ -200,8 +358,7 @@ impl Bvh {
                 BvhTriAux {
                     min: a.min(b.min(c)),
                     max: a.max(b.max(c)),
-                    center: (a + b + c) * 0.33333332,
-                    area: (b - a).cross(c - a).length() * 0.5,
+                    center: config.centroid(a, b, c),
                 }
             })
             .collect();
@@ -209,21 +366,52 @@ impl Bvh {
         // Create BVH root node.
         let mut tmp = Bvh {
             min: aux.iter().map(|f| f.min).fold(Vec3::MAX, |a, b| a.min(b)),
-            max: aux.iter().map(|f| f.min).fold(Vec3::MIN, |a, b| a.max(b)),
+            max: aux.iter().map(|f| f.max).fold(Vec3::MIN, |a, b| a.max(b)),
             content: BvhContent::Leaf(BvhLeaf {
                 begin: 0,
                 end: mesh.tris.len(),
-                cost: Bvh::eval_sah(&aux, 0, f32::MAX, true),
+                cost: Bvh::eval_sah(&aux, 0, f64::MAX, true, config),
             }),
         };
 
         // Recursively try to split the BVH.
-        tmp.build_impl(mesh, &mut aux, 0);
+        tmp.build_impl(mesh, &mut aux, 0, config);
 
         tmp
     }
 }
 
+/// Distance along a ray to an axis-aligned box, or `None` if it misses.
+/// `inv_dir` is the component-wise reciprocal of the ray direction.
+fn ray_test_box(pos: Vec3, inv_dir: Vec3, min: Vec3, max: Vec3) -> Option<f32> {
+    let t0 = (min - pos) * inv_dir;
+    let t1 = (max - pos) * inv_dir;
+    let near = t0.min(t1).max_element().max(0.0);
+    let far = t0.max(t1).min_element();
+    (near <= far).then_some(near)
+}
+
+/// Distance along a ray to a triangle using the Möller-Trumbore algorithm.
+fn ray_test_tri(pos: Vec3, dir: Vec3, [a, b, c]: [Vec3; 3]) -> Option<f32> {
+    let edge0 = b - a;
+    let edge1 = c - a;
+    let p = dir.cross(edge1);
+    let det = edge0.dot(p);
+    if det.abs() < 1e-8 {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+    let t = pos - a;
+    let u = t.dot(p) * inv_det;
+    let q = t.cross(edge0);
+    let v = dir.dot(q) * inv_det;
+    if u < 0.0 || v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let dist = edge1.dot(q) * inv_det;
+    (dist > 0.00001).then_some(dist)
+}
+
 /// Inner node in a bounding-volume hierarchy.
 #[derive(Debug, Clone)]
 pub enum BvhContent {
@@ -252,7 +440,7 @@ impl BvhContent {
 pub struct BvhLeaf {
     pub begin: usize,
     pub end: usize,
-    pub cost: f32,
+    pub cost: f64,
 }
 
 /// 3D mesh for use by the ray tracer..
@@ -270,12 +458,331 @@ pub struct Mesh {
     pub vert_cols: Option<Vec<Vec3>>,
     /// Vertex UV coordinates.
     pub vert_uv: Option<Vec<Vec2>>,
+    /// Per-triangle material indices.
+    pub tri_materials: Option<Vec<u32>>,
+    /// Progressively coarser versions of this mesh, used for distant instances; see [`Mesh::build_lods`].
+    pub lods: Vec<Arc<Mesh>>,
+    /// Whether rays may skip triangles facing away from them; only valid for closed meshes.
+    /// Ignored for objects using materials that rays can pass through.
+    pub cull_backfaces: bool,
+}
+
+/// Problem with a mesh's data found by [`Mesh::validate`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum MeshIssue {
+    /// Triangle corner referencing a vertex that doesn't exist.
+    IndexOutOfRange { tri: usize, index: usize },
+    /// Vertex position containing NaN or infinite values.
+    NonFiniteVertex { vert: usize },
+    /// Per-vertex or per-triangle attribute array with the wrong number of entries.
+    AttributeLengthMismatch {
+        attribute: &'static str,
+        len: usize,
+        expected: usize,
+    },
+    /// Edge shared by more than two triangles.
+    NonManifoldEdge { verts: [usize; 2], tris: usize },
 }
 
 impl Mesh {
+    /// Check the mesh for data that would render wrong or can't be uploaded to the GPU.
+    pub fn validate(&self) -> Vec<MeshIssue> {
+        let mut issues = vec![];
+        for (tri, corners) in self.tris.iter().enumerate() {
+            for index in corners {
+                if *index >= self.verts.len() {
+                    issues.push(MeshIssue::IndexOutOfRange { tri, index: *index });
+                }
+            }
+        }
+        for (vert, pos) in self.verts.iter().enumerate() {
+            if !pos.is_finite() {
+                issues.push(MeshIssue::NonFiniteVertex { vert });
+            }
+        }
+
+        let lengths = [
+            (
+                "normals",
+                self.normals.as_ref().map(|f| f.len()),
+                self.verts.len(),
+            ),
+            (
+                "vert_cols",
+                self.vert_cols.as_ref().map(|f| f.len()),
+                self.verts.len(),
+            ),
+            (
+                "vert_uv",
+                self.vert_uv.as_ref().map(|f| f.len()),
+                self.verts.len(),
+            ),
+            (
+                "tri_materials",
+                self.tri_materials.as_ref().map(|f| f.len()),
+                self.tris.len(),
+            ),
+        ];
+        for (attribute, len, expected) in lengths {
+            if let Some(len) = len.filter(|f| *f != expected) {
+                issues.push(MeshIssue::AttributeLengthMismatch {
+                    attribute,
+                    len,
+                    expected,
+                });
+            }
+        }
+
+        let mut edges = HashMap::<[usize; 2], usize>::new();
+        for tri in &self.tris {
+            for i in 0..3 {
+                let (a, b) = (tri[i], tri[(i + 1) % 3]);
+                *edges.entry([a.min(b), a.max(b)]).or_default() += 1;
+            }
+        }
+        let mut non_manifold: Vec<_> = edges.into_iter().filter(|f| f.1 > 2).collect();
+        non_manifold.sort();
+        issues.extend(
+            non_manifold
+                .into_iter()
+                .map(|(verts, tris)| MeshIssue::NonManifoldEdge { verts, tris }),
+        );
+        issues
+    }
+
+    /// Simplify the mesh by merging all vertices within each cell of a grid of size `cell_size`.
+    /// Triangles that collapse are removed; the LODs of this mesh are not kept.
+    pub fn decimate(&self, cell_size: f32) -> Mesh {
+        let mut cells = HashMap::<IVec3, usize>::new();
+        let mut remap = Vec::with_capacity(self.verts.len());
+        let mut counts = vec![];
+        for vert in &self.verts {
+            let cell = (*vert / cell_size).floor().as_ivec3();
+            let index = *cells.entry(cell).or_insert_with(|| {
+                counts.push(0);
+                counts.len() - 1
+            });
+            counts[index] += 1;
+            remap.push(index);
+        }
+
+        // Merged vertices are the average of the vertices in their cell; UVs can't be averaged
+        // across seams, so they keep the first one.
+        let average = |values: &[Vec3]| {
+            let mut out = vec![Vec3::ZERO; counts.len()];
+            for (value, index) in values.iter().zip(&remap) {
+                out[*index] += *value / counts[*index] as f32;
+            }
+            out
+        };
+        let mut vert_uv = self
+            .vert_uv
+            .as_ref()
+            .map(|_| vec![Vec2::ZERO; counts.len()]);
+        if let (Some(out), Some(uvs)) = (&mut vert_uv, &self.vert_uv) {
+            for (uv, index) in uvs.iter().zip(&remap).rev() {
+                out[*index] = *uv;
+            }
+        }
+
+        let mut tris = vec![];
+        let mut tri_materials = self.tri_materials.as_ref().map(|_| vec![]);
+        for (i, tri) in self.tris.iter().enumerate() {
+            let [a, b, c] = tri.map(|f| remap[f]);
+            if a == b || b == c || a == c {
+                continue;
+            }
+            tris.push([a, b, c]);
+            if let (Some(out), Some(materials)) = (&mut tri_materials, &self.tri_materials) {
+                out.push(materials[i]);
+            }
+        }
+
+        let mut mesh = Mesh {
+            bvh: None,
+            tris,
+            verts: average(&self.verts),
+            normals: self
+                .normals
+                .as_ref()
+                .map(|f| average(f).iter().map(|f| f.normalize_or_zero()).collect()),
+            vert_cols: self.vert_cols.as_ref().map(|f| average(f)),
+            vert_uv,
+            tri_materials,
+            lods: vec![],
+            cull_backfaces: self.cull_backfaces,
+        };
+        mesh.create_bvh(&BvhConfig::default());
+        mesh
+    }
+
+    /// Replace the LODs with up to `levels` decimated versions of this mesh,
+    /// each using a grid twice as coarse as the previous one.
+    /// Stops early once decimating no longer removes triangles.
+    pub fn build_lods(&mut self, levels: usize) {
+        let (min, max) = self
+            .verts
+            .iter()
+            .fold((Vec3::MAX, Vec3::MIN), |(min, max), f| {
+                (min.min(*f), max.max(*f))
+            });
+        let mut cell_size = (max - min).max_element() / 64.0;
+        self.lods.clear();
+        if cell_size <= 0.0 {
+            return;
+        }
+        let mut tri_count = self.tris.len();
+        for _ in 0..levels {
+            let lod = self.decimate(cell_size);
+            if lod.tris.is_empty() || lod.tris.len() >= tri_count {
+                break;
+            }
+            tri_count = lod.tris.len();
+            self.lods.push(Arc::new(lod));
+            cell_size *= 2.0;
+        }
+    }
+
+    /// Area-weighted vertex normals computed from the triangles.
+    pub fn smooth_normals(&self) -> Vec<Vec3> {
+        let mut normals = vec![Vec3::ZERO; self.verts.len()];
+        for tri in &self.tris {
+            let [a, b, c] = tri.map(|f| self.verts[f]);
+            let normal = (b - a).cross(c - a);
+            for index in tri {
+                normals[*index] += normal;
+            }
+        }
+        normals.iter().map(|f| f.normalize_or_zero()).collect()
+    }
+
+    /// Append another mesh transformed by `matrix`.
+    /// `material` maps each appended triangle's material index, if any, to its index in this mesh;
+    /// triangles already in this mesh without one get `u32::MAX`, which falls back to the node's material.
+    /// Attributes only one of the meshes has are filled in with smooth normals, white or zero UVs.
+    /// The BVH is not updated.
+    pub fn append(&mut self, other: &Mesh, matrix: Mat4, material: impl Fn(Option<u32>) -> u32) {
+        let offset = self.verts.len();
+        // Mirroring transforms flip the winding, which determines the face normals.
+        let flip = matrix.determinant() < 0.0;
+        let normal_matrix = matrix.inverse().transpose();
+
+        if other.normals.is_some() && self.normals.is_none() {
+            self.normals = Some(self.smooth_normals());
+        }
+        if let Some(normals) = &mut self.normals {
+            let other_normals = other
+                .normals
+                .clone()
+                .unwrap_or_else(|| other.smooth_normals());
+            normals.extend(
+                other_normals
+                    .iter()
+                    .map(|f| normal_matrix.transform_vector3(*f).normalize_or_zero()),
+            );
+        }
+        if other.vert_cols.is_some() && self.vert_cols.is_none() {
+            self.vert_cols = Some(vec![Vec3::ONE; offset]);
+        }
+        if let Some(vert_cols) = &mut self.vert_cols {
+            match &other.vert_cols {
+                Some(other_cols) => vert_cols.extend(other_cols),
+                None => vert_cols.resize(offset + other.verts.len(), Vec3::ONE),
+            }
+        }
+        if other.vert_uv.is_some() && self.vert_uv.is_none() {
+            self.vert_uv = Some(vec![Vec2::ZERO; offset]);
+        }
+        if let Some(vert_uv) = &mut self.vert_uv {
+            match &other.vert_uv {
+                Some(other_uv) => vert_uv.extend(other_uv),
+                None => vert_uv.resize(offset + other.verts.len(), Vec2::ZERO),
+            }
+        }
+
+        let tri_materials = self
+            .tri_materials
+            .get_or_insert_with(|| vec![u32::MAX; self.tris.len()]);
+        tri_materials.extend(
+            (0..other.tris.len()).map(|i| material(other.tri_materials.as_ref().map(|f| f[i]))),
+        );
+        self.tris.extend(other.tris.iter().map(|f| {
+            let [a, b, c] = f.map(|f| f + offset);
+            if flip {
+                [a, c, b]
+            } else {
+                [a, b, c]
+            }
+        }));
+        self.verts
+            .extend(other.verts.iter().map(|f| matrix.transform_point3(*f)));
+    }
+
     /// Create / update the BVH for this mesh.
-    pub fn create_bvh(&mut self) {
-        self.bvh = Some(Bvh::build(self));
+    /// Meshes with at most [`BvhConfig::min_mesh_tris`] triangles are left without one.
+    pub fn create_bvh(&mut self, config: &BvhConfig) {
+        self.bvh = (self.tris.len() > config.min_mesh_tris).then(|| Bvh::build(self, config));
+    }
+
+    /// Distance to the nearest triangle hit by a ray, using the BVH if there is one.
+    pub fn ray_test(&self, pos: Vec3, dir: Vec3) -> Option<f32> {
+        self.ray_hit(pos, dir).map(|(_, dist)| dist)
+    }
+
+    /// Index of and distance to the nearest triangle hit by a ray, using the BVH if there is one.
+    pub fn ray_hit(&self, pos: Vec3, dir: Vec3) -> Option<(usize, f32)> {
+        match &self.bvh {
+            Some(bvh) => self.ray_test_bvh(bvh, pos, dir, dir.recip(), f32::INFINITY),
+            None => self.ray_test_range(0, self.tris.len(), pos, dir, f32::INFINITY),
+        }
+    }
+
+    /// Test a ray against a range of triangles, returning the nearest hit closer than `max_dist`.
+    fn ray_test_range(
+        &self,
+        begin: usize,
+        end: usize,
+        pos: Vec3,
+        dir: Vec3,
+        max_dist: f32,
+    ) -> Option<(usize, f32)> {
+        (begin..end)
+            .filter_map(|i| {
+                ray_test_tri(pos, dir, self.tris[i].map(|f| self.verts[f])).map(|f| (i, f))
+            })
+            .filter(|f| f.1 < max_dist)
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+    }
+
+    /// Test a ray against a BVH node, returning the nearest hit closer than `max_dist`.
+    fn ray_test_bvh(
+        &self,
+        bvh: &Bvh,
+        pos: Vec3,
+        dir: Vec3,
+        inv_dir: Vec3,
+        max_dist: f32,
+    ) -> Option<(usize, f32)> {
+        if ray_test_box(pos, inv_dir, bvh.min, bvh.max).is_none_or(|f| f >= max_dist) {
+            return None;
+        }
+        match &bvh.content {
+            BvhContent::Leaf(leaf) => self.ray_test_range(leaf.begin, leaf.end, pos, dir, max_dist),
+            BvhContent::Node((a, b)) => {
+                let hit_a = self.ray_test_bvh(a, pos, dir, inv_dir, max_dist);
+                let hit_b =
+                    self.ray_test_bvh(b, pos, dir, inv_dir, hit_a.map_or(max_dist, |f| f.1));
+                hit_b.or(hit_a)
+            }
+        }
+    }
+
+    /// Swap two triangles along with their per-triangle data.
+    fn swap_tris(&mut self, a: usize, b: usize) {
+        self.tris.swap(a, b);
+        if let Some(tri_materials) = self.tri_materials.as_mut() {
+            tri_materials.swap(a, b);
+        }
     }
 }
 
@@ -303,10 +810,38 @@ impl Hash for ObjPolyCorner {
 }
 impl Eq for ObjPolyCorner {}
 
+/// Parse vertex colors from the non-standard `v x y z r g b` OBJ extension.
+/// The `obj` crate discards these, so they are read separately and indexed like [`obj::ObjData::position`].
+/// Returns `None` if any vertex lacks a color.
+pub fn parse_obj_vert_cols(input: impl BufRead) -> io::Result<Option<Vec<Vec3>>> {
+    let mut cols = Vec::new();
+    for line in input.lines() {
+        let line = line?;
+        let mut words = line.split_whitespace();
+        if words.next() != Some("v") {
+            continue;
+        }
+        let values: Vec<f32> = words.filter_map(|f| f.parse().ok()).collect();
+        if values.len() < 6 {
+            return Ok(None);
+        }
+        cols.push(Vec3::new(values[3], values[4], values[5]));
+    }
+    Ok((!cols.is_empty()).then_some(cols))
+}
+
 impl Mesh {
     /// Create a mesh from an Obj group.
-    pub fn from_group(object: &Obj, group: &Group) -> Self {
+    /// If `vert_cols` is present, it is indexed by the Obj's vertex position indices.
+    pub fn from_group(object: &Obj, group: &Group, vert_cols: Option<&[Vec3]>) -> Self {
+        Self::from_groups(object, &[group], vert_cols)
+    }
+
+    /// Create a single mesh from several Obj groups, sharing vertices between them.
+    /// If there is more than one group, each triangle's material index is the index of its group.
+    pub fn from_groups(object: &Obj, groups: &[&Group], vert_cols: Option<&[Vec3]>) -> Self {
         let mut tris = Vec::<[usize; 3]>::new();
+        let mut tri_materials = Vec::<u32>::new();
         let mut verts = HashSet::<ObjPolyCorner>::new();
         let mut index = 0usize;
         let mut use_norm = false;
@@ -330,15 +865,18 @@ impl Mesh {
             })
         };
 
-        for poly in &group.polys {
-            if poly.0.len() != 3 {
-                continue;
+        for (material, group) in groups.iter().enumerate() {
+            for poly in &group.polys {
+                if poly.0.len() != 3 {
+                    continue;
+                }
+                tris.push([
+                    dedup_corner(&poly.0[0]),
+                    dedup_corner(&poly.0[1]),
+                    dedup_corner(&poly.0[2]),
+                ]);
+                tri_materials.push(material as u32);
             }
-            tris.push([
-                dedup_corner(&poly.0[0]),
-                dedup_corner(&poly.0[1]),
-                dedup_corner(&poly.0[2]),
-            ]);
         }
 
         let mut verts: Vec<_> = verts.into_iter().collect();
@@ -351,21 +889,226 @@ impl Mesh {
                 .iter()
                 .map(|f| object.data.position[f.pos].into())
                 .collect(),
+            // Corners with missing or out-of-range attributes are skipped rather than panicking;
+            // the length mismatch is reported by `validate`.
             normals: use_norm.then(|| {
                 verts
                     .iter()
-                    .map(|f| object.data.normal[f.normal.unwrap()].into())
+                    .filter_map(|f| object.data.normal.get(f.normal?))
+                    .map(|f| (*f).into())
+                    .collect()
+            }),
+            vert_cols: vert_cols.map(|cols| {
+                verts
+                    .iter()
+                    .filter_map(|f| cols.get(f.pos))
+                    .copied()
                     .collect()
             }),
-            vert_cols: None,
             vert_uv: use_uv.then(|| {
                 verts
                     .iter()
-                    .map(|f| object.data.texture[f.uv.unwrap()].into())
+                    .filter_map(|f| object.data.texture.get(f.uv?))
+                    .map(|f| (*f).into())
                     .collect()
             }),
+            tri_materials: (groups.len() > 1).then_some(tri_materials),
+            lods: vec![],
+            cull_backfaces: false,
         };
-        tmp.create_bvh();
+        tmp.create_bvh(&BvhConfig::default());
         tmp
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bvh_root_encloses_all_vertices() {
+        // The second triangle reaches past both triangles' minimum corners.
+        let mut mesh = Mesh {
+            bvh: None,
+            tris: vec![[0, 1, 2], [3, 4, 5]],
+            verts: vec![
+                Vec3::ZERO,
+                Vec3::X,
+                Vec3::Y,
+                Vec3::Z,
+                Vec3::new(2.0, 0.0, 1.0),
+                Vec3::new(0.0, 3.0, 1.0),
+            ],
+            normals: None,
+            vert_cols: None,
+            vert_uv: None,
+            tri_materials: None,
+            lods: vec![],
+            cull_backfaces: false,
+        };
+        let bvh = Bvh::build(&mut mesh, &BvhConfig::default());
+        assert_eq!(bvh.min, Vec3::ZERO);
+        assert_eq!(bvh.max, Vec3::new(2.0, 3.0, 1.0));
+    }
+
+    /// Mesh of `count` small disjoint triangles scattered through a 10-unit cube.
+    fn scattered_tris(count: usize) -> Mesh {
+        let mut verts = vec![];
+        let mut tris = vec![];
+        for i in 0..count {
+            // Cheap deterministic scatter; the exact distribution doesn't matter.
+            let hash = |salt: u32| {
+                let bits = (i as u32 * 3 + salt).wrapping_mul(2654435761);
+                (bits >> 16) as f32 % 1000.0 / 100.0
+            };
+            let base = Vec3::new(hash(1), hash(2), hash(3));
+            tris.push([verts.len(), verts.len() + 1, verts.len() + 2]);
+            verts.extend([base, base + Vec3::X * 0.1, base + Vec3::Y * 0.1]);
+        }
+        Mesh {
+            bvh: None,
+            tris,
+            verts,
+            normals: None,
+            vert_cols: None,
+            vert_uv: None,
+            tri_materials: None,
+            lods: vec![],
+            cull_backfaces: false,
+        }
+    }
+
+    /// Check that every node encloses its children and triangles, and that leaves cover every
+    /// triangle exactly once. Returns the number of triangles under the node.
+    fn check_bvh(mesh: &Mesh, bvh: &Bvh, covered: &mut [bool]) -> usize {
+        assert!(bvh.min.cmple(bvh.max).all(), "inverted bounds {bvh:?}");
+        match &bvh.content {
+            BvhContent::Leaf(leaf) => {
+                for (tri, covered) in mesh.tris[leaf.begin..leaf.end]
+                    .iter()
+                    .zip(&mut covered[leaf.begin..leaf.end])
+                {
+                    assert!(!*covered, "triangle in more than one leaf");
+                    *covered = true;
+                    for vert in tri.map(|f| mesh.verts[f]) {
+                        assert!(bvh.min.cmple(vert).all() && vert.cmple(bvh.max).all());
+                    }
+                }
+                leaf.end - leaf.begin
+            }
+            BvhContent::Node((a, b)) => {
+                for child in [a, b] {
+                    assert!(bvh.min.cmple(child.min).all() && child.max.cmple(bvh.max).all());
+                }
+                check_bvh(mesh, a, covered) + check_bvh(mesh, b, covered)
+            }
+        }
+    }
+
+    #[test]
+    fn bvh_build_modes_enclose_all_tris() {
+        for mode in [BvhBuildMode::Sah, BvhBuildMode::Median] {
+            let mut mesh = scattered_tris(500);
+            let config = BvhConfig {
+                mode,
+                ..Default::default()
+            };
+            mesh.create_bvh(&config);
+            let bvh = mesh.bvh.as_ref().expect("mesh should get a BVH");
+            assert!(
+                bvh.content.as_node().is_some(),
+                "{mode:?} didn't split the root"
+            );
+            let mut covered = vec![false; mesh.tris.len()];
+            assert_eq!(check_bvh(&mesh, bvh, &mut covered), mesh.tris.len());
+            assert!(covered.iter().all(|f| *f));
+        }
+    }
+
+    #[test]
+    fn split_median_halves_range() {
+        let mut mesh = scattered_tris(33);
+        let config = BvhConfig {
+            mode: BvhBuildMode::Median,
+            max_depth: 1,
+            ..Default::default()
+        };
+        mesh.create_bvh(&config);
+        let (a, b) = mesh.bvh.as_ref().unwrap().content.as_node().unwrap();
+        let (a, b) = (a.content.as_leaf().unwrap(), b.content.as_leaf().unwrap());
+        assert_eq!((a.begin, a.end, b.begin, b.end), (0, 16, 16, 33));
+    }
+
+    #[test]
+    fn validate_reports_bad_indices_and_attributes() {
+        let mut mesh = scattered_tris(2);
+        assert_eq!(mesh.validate(), vec![]);
+
+        mesh.tris[1][2] = 6;
+        mesh.normals = Some(vec![Vec3::Z; 5]);
+        mesh.vert_uv = Some(vec![Vec2::ZERO; 6]);
+        mesh.tri_materials = Some(vec![0; 3]);
+        assert_eq!(
+            mesh.validate(),
+            vec![
+                MeshIssue::IndexOutOfRange { tri: 1, index: 6 },
+                MeshIssue::AttributeLengthMismatch {
+                    attribute: "normals",
+                    len: 5,
+                    expected: 6,
+                },
+                MeshIssue::AttributeLengthMismatch {
+                    attribute: "tri_materials",
+                    len: 3,
+                    expected: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn eval_sah_rejects_empty_side() {
+        let config = BvhConfig::default();
+        let aux: Vec<_> = (0..3)
+            .map(|i| {
+                let min = Vec3::splat(i as f32);
+                BvhTriAux {
+                    min,
+                    max: min + 1.0,
+                    center: (min + 0.5).as_dvec3(),
+                }
+            })
+            .collect();
+
+        // Every center is above the split, so the side before it is empty.
+        assert_eq!(Bvh::eval_sah(&aux, 0, -1.0, true, &config), f64::MAX);
+        let after = Bvh::eval_sah(&aux, 0, -1.0, false, &config);
+        assert_eq!(
+            after,
+            3.0 * config.surface_area(Vec3::ZERO, Vec3::splat(3.0))
+        );
+
+        // Triangles sharing a center can't be split at all, so the root stays a leaf.
+        let mut mesh = scattered_tris(1);
+        mesh.tris = vec![[0, 1, 2]; 40];
+        mesh.create_bvh(&config);
+        assert!(mesh.bvh.unwrap().content.as_leaf().is_some());
+    }
+
+    #[test]
+    fn create_bvh_skips_small_meshes() {
+        let config = BvhConfig::default();
+        let mut small = scattered_tris(4);
+        small.create_bvh(&config);
+        assert!(small.bvh.is_none());
+
+        let mut large = scattered_tris(1000);
+        large.create_bvh(&config);
+        assert!(large.bvh.is_some());
+
+        // The threshold itself still gets no BVH.
+        let mut threshold = scattered_tris(config.min_mesh_tris);
+        threshold.create_bvh(&config);
+        assert!(threshold.bvh.is_none());
+    }
+}
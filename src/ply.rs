@@ -0,0 +1,273 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::SplitWhitespace;
+
+use glam::Vec3;
+
+use crate::mesh::{BvhConfig, Mesh};
+
+/// Scalar type of a PLY property.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PlyScalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl PlyScalar {
+    fn parse(name: &str) -> io::Result<Self> {
+        Ok(match name {
+            "char" | "int8" => Self::I8,
+            "uchar" | "uint8" => Self::U8,
+            "short" | "int16" => Self::I16,
+            "ushort" | "uint16" => Self::U16,
+            "int" | "int32" => Self::I32,
+            "uint" | "uint32" => Self::U32,
+            "float" | "float32" => Self::F32,
+            "double" | "float64" => Self::F64,
+            _ => return Err(invalid(format!("unknown PLY type `{name}`"))),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Self::I8 | Self::U8 => 1,
+            Self::I16 | Self::U16 => 2,
+            Self::I32 | Self::U32 | Self::F32 => 4,
+            Self::F64 => 8,
+        }
+    }
+
+    /// Scale that maps this type's range to [0, 1] when used as a color.
+    fn color_scale(self) -> f64 {
+        match self {
+            Self::U8 => 1.0 / 255.0,
+            Self::U16 => 1.0 / 65535.0,
+            _ => 1.0,
+        }
+    }
+}
+
+/// Property of a PLY element.
+#[derive(Debug, Clone)]
+struct PlyProperty {
+    name: String,
+    /// Type of the list length, if this is a list property.
+    count: Option<PlyScalar>,
+    value: PlyScalar,
+}
+
+/// Element declaration from a PLY header.
+#[derive(Debug, Clone)]
+struct PlyElement {
+    name: String,
+    count: usize,
+    props: Vec<PlyProperty>,
+}
+
+/// Body of a PLY file, after the header.
+enum PlyBody<'a> {
+    Ascii(SplitWhitespace<'a>),
+    Binary(&'a [u8]),
+}
+
+impl PlyBody<'_> {
+    fn read(&mut self, ty: PlyScalar) -> io::Result<f64> {
+        match self {
+            Self::Ascii(words) => words
+                .next()
+                .ok_or_else(|| invalid("unexpected end of PLY data"))?
+                .parse()
+                .map_err(|_| invalid("invalid number in PLY data")),
+            Self::Binary(data) => {
+                if data.len() < ty.size() {
+                    return Err(invalid("unexpected end of PLY data"));
+                }
+                let (bytes, rest) = data.split_at(ty.size());
+                *data = rest;
+                Ok(match ty {
+                    PlyScalar::I8 => bytes[0] as i8 as f64,
+                    PlyScalar::U8 => bytes[0] as f64,
+                    PlyScalar::I16 => i16::from_le_bytes(bytes.try_into().unwrap()) as f64,
+                    PlyScalar::U16 => u16::from_le_bytes(bytes.try_into().unwrap()) as f64,
+                    PlyScalar::I32 => i32::from_le_bytes(bytes.try_into().unwrap()) as f64,
+                    PlyScalar::U32 => u32::from_le_bytes(bytes.try_into().unwrap()) as f64,
+                    PlyScalar::F32 => f32::from_le_bytes(bytes.try_into().unwrap()) as f64,
+                    PlyScalar::F64 => f64::from_le_bytes(bytes.try_into().unwrap()),
+                })
+            }
+        }
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Parse a PLY header; returns the elements, whether the body is binary and the header length.
+fn parse_header(data: &[u8]) -> io::Result<(Vec<PlyElement>, bool, usize)> {
+    let mut elements = Vec::<PlyElement>::new();
+    let mut binary = None;
+    let mut offset = 0;
+
+    for line in data.split_inclusive(|f| *f == b'\n') {
+        offset += line.len();
+        let line = std::str::from_utf8(line).map_err(|_| invalid("invalid PLY header"))?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["ply"] | ["comment", ..] | ["obj_info", ..] | [] => (),
+            ["format", "ascii", _] => binary = Some(false),
+            ["format", "binary_little_endian", _] => binary = Some(true),
+            ["format", format, _] => {
+                return Err(invalid(format!("unsupported PLY format `{format}`")))
+            }
+            ["element", name, count] => elements.push(PlyElement {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .map_err(|_| invalid("invalid element count"))?,
+                props: vec![],
+            }),
+            ["property", "list", count, value, name] => elements
+                .last_mut()
+                .ok_or_else(|| invalid("PLY property outside of element"))?
+                .props
+                .push(PlyProperty {
+                    name: name.to_string(),
+                    count: Some(PlyScalar::parse(count)?),
+                    value: PlyScalar::parse(value)?,
+                }),
+            ["property", value, name] => elements
+                .last_mut()
+                .ok_or_else(|| invalid("PLY property outside of element"))?
+                .props
+                .push(PlyProperty {
+                    name: name.to_string(),
+                    count: None,
+                    value: PlyScalar::parse(value)?,
+                }),
+            ["end_header"] => {
+                let binary = binary.ok_or_else(|| invalid("missing PLY format"))?;
+                return Ok((elements, binary, offset));
+            }
+            _ => {
+                return Err(invalid(format!(
+                    "invalid PLY header line `{}`",
+                    line.trim()
+                )))
+            }
+        }
+    }
+
+    Err(invalid("missing PLY end_header"))
+}
+
+impl Mesh {
+    /// Load a mesh from an ASCII or binary little-endian PLY file.
+    /// Normals and vertex colors are used if present and polygons are triangulated as fans.
+    pub fn from_ply(path: impl AsRef<Path>) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        if !data.starts_with(b"ply") {
+            return Err(invalid("not a PLY file"));
+        }
+        let (elements, binary, offset) = parse_header(&data)?;
+        let mut body = if binary {
+            PlyBody::Binary(&data[offset..])
+        } else {
+            PlyBody::Ascii(
+                std::str::from_utf8(&data[offset..])
+                    .map_err(|_| invalid("invalid PLY data"))?
+                    .split_whitespace(),
+            )
+        };
+
+        let mut verts = vec![];
+        let mut normals = vec![];
+        let mut vert_cols = vec![];
+        let mut tris = vec![];
+        let mut has_normals = false;
+        let mut has_cols = false;
+
+        for element in &elements {
+            let find = |name: &str| element.props.iter().position(|f| f.name == name);
+            let pos = [find("x"), find("y"), find("z")];
+            let norm = [find("nx"), find("ny"), find("nz")];
+            let col = [find("red"), find("green"), find("blue")];
+            let indices = find("vertex_indices").or_else(|| find("vertex_index"));
+            let is_vertex = element.name == "vertex";
+            let is_face = element.name == "face";
+            if is_vertex {
+                if pos.iter().any(Option::is_none) {
+                    return Err(invalid("PLY vertices lack a position"));
+                }
+                has_normals = norm.iter().all(Option::is_some);
+                has_cols = col.iter().all(Option::is_some);
+            }
+
+            let mut values = vec![0.0; element.props.len()];
+            let mut list = vec![];
+            for _ in 0..element.count {
+                for (i, prop) in element.props.iter().enumerate() {
+                    match prop.count {
+                        Some(count) => {
+                            let count = body.read(count)? as usize;
+                            let keep = is_face && Some(i) == indices;
+                            if keep {
+                                list.clear();
+                            }
+                            for _ in 0..count {
+                                let value = body.read(prop.value)?;
+                                if keep {
+                                    list.push(value as usize);
+                                }
+                            }
+                        }
+                        None => values[i] = body.read(prop.value)?,
+                    }
+                }
+
+                if is_vertex {
+                    let get = |idx: [Option<usize>; 3]| {
+                        Vec3::from_array(idx.map(|f| values[f.unwrap()] as f32))
+                    };
+                    verts.push(get(pos));
+                    if has_normals {
+                        normals.push(get(norm));
+                    }
+                    if has_cols {
+                        let scale = element.props[col[0].unwrap()].value.color_scale();
+                        vert_cols.push(get(col) * scale as f32);
+                    }
+                } else if is_face && indices.is_some() {
+                    for i in 2..list.len() {
+                        tris.push([list[0], list[i - 1], list[i]]);
+                    }
+                }
+            }
+        }
+
+        if tris.iter().flatten().any(|f| *f >= verts.len()) {
+            return Err(invalid("PLY face index out of range"));
+        }
+
+        let mut tmp = Self {
+            bvh: None,
+            tris,
+            verts,
+            normals: has_normals.then_some(normals),
+            vert_cols: has_cols.then_some(vert_cols),
+            vert_uv: None,
+            tri_materials: None,
+            lods: vec![],
+            cull_backfaces: false,
+        };
+        tmp.create_bvh(&BvhConfig::default());
+        Ok(tmp)
+    }
+}
@@ -0,0 +1,50 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Writes per-frame timing statistics to a CSV file.
+pub struct FrameProfiler {
+    out: BufWriter<File>,
+    frame: u64,
+    last_flush: Instant,
+}
+
+impl FrameProfiler {
+    /// How often buffered rows are written to disk.
+    const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Create the CSV file and write its header.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "frame,compute_ms,present_ms,samples")?;
+        Ok(Self {
+            out,
+            frame: 0,
+            last_flush: Instant::now(),
+        })
+    }
+
+    /// Record the timings of a single frame.
+    pub fn record(&mut self, compute: Duration, present: Duration, samples: u32) -> io::Result<()> {
+        self.frame += 1;
+        writeln!(
+            self.out,
+            "{},{:.3},{:.3},{}",
+            self.frame,
+            compute.as_secs_f64() * 1000.0,
+            present.as_secs_f64() * 1000.0,
+            samples
+        )?;
+        if self.last_flush.elapsed() >= Self::FLUSH_INTERVAL {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write all buffered rows to disk.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        self.out.flush()
+    }
+}
@@ -1,10 +1,19 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::f32::consts::{FRAC_PI_2, PI};
+use std::fs::File;
+use std::io::BufReader;
 use std::ops::Mul;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::thread;
 
-use glam::{Mat4, Vec3};
+use glam::{Mat4, Quat, Vec3};
 use obj::Obj;
 
+use crate::export::srgb_to_linear;
 use crate::mesh::*;
+use crate::texture::Texture;
 
 #[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub struct Transform {
@@ -25,6 +34,22 @@ impl Mul<Transform> for Transform {
 }
 
 impl Transform {
+    /// Camera transform at `eye` looking at `target`, with `up` pointing up on screen.
+    /// The camera looks along its local +Z axis with +X to the right and +Y down on screen,
+    /// so in this renderer's Y-down world `up` is usually [`Vec3::NEG_Y`].
+    pub fn look_at(eye: Vec3, target: Vec3, up: Vec3) -> Self {
+        let forward = (target - eye).normalize();
+        let right = forward.cross(up).normalize();
+        let down = forward.cross(right);
+        Mat4::from_cols(
+            right.extend(0.0),
+            down.extend(0.0),
+            forward.extend(0.0),
+            eye.extend(1.0),
+        )
+        .into()
+    }
+
     pub fn matrix<'a>(&'a self) -> &'a Mat4 {
         &self.matrix
     }
@@ -59,6 +84,18 @@ impl Transform {
     pub fn world_to_local(&self, pos: Vec3) -> Vec3 {
         self.inv_matrix.transform_point3(pos)
     }
+    /// Interpolate between two transforms by decomposing them into scale, rotation and translation.
+    /// Rotation is interpolated with slerp, scale and translation linearly.
+    pub fn lerp(&self, other: &Transform, t: f32) -> Transform {
+        let (scale0, rot0, pos0) = self.matrix.to_scale_rotation_translation();
+        let (scale1, rot1, pos1) = other.matrix.to_scale_rotation_translation();
+        Mat4::from_scale_rotation_translation(
+            scale0.lerp(scale1, t),
+            rot0.slerp(rot1, t),
+            pos0.lerp(pos1, t),
+        )
+        .into()
+    }
 }
 
 impl From<Mat4> for Transform {
@@ -78,13 +115,72 @@ pub struct Ray {
     pub normal: Vec3,
 }
 
+impl Ray {
+    /// Distance to the nearest intersection with the unit sphere at the origin, if any.
+    /// CPU reference for the shader's sphere test; `normal` must be normalized.
+    /// Uses a quadratic formulation that stays accurate when the ray starts far from the sphere.
+    pub fn intersect_unit_sphere(&self) -> Option<f32> {
+        let a = -self.normal.dot(self.pos);
+        let closest = self.pos + a * self.normal;
+        let b = 1.0 - closest.length_squared();
+        let q = a + b.max(0.0).sqrt().copysign(a);
+        if b < 0.0 || q == 0.0 {
+            return None;
+        }
+        let dist0 = q;
+        let dist1 = (self.pos.length_squared() - 1.0) / q;
+        [dist0.min(dist1), dist0.max(dist1)]
+            .into_iter()
+            .find(|f| *f > 0.00001)
+    }
+
+    /// Distance to the XY-plane square with radius 1 at the origin, if it is hit.
+    /// CPU reference for the shader's plane test; `normal` must be normalized.
+    pub fn intersect_unit_plane(&self) -> Option<f32> {
+        let dist = -self.pos.z / self.normal.z;
+        if self.normal.z.abs() < 0.00001 || dist < 0.00001 {
+            return None;
+        }
+        let pos = self.pos + self.normal * dist;
+        (pos.x.abs() <= 1.0 && pos.y.abs() <= 1.0).then_some(dist)
+    }
+}
+
+/// Procedural checkerboard pattern alternating between a material's color and a second color.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Checker {
+    /// Color of the alternate cells.
+    pub color: Vec3,
+    /// Size of a cell in object space.
+    pub scale: f32,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct PhysProp {
     pub ior: f32,
     pub opacity: f32,
     pub roughness: f32,
     pub color: Vec3,
-    pub emission: Vec3,
+    /// Hue of the emitted light, multiplied by `emission_strength`.
+    pub emission_color: Vec3,
+    /// Intensity of the emitted light.
+    pub emission_strength: f32,
+    /// Optional checkerboard pattern evaluated from the object-space hit position.
+    pub checker: Option<Checker>,
+    /// Color that light is tinted by each time it scatters inside the object.
+    pub scatter_color: Vec3,
+    /// Mean distance light travels inside the object before scattering;
+    /// 0 disables subsurface scattering, leaving a plain dielectric.
+    pub scatter_radius: f32,
+    /// Rays pass straight through where the alpha texture is below this; 0 disables alpha testing.
+    pub alpha_cutoff: f32,
+    /// Index into [`Scene::textures`] whose alpha channel masks this material.
+    pub alpha_texture: Option<usize>,
+    /// Only light the scene: invisible to camera rays and mirror-like reflections,
+    /// but still seen by diffuse bounces.
+    pub light_only: bool,
+    /// Whether emission leaves both sides of the surface, or only the side its normal points to.
+    pub emit_both_sides: bool,
 }
 impl Eq for PhysProp {}
 
@@ -95,19 +191,73 @@ impl Default for PhysProp {
             opacity: 1.0,
             roughness: 0.5,
             color: Vec3::new(0.5, 0.5, 0.5),
-            emission: Vec3::ZERO,
+            emission_color: Vec3::ZERO,
+            emission_strength: 0.0,
+            checker: None,
+            scatter_color: Vec3::ONE,
+            scatter_radius: 0.0,
+            alpha_cutoff: 0.0,
+            alpha_texture: None,
+            light_only: false,
+            emit_both_sides: true,
         }
     }
 }
 
 impl PhysProp {
+    /// Get a copy with values the shader can't handle fixed up:
+    /// roughness and opacity are clamped to [0, 1], IOR to at least 1,
+    /// and NaN or infinite values are replaced with defaults.
+    pub fn sanitize(&self) -> PhysProp {
+        let default = PhysProp::default();
+        let finite = |value: f32, default: f32| if value.is_finite() { value } else { default };
+        let finite_vec =
+            |value: Vec3, default: Vec3| Vec3::select(value.is_finite_mask(), value, default);
+        PhysProp {
+            ior: finite(self.ior, default.ior).max(1.0),
+            opacity: finite(self.opacity, default.opacity).clamp(0.0, 1.0),
+            roughness: finite(self.roughness, default.roughness).clamp(0.0, 1.0),
+            color: finite_vec(self.color, default.color),
+            emission_color: finite_vec(self.emission_color, default.emission_color),
+            emission_strength: finite(self.emission_strength, default.emission_strength),
+            checker: self
+                .checker
+                .filter(|f| f.scale.is_finite())
+                .map(|f| Checker {
+                    color: finite_vec(f.color, default.color),
+                    ..f
+                }),
+            scatter_color: finite_vec(self.scatter_color, default.scatter_color),
+            scatter_radius: finite(self.scatter_radius, default.scatter_radius).max(0.0),
+            alpha_cutoff: finite(self.alpha_cutoff, default.alpha_cutoff).clamp(0.0, 1.0),
+            alpha_texture: self.alpha_texture,
+            light_only: self.light_only,
+            emit_both_sides: self.emit_both_sides,
+        }
+    }
+
+    /// Whether rays can never pass through or into a surface with this material.
+    pub fn is_opaque(&self) -> bool {
+        self.opacity >= 1.0
+            && self.scatter_radius <= 0.0
+            && (self.alpha_texture.is_none() || self.alpha_cutoff <= 0.0)
+    }
+
     pub fn from_color(color: Vec3) -> PhysProp {
         PhysProp {
             ior: 1.0,
             opacity: 1.0,
             roughness: 1.0,
             color,
-            emission: Vec3::ZERO,
+            emission_color: Vec3::ZERO,
+            emission_strength: 0.0,
+            checker: None,
+            scatter_color: Vec3::ONE,
+            scatter_radius: 0.0,
+            alpha_cutoff: 0.0,
+            alpha_texture: None,
+            light_only: false,
+            emit_both_sides: true,
         }
     }
     pub fn from_opacity(color: Vec3, opacity: f32) -> PhysProp {
@@ -116,17 +266,83 @@ impl PhysProp {
             opacity,
             roughness: 1.0,
             color,
-            emission: Vec3::ZERO,
+            emission_color: Vec3::ZERO,
+            emission_strength: 0.0,
+            checker: None,
+            scatter_color: Vec3::ONE,
+            scatter_radius: 0.0,
+            alpha_cutoff: 0.0,
+            alpha_texture: None,
+            light_only: false,
+            emit_both_sides: true,
+        }
+    }
+    /// Translucent material that scatters light below its surface, like wax or marble.
+    pub fn from_subsurface(color: Vec3, scatter_color: Vec3, scatter_radius: f32) -> PhysProp {
+        PhysProp {
+            ior: 1.4,
+            opacity: 0.0,
+            roughness: 0.3,
+            scatter_color,
+            scatter_radius,
+            ..Self::from_color(color)
+        }
+    }
+
+    pub fn from_checker(color: Vec3, checker_color: Vec3, scale: f32) -> PhysProp {
+        PhysProp {
+            checker: Some(Checker {
+                color: checker_color,
+                scale,
+            }),
+            ..Self::from_color(color)
         }
     }
-    pub fn from_emission(color: Vec3, emission: Vec3) -> PhysProp {
+    pub fn from_emission(color: Vec3, emission_color: Vec3, emission_strength: f32) -> PhysProp {
         PhysProp {
             ior: 1.0,
             opacity: 1.0,
             roughness: 1.0,
             color,
-            emission,
+            emission_color,
+            emission_strength,
+            checker: None,
+            scatter_color: Vec3::ONE,
+            scatter_radius: 0.0,
+            alpha_cutoff: 0.0,
+            alpha_texture: None,
+            light_only: false,
+            emit_both_sides: true,
+        }
+    }
+
+    /// Emitted light, combining its color and strength.
+    pub fn emission(&self) -> Vec3 {
+        self.emission_color * self.emission_strength
+    }
+
+    /// Parse an sRGB hex color of the form `#RRGGBB` (the `#` is optional) into linear RGB.
+    pub fn parse_hex(hex: &str) -> Result<Vec3, Box<dyn Error>> {
+        let digits = hex.strip_prefix('#').unwrap_or(hex);
+        if digits.len() != 6 || !digits.bytes().all(|f| f.is_ascii_hexdigit()) {
+            return Err(format!("Invalid hex color `{hex}`, expected `#RRGGBB`").into());
         }
+        let channel = |i: usize| {
+            let value = u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16).unwrap();
+            srgb_to_linear(value as f32 / 255.0)
+        };
+        Ok(Vec3::new(channel(0), channel(1), channel(2)))
+    }
+
+    /// Diffuse material with an sRGB hex color like `#RRGGBB`.
+    pub fn diffuse(hex: &str) -> Result<PhysProp, Box<dyn Error>> {
+        Ok(Self::from_color(Self::parse_hex(hex)?))
+    }
+
+    /// Light-emitting material with an sRGB hex color like `#RRGGBB`, scaled by `strength`.
+    pub fn emissive(hex: &str, strength: f32) -> Result<PhysProp, Box<dyn Error>> {
+        let color = Self::parse_hex(hex)?;
+        Ok(Self::from_emission(color, color, strength))
     }
 }
 
@@ -160,6 +376,24 @@ impl Default for Model {
     }
 }
 
+/// Procedural animation applied to a node's transform.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Animator {
+    /// Constant rotation around a local axis in radians per second.
+    Spin { axis: Vec3, speed: f32 },
+}
+
+impl Animator {
+    /// Advance the animation of `transform` by `dt` seconds.
+    pub fn apply(&self, transform: &mut Transform, dt: f32) {
+        match *self {
+            Self::Spin { axis, speed } => transform.set_matrix(
+                *transform.matrix() * Mat4::from_axis_angle(axis.normalize(), speed * dt),
+            ),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Node {
     /// Node's position, rotation and scale.
@@ -170,19 +404,127 @@ pub struct Node {
     pub model: Model,
     /// Node's material/properties.
     pub prop: PhysProp,
+    /// Whether `prop` is an importer's placeholder rather than an explicitly set material,
+    /// so [`Scene::set_default_material`] may replace it.
+    pub prop_is_default: bool,
+    /// Material table indexed by the mesh's per-triangle material indices.
+    /// Triangles whose index is out of range use `prop` instead.
+    pub materials: Vec<PhysProp>,
+    /// Optional animation of the node's transform.
+    pub animation: Option<Animator>,
+    /// Kinds of rays that see the node's model.
+    pub visibility: RayVisibility,
 }
 
-impl From<&Obj> for Node {
-    fn from(value: &Obj) -> Self {
+/// Bitmask of the kinds of rays that see an object; rays of other kinds pass through it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RayVisibility(pub u32);
+
+impl RayVisibility {
+    /// Rays coming straight from the camera.
+    pub const CAMERA: RayVisibility = RayVisibility(1);
+    /// Rays after a mirror-like reflection.
+    pub const REFLECTION: RayVisibility = RayVisibility(2);
+    /// Rays after refracting into or out of a transparent object.
+    pub const REFRACTION: RayVisibility = RayVisibility(4);
+    /// Rays after a rough or diffuse reflection.
+    pub const DIFFUSE: RayVisibility = RayVisibility(8);
+    pub const ALL: RayVisibility = RayVisibility(15);
+
+    /// Whether rays of every kind in `other` see the object.
+    pub fn contains(self, other: RayVisibility) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl Default for RayVisibility {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl std::ops::BitOr for RayVisibility {
+    type Output = RayVisibility;
+
+    fn bitor(self, rhs: RayVisibility) -> RayVisibility {
+        RayVisibility(self.0 | rhs.0)
+    }
+}
+
+/// How to import an Obj file with multiple groups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ObjGroupMode {
+    /// One child node with its own mesh per group.
+    #[default]
+    Split,
+    /// One mesh with per-triangle material indices, sharing vertices between groups.
+    Combined,
+}
+
+/// Axis that points up in an imported file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UpAxis {
+    /// +Y is up and +Z is the front, as in most Obj files and glTF.
+    #[default]
+    Y,
+    /// +Z is up and -Y is the front, as in CAD software and many PLY files.
+    Z,
+}
+
+impl UpAxis {
+    /// Get the rotation that stands a file with this up axis upright in the scene, where up is -Y,
+    /// with its front facing the default camera.
+    pub fn rotation(self) -> Quat {
+        match self {
+            Self::Y => Quat::from_rotation_x(PI),
+            Self::Z => Quat::from_rotation_x(FRAC_PI_2),
+        }
+    }
+}
+
+impl Node {
+    /// Advance this node's and its children's animations; returns whether anything changed.
+    pub fn update(&mut self, dt: f32) -> bool {
+        let mut changed = false;
+        if let Some(animation) = &self.animation {
+            animation.apply(&mut self.transform, dt);
+            changed = true;
+        }
+        for child in &mut self.children {
+            changed |= child.update(dt);
+        }
+        changed
+    }
+
+    /// Call `f` on every mesh in this node and its children.
+    fn for_each_mesh_mut(&mut self, f: &mut impl FnMut(&mut Arc<Mesh>)) {
+        if let Model::Mesh(mesh) = &mut self.model {
+            f(mesh);
+        }
+        for child in &mut self.children {
+            child.for_each_mesh_mut(f);
+        }
+    }
+
+    /// Create a node from an Obj, with optional per-position vertex colors.
+    fn from_obj(value: &Obj, vert_cols: Option<&[Vec3]>, mode: ObjGroupMode) -> Self {
         let mut groups = vec![];
 
         for object in &value.data.objects {
             groups.extend(object.groups.iter());
         }
 
-        if groups.len() == 1 {
+        // Obj materials aren't imported, so every node starts with the default material.
+        if mode == ObjGroupMode::Combined {
+            Self {
+                model: Model::Mesh(Arc::new(Mesh::from_groups(value, &groups, vert_cols))),
+                prop_is_default: true,
+                ..Default::default()
+            }
+        } else if groups.len() == 1 {
             Self {
-                model: Model::Mesh(Arc::new(Mesh::from_group(value, groups[0]))),
+                model: Model::Mesh(Arc::new(Mesh::from_group(value, groups[0], vert_cols))),
+                prop_is_default: true,
                 ..Default::default()
             }
         } else {
@@ -190,17 +532,94 @@ impl From<&Obj> for Node {
                 children: groups
                     .iter()
                     .map(|group| Self {
-                        model: Model::Mesh(Arc::new(Mesh::from_group(value, group))),
+                        model: Model::Mesh(Arc::new(Mesh::from_group(value, group, vert_cols))),
+                        prop_is_default: true,
                         ..Default::default()
                     })
                     .collect(),
+                prop_is_default: true,
                 ..Default::default()
             }
         }
     }
+
+    /// Load a Y-up Obj file, including vertex colors if the file has them.
+    pub fn load_obj(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        Self::load_obj_with(path, ObjGroupMode::default(), UpAxis::default())
+    }
+
+    /// Load an Obj file, choosing how multiple groups are imported.
+    /// The node's transform rotates the file's `up_axis` to the scene's up.
+    pub fn load_obj_with(
+        path: impl AsRef<Path>,
+        mode: ObjGroupMode,
+        up_axis: UpAxis,
+    ) -> Result<Self, Box<dyn Error>> {
+        let obj = Obj::load(path.as_ref())?;
+        let vert_cols = parse_obj_vert_cols(BufReader::new(File::open(path)?))?;
+        let mut node = Self::from_obj(&obj, vert_cols.as_deref(), mode);
+        node.transform = Mat4::from_quat(up_axis.rotation()).into();
+        Ok(node)
+    }
+
+    /// Load a Y-up PLY file as a single mesh.
+    pub fn load_ply(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        Self::load_ply_with(path, UpAxis::default())
+    }
+
+    /// Load a PLY file as a single mesh, whose transform rotates the file's `up_axis` to the scene's up.
+    pub fn load_ply_with(path: impl AsRef<Path>, up_axis: UpAxis) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            transform: Mat4::from_quat(up_axis.rotation()).into(),
+            model: Model::Mesh(Arc::new(Mesh::from_ply(path)?)),
+            prop_is_default: true,
+            ..Default::default()
+        })
+    }
 }
 
+impl From<&Obj> for Node {
+    fn from(value: &Obj) -> Self {
+        Self::from_obj(value, None, ObjGroupMode::default())
+    }
+}
+
+/// Directional light at infinity.
 #[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Sun {
+    /// Sun color.
+    pub color: Vec3,
+    /// Unit vector pointing at the sun.
+    pub direction: Vec3,
+    /// Dot product threshold for a ray to be pointing at the sun.
+    pub radius: f32,
+}
+
+impl Sun {
+    /// Get the sun's azimuth and elevation in radians, see [`Sun::set_angles`].
+    pub fn angles(&self) -> (f32, f32) {
+        let direction = self.direction.normalize_or_zero();
+        (
+            direction.x.atan2(direction.z),
+            (-direction.y).clamp(-1.0, 1.0).asin(),
+        )
+    }
+
+    /// Point the sun in a direction given in radians: the azimuth turns from +Z towards +X
+    /// and the elevation is above the horizon, where up is -Y.
+    /// At an elevation of ±90° the azimuth has no effect.
+    pub fn set_angles(&mut self, azimuth: f32, elevation: f32) {
+        let horizontal = elevation.cos();
+        self.direction = Vec3::new(
+            horizontal * azimuth.sin(),
+            -elevation.sin(),
+            horizontal * azimuth.cos(),
+        )
+        .normalize();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Skybox {
     /// Ground color.
     pub ground_color: Vec3,
@@ -208,23 +627,37 @@ pub struct Skybox {
     pub horizon_color: Vec3,
     /// Skybox color.
     pub skybox_color: Vec3,
-    /// Sun color.
-    pub sun_color: Vec3,
-    /// Unit vector pointing at the sun.
-    pub sun_direction: Vec3,
-    /// Dot product threshold for a ray to be pointing at the sun.
-    pub sun_radius: f32,
+    /// Suns; at most [`Skybox::MAX_SUNS`] are rendered.
+    pub suns: Vec<Sun>,
+    /// Optional cubemap face images ordered +X, -X, +Y, -Y, +Z, -Z, replacing the color gradient.
+    pub cubemap: Option<[PathBuf; 6]>,
 }
 
 impl Skybox {
+    /// Maximum number of suns the GPU can render.
+    pub const MAX_SUNS: usize = 4;
+
+    /// Get the azimuth and elevation of the first sun in radians, if there is one.
+    pub fn sun_angles(&self) -> Option<(f32, f32)> {
+        self.suns.first().map(Sun::angles)
+    }
+
+    /// Point the first sun in a direction given in radians, see [`Sun::set_angles`].
+    /// If there are no suns, the default sun is added first.
+    pub fn set_sun_angles(&mut self, azimuth: f32, elevation: f32) {
+        if self.suns.is_empty() {
+            self.suns.push(Skybox::default().suns[0]);
+        }
+        self.suns[0].set_angles(azimuth, elevation);
+    }
+
     pub fn empty() -> Skybox {
         Skybox {
             ground_color: Vec3::ZERO,
             horizon_color: Vec3::ZERO,
             skybox_color: Vec3::ZERO,
-            sun_color: Vec3::ZERO,
-            sun_direction: Vec3::NEG_Y,
-            sun_radius: 1.0,
+            suns: vec![],
+            cubemap: None,
         }
     }
 }
@@ -235,16 +668,545 @@ impl Default for Skybox {
             ground_color: Vec3::new(0.15, 0.075, 0.0375),
             horizon_color: Vec3::new(0.35, 0.45, 0.5),
             skybox_color: Vec3::new(0.0, 0.35, 0.4),
-            sun_color: Vec3::splat(16.0),
-            sun_direction: Vec3::new(0.577350269, -0.577350269, -0.577350269),
-            sun_radius: 0.9,
+            suns: vec![Sun {
+                color: Vec3::splat(16.0),
+                direction: Vec3::new(0.577350269, -0.577350269, -0.577350269),
+                radius: 0.9,
+            }],
+            cubemap: None,
         }
     }
 }
 
+/// Likely authoring mistake found by [`Scene::validate`].
+/// Nodes are identified by their path of child indices starting at the root.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SceneWarning {
+    /// Mesh without any triangles.
+    EmptyMesh { node: Vec<usize> },
+    /// Transform containing NaN or infinite values.
+    NonFiniteTransform { node: Vec<usize> },
+    /// Transform with a negative determinant, which flips normals.
+    NegativeScale { node: Vec<usize> },
+    /// Emission color with negative components.
+    NegativeEmission { node: Vec<usize> },
+    /// Index of refraction below 1.
+    IorBelowOne { node: Vec<usize>, ior: f32 },
+    /// Sun direction that is not a unit vector.
+    SunDirectionNotNormalized { sun: usize, length: f32 },
+    /// More suns than the GPU can render.
+    TooManySuns { count: usize },
+    /// Material referencing a texture that isn't in the scene.
+    MissingTexture { node: Vec<usize>, texture: usize },
+    /// Problem with the data of a mesh, see [`Mesh::validate`].
+    InvalidMesh { node: Vec<usize>, issue: MeshIssue },
+}
+
+impl Node {
+    /// Collect warnings for this node and its children.
+    fn validate(&self, path: &mut Vec<usize>, textures: usize, warnings: &mut Vec<SceneWarning>) {
+        if let Model::Mesh(mesh) = &self.model {
+            if mesh.tris.is_empty() {
+                warnings.push(SceneWarning::EmptyMesh { node: path.clone() });
+            }
+            warnings.extend(
+                mesh.validate()
+                    .into_iter()
+                    .map(|issue| SceneWarning::InvalidMesh {
+                        node: path.clone(),
+                        issue,
+                    }),
+            );
+        }
+        let matrix = self.transform.matrix();
+        if !matrix.is_finite() {
+            warnings.push(SceneWarning::NonFiniteTransform { node: path.clone() });
+        } else if matrix.determinant() < 0.0 {
+            warnings.push(SceneWarning::NegativeScale { node: path.clone() });
+        }
+        for prop in std::iter::once(&self.prop).chain(&self.materials) {
+            if prop.emission().min_element() < 0.0 {
+                warnings.push(SceneWarning::NegativeEmission { node: path.clone() });
+            }
+            if prop.ior < 1.0 {
+                warnings.push(SceneWarning::IorBelowOne {
+                    node: path.clone(),
+                    ior: prop.ior,
+                });
+            }
+            if let Some(texture) = prop.alpha_texture.filter(|f| *f >= textures) {
+                warnings.push(SceneWarning::MissingTexture {
+                    node: path.clone(),
+                    texture,
+                });
+            }
+        }
+        for (i, child) in self.children.iter().enumerate() {
+            path.push(i);
+            child.validate(path, textures, warnings);
+            path.pop();
+        }
+    }
+}
+
+/// Camera placement.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Camera {
+    /// Camera position and orientation; the camera looks along its local +Z axis.
+    pub transform: Transform,
+    /// Vertical field of view in radians.
+    pub fov: f32,
+}
+
 pub struct Scene {
     /// Scene root node.
     pub nodes: Vec<Node>,
     /// Scene skybox.
     pub skybox: Skybox,
+    /// Initial camera placement, or `None` to start at the origin.
+    pub camera: Option<Camera>,
+    /// Textures referenced by materials, e.g. [`PhysProp::alpha_texture`].
+    pub textures: Vec<Texture>,
+    /// Maximum number of threads used for preprocessing like BVH building; `None` uses all cores.
+    pub preprocess_threads: Option<usize>,
+}
+
+impl Scene {
+    /// Add a point cloud, rendering each point as a sphere of the given radius.
+    /// Points without a color use the default material color.
+    pub fn add_point_cloud(&mut self, points: &[Vec3], colors: Option<&[Vec3]>, radius: f32) {
+        let default_color = PhysProp::default().color;
+        self.nodes.push(Node {
+            children: points
+                .iter()
+                .enumerate()
+                .map(|(i, pos)| Node {
+                    model: Model::Sphere,
+                    transform: Mat4::from_scale_rotation_translation(
+                        Vec3::splat(radius),
+                        Quat::IDENTITY,
+                        *pos,
+                    )
+                    .into(),
+                    prop: PhysProp::from_color(
+                        colors
+                            .and_then(|f| f.get(i).copied())
+                            .unwrap_or(default_color),
+                    ),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        });
+    }
+
+    /// Add an instance of `mesh` at each of `transforms`, grouped under a new node.
+    /// The instances share one copy of the mesh, which is uploaded to the GPU once.
+    /// [`Scene::flatten_static`] bakes each static instance into its merged mesh separately,
+    /// so call it before adding instances that should stay shared.
+    pub fn add_instances(&mut self, mesh: Arc<Mesh>, transforms: &[Transform], prop: PhysProp) {
+        self.nodes.push(Node {
+            children: transforms
+                .iter()
+                .map(|transform| Node {
+                    model: Model::Mesh(mesh.clone()),
+                    transform: *transform,
+                    prop,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        });
+    }
+
+    /// Append another scene's nodes under a new node with `transform`.
+    /// This scene's skybox always wins and `other`'s is dropped; `other`'s camera,
+    /// moved by `transform`, is only used if this scene has none.
+    /// `other`'s textures are appended and its materials' texture indices adjusted to match.
+    pub fn merge(&mut self, other: Scene, transform: Transform) {
+        fn offset_textures(nodes: &mut [Node], offset: usize) {
+            for node in nodes {
+                for prop in std::iter::once(&mut node.prop).chain(&mut node.materials) {
+                    if let Some(texture) = &mut prop.alpha_texture {
+                        *texture += offset;
+                    }
+                }
+                offset_textures(&mut node.children, offset);
+            }
+        }
+
+        let mut nodes = other.nodes;
+        offset_textures(&mut nodes, self.textures.len());
+        self.textures.extend(other.textures);
+        if self.camera.is_none() {
+            self.camera = other.camera.map(|camera| Camera {
+                transform: transform * camera.transform,
+                ..camera
+            });
+        }
+        self.nodes.push(Node {
+            transform,
+            children: nodes,
+            ..Default::default()
+        });
+    }
+
+    /// Advance all animations in the scene; returns whether anything changed.
+    pub fn update(&mut self, dt: f32) -> bool {
+        let mut changed = false;
+        for node in &mut self.nodes {
+            changed |= node.update(dt);
+        }
+        changed
+    }
+
+    /// Get every node with a model along with its world transform, in the order they are uploaded to the GPU.
+    /// This is a depth-first, pre-order traversal of `nodes` that skips [`Model::None`];
+    /// object indices everywhere refer to this order.
+    pub fn objects(&self) -> Vec<(Transform, &Node)> {
+        fn collect<'a>(
+            out: &mut Vec<(Transform, &'a Node)>,
+            transform: Transform,
+            nodes: &'a [Node],
+        ) {
+            for node in nodes {
+                // A child's transform is applied first, then its parent's.
+                let transform = transform * node.transform;
+                if node.model != Model::None {
+                    out.push((transform, node));
+                }
+                collect(out, transform, &node.children);
+            }
+        }
+        let mut out = vec![];
+        collect(&mut out, Transform::default(), &self.nodes);
+        out
+    }
+
+    /// Get the node of an object by its index as in [`Scene::objects`].
+    pub fn object_mut(&mut self, index: usize) -> Option<&mut Node> {
+        fn find<'a>(nodes: &'a mut [Node], index: &mut usize) -> Option<&'a mut Node> {
+            for node in nodes {
+                if node.model != Model::None {
+                    if *index == 0 {
+                        return Some(node);
+                    }
+                    *index -= 1;
+                }
+                if let Some(found) = find(&mut node.children, index) {
+                    return Some(found);
+                }
+            }
+            None
+        }
+        find(&mut self.nodes, &mut { index })
+    }
+
+    /// Find the nearest object hit by a world-space ray on the CPU.
+    /// Returns its index as in [`Scene::objects`] and the distance to the hit.
+    pub fn ray_test(&self, ray: Ray) -> Option<(usize, f32)> {
+        self.objects()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (transform, node))| {
+                let local = transform.ray_world_to_local(ray);
+                let local = Ray {
+                    pos: local.pos,
+                    normal: local.normal.normalize(),
+                };
+                let dist = match &node.model {
+                    Model::None => None,
+                    Model::Sphere => local.intersect_unit_sphere(),
+                    Model::Plane => local.intersect_unit_plane(),
+                    Model::Mesh(mesh) => mesh.ray_test(local.pos, local.normal),
+                }?;
+                let pos = transform.local_to_world(local.pos + local.normal * dist);
+                Some((i, (pos - ray.pos).length()))
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+    }
+
+    /// Check the scene for common authoring mistakes that would otherwise render wrong silently.
+    pub fn validate(&self) -> Result<(), Vec<SceneWarning>> {
+        let mut warnings = vec![];
+        for (i, sun) in self.skybox.suns.iter().enumerate() {
+            let length = sun.direction.length();
+            if (length - 1.0).abs() > 0.001 {
+                warnings.push(SceneWarning::SunDirectionNotNormalized { sun: i, length });
+            }
+        }
+        if self.skybox.suns.len() > Skybox::MAX_SUNS {
+            warnings.push(SceneWarning::TooManySuns {
+                count: self.skybox.suns.len(),
+            });
+        }
+        let mut path = vec![];
+        for (i, node) in self.nodes.iter().enumerate() {
+            path.push(i);
+            node.validate(&mut path, self.textures.len(), &mut warnings);
+            path.pop();
+        }
+        if warnings.is_empty() {
+            Ok(())
+        } else {
+            Err(warnings)
+        }
+    }
+
+    /// Bake every static mesh, one without an animation on its node or any of its parents,
+    /// into world space and merge them into a single mesh node with one BVH.
+    /// Per-triangle materials keep each part's appearance; animated nodes, meshes hidden from some rays
+    /// and other models stay separate.
+    /// Instances sharing a mesh, e.g. from [`Scene::add_instances`], are baked as separate copies,
+    /// so static instances no longer share one mesh afterwards.
+    /// This changes object indices, so it should be done before the scene is uploaded.
+    pub fn flatten_static(&mut self) {
+        fn count(nodes: &[Node]) -> usize {
+            nodes
+                .iter()
+                .filter(|f| f.animation.is_none())
+                .map(|f| {
+                    matches!(
+                        (&f.model, f.visibility),
+                        (Model::Mesh(_), RayVisibility::ALL)
+                    ) as usize
+                        + count(&f.children)
+                })
+                .sum()
+        }
+        fn flatten(
+            nodes: &mut [Node],
+            transform: Transform,
+            mesh: &mut Mesh,
+            materials: &mut Vec<PhysProp>,
+        ) {
+            for node in nodes {
+                // Animated nodes move their children along, so none of them are static.
+                if node.animation.is_some() {
+                    continue;
+                }
+                let transform = transform * node.transform;
+                // Meshes hidden from some rays keep their own object for the visibility mask.
+                if let (Model::Mesh(part), RayVisibility::ALL) =
+                    (node.model.clone(), node.visibility)
+                {
+                    // Each part's material table follows its default material.
+                    let base = materials.len() as u32;
+                    let count = node.materials.len() as u32;
+                    materials.push(node.prop);
+                    materials.append(&mut node.materials);
+                    mesh.append(&part, *transform.matrix(), |f| match f {
+                        Some(index) if index < count => base + 1 + index,
+                        _ => base,
+                    });
+                    node.model = Model::None;
+                }
+                flatten(&mut node.children, transform, mesh, materials);
+            }
+        }
+
+        // Merging a single mesh would only cost memory.
+        if count(&self.nodes) < 2 {
+            return;
+        }
+        let mut mesh = Mesh {
+            bvh: None,
+            tris: vec![],
+            verts: vec![],
+            normals: None,
+            vert_cols: None,
+            vert_uv: None,
+            tri_materials: None,
+            lods: vec![],
+            cull_backfaces: false,
+        };
+        let mut materials = vec![];
+        flatten(
+            &mut self.nodes,
+            Transform::default(),
+            &mut mesh,
+            &mut materials,
+        );
+        mesh.create_bvh(&BvhConfig::default());
+        self.nodes.push(Node {
+            model: Model::Mesh(Arc::new(mesh)),
+            materials,
+            ..Default::default()
+        });
+    }
+
+    /// Give every mesh in the scene up to `levels` LODs, see [`Mesh::build_lods`].
+    /// Meshes shared between nodes stay shared.
+    pub fn build_lods(&mut self, levels: usize) {
+        let mut built = HashMap::<*const Mesh, Arc<Mesh>>::new();
+        for node in &mut self.nodes {
+            node.for_each_mesh_mut(&mut |mesh| {
+                *mesh = built
+                    .entry(Arc::as_ptr(mesh))
+                    .or_insert_with(|| {
+                        let mut mesh = Mesh::clone(mesh);
+                        mesh.build_lods(levels);
+                        Arc::new(mesh)
+                    })
+                    .clone();
+            });
+        }
+    }
+
+    /// Mark every mesh in the scene, including its LODs, as closed so rays may skip its back faces.
+    /// Objects with materials that rays can pass through are never culled.
+    pub fn set_cull_backfaces(&mut self, cull: bool) {
+        fn with_culling(mesh: &Mesh, cull: bool) -> Mesh {
+            let mut mesh = mesh.clone();
+            mesh.cull_backfaces = cull;
+            for lod in &mut mesh.lods {
+                *lod = Arc::new(with_culling(lod, cull));
+            }
+            mesh
+        }
+        let mut built = HashMap::<*const Mesh, Arc<Mesh>>::new();
+        for node in &mut self.nodes {
+            node.for_each_mesh_mut(&mut |mesh| {
+                *mesh = built
+                    .entry(Arc::as_ptr(mesh))
+                    .or_insert_with(|| Arc::new(with_culling(mesh, cull)))
+                    .clone();
+            });
+        }
+    }
+
+    /// Replace the material of every node that was imported without one, see [`Node::prop_is_default`].
+    /// Nodes keep being marked as default, so this can be called again to try another material.
+    pub fn set_default_material(&mut self, prop: PhysProp) {
+        fn apply(nodes: &mut [Node], prop: PhysProp) {
+            for node in nodes {
+                if node.prop_is_default {
+                    node.prop = prop;
+                }
+                apply(&mut node.children, prop);
+            }
+        }
+        apply(&mut self.nodes, prop);
+    }
+
+    /// Limit the number of threads used for preprocessing; 0 uses all cores.
+    pub fn set_preprocess_threads(&mut self, threads: usize) {
+        self.preprocess_threads = (threads > 0).then_some(threads);
+    }
+
+    /// Rebuild the BVH of every mesh in the scene with `config`, spread across up to `preprocess_threads` threads.
+    /// Meshes shared between nodes are built once and stay shared.
+    pub fn build_all_bvhs(&mut self, config: &BvhConfig) {
+        let mut meshes: Vec<Arc<Mesh>> = vec![];
+        let mut indices = HashMap::<*const Mesh, usize>::new();
+        for node in &mut self.nodes {
+            node.for_each_mesh_mut(&mut |mesh| {
+                indices.entry(Arc::as_ptr(mesh)).or_insert_with(|| {
+                    meshes.push(mesh.clone());
+                    meshes.len() - 1
+                });
+            });
+        }
+        if meshes.is_empty() {
+            return;
+        }
+
+        let threads = self
+            .preprocess_threads
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+        let chunk_size = meshes.len().div_ceil(threads);
+        let built: Vec<Arc<Mesh>> = thread::scope(|scope| {
+            let handles: Vec<_> = meshes
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|mesh| {
+                                let mut mesh = Mesh::clone(mesh);
+                                mesh.create_bvh(config);
+                                Arc::new(mesh)
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect()
+        });
+
+        for node in &mut self.nodes {
+            node.for_each_mesh_mut(&mut |mesh| {
+                *mesh = built[indices[&Arc::as_ptr(mesh)]].clone();
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::DVec3;
+
+    use super::*;
+
+    #[test]
+    fn child_transform_applies_before_parent() {
+        let scene = Scene {
+            nodes: vec![Node {
+                transform: Mat4::from_translation(Vec3::new(10.0, 0.0, 0.0)).into(),
+                children: vec![Node {
+                    transform: Mat4::from_scale(Vec3::splat(2.0)).into(),
+                    model: Model::Sphere,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            skybox: Skybox::default(),
+            camera: None,
+            textures: vec![],
+            preprocess_threads: None,
+        };
+        let objects = scene.objects();
+        assert_eq!(objects.len(), 1);
+        // Scaled around its own origin, then moved along with the parent.
+        let transform = objects[0].0;
+        assert_eq!(
+            transform.local_to_world(Vec3::ZERO),
+            Vec3::new(10.0, 0.0, 0.0)
+        );
+        assert_eq!(transform.local_to_world(Vec3::X), Vec3::new(12.0, 0.0, 0.0));
+        assert_eq!(transform.world_to_local(Vec3::new(12.0, 0.0, 0.0)), Vec3::X);
+    }
+
+    #[test]
+    fn intersect_distant_unit_sphere() {
+        const DISTANCE: f64 = 1e4;
+        let dir = DVec3::new(1.0, 2.0, 3.0).normalize();
+        let side = dir.cross(DVec3::X).normalize();
+        for offset in [0.0, 0.5, 0.9] {
+            let pos = -dir * DISTANCE + side * offset;
+            let ray = Ray {
+                pos: pos.as_vec3(),
+                normal: dir.as_vec3(),
+            };
+            // Distance to the near side of the sphere, in double precision.
+            let expected = DISTANCE - (1.0 - offset * offset).sqrt();
+            let dist = ray
+                .intersect_unit_sphere()
+                .expect("ray should hit the sphere") as f64;
+            assert!(
+                (dist - expected).abs() < 0.01,
+                "offset {offset}: got {dist}, expected {expected}"
+            );
+        }
+
+        // Rays passing just outside the sphere miss it.
+        let ray = Ray {
+            pos: (-dir * DISTANCE + side * 1.01).as_vec3(),
+            normal: dir.as_vec3(),
+        };
+        assert_eq!(ray.intersect_unit_sphere(), None);
+    }
 }
@@ -1,17 +1,25 @@
-use std::{error::Error, fmt::Debug, ops::Deref, sync::Arc, u32};
+use std::{
+    collections::HashMap, error::Error, fmt::Debug, mem::size_of, ops::Deref, sync::Arc, u32,
+};
 
-use glam::{Vec2, Vec3, Vec4};
+use glam::{Mat4, Vec2, Vec3, Vec4};
+use log::{debug, warn};
 use vulkano::{
     buffer::{Buffer, BufferContents, BufferCreateInfo, BufferUsage, Subbuffer},
-    memory::allocator::{AllocationCreateInfo, MemoryAllocator, MemoryTypeFilter},
+    device::{physical::PhysicalDevice, DeviceOwned},
+    memory::{
+        allocator::{AllocationCreateInfo, MemoryAllocator, MemoryTypeFilter},
+        MemoryPropertyFlags,
+    },
+    DeviceSize, Version, VulkanObject,
 };
 
-use crate::{mesh::*, scene::*};
+use crate::{export::color_space, mesh::*, scene::*, texture::Texture};
 
 /// On-GPU vec3.
 /// WARNING: The GPU types can be smaller than their alignment, so the most-aligned field must always come last.
 #[repr(C, align(16))]
-#[derive(Debug, Copy, Clone, BufferContents)]
+#[derive(Debug, Default, Copy, Clone, BufferContents)]
 pub struct GpuVec4 {
     pub x: f32,
     pub y: f32,
@@ -96,20 +104,57 @@ pub struct GpuPhysProp {
     pub ior: f32,
     pub opacity: f32,
     pub roughness: f32,
+    /// Checkerboard cell size, or 0 if there is no checkerboard.
+    pub checker_scale: f32,
     pub color: GpuVec4,
+    /// Emission color, with the strength in the fourth component.
     pub emission: GpuVec4,
+    /// Color of the alternate checkerboard cells.
+    pub checker_color: GpuVec4,
+    /// Subsurface scattering color, with the radius in the fourth component.
+    pub scatter: GpuVec4,
+    /// Alpha below which rays pass through; 0 disables alpha testing.
+    pub alpha_cutoff: f32,
+    /// Index of the alpha texture, or `u32::MAX` if there is none.
+    pub alpha_texture: u32,
+    /// Whether the object is invisible to camera rays and reflections.
+    pub light_only: u32,
+    /// Whether emission leaves both sides of the surface; this also pads the struct
+    /// to the 16-byte alignment the GPU uses for arrays of it.
+    pub emit_both_sides: u32,
 }
 unsafe impl Send for GpuPhysProp {}
 unsafe impl Sync for GpuPhysProp {}
 
 impl From<PhysProp> for GpuPhysProp {
     fn from(value: PhysProp) -> Self {
+        let sanitized = value.sanitize();
+        if sanitized != value {
+            warn!("Invalid material {:?}, using {:?}", value, sanitized);
+        }
+        let value = sanitized;
+        let space = color_space();
         Self {
             ior: value.ior,
             opacity: value.opacity,
             roughness: value.roughness,
-            color: value.color.into(),
-            emission: value.emission.into(),
+            checker_scale: value.checker.map_or(0.0, |f| f.scale),
+            color: space.convert_from_linear_srgb(value.color).into(),
+            emission: space
+                .convert_from_linear_srgb(value.emission_color)
+                .extend(value.emission_strength)
+                .into(),
+            checker_color: space
+                .convert_from_linear_srgb(value.checker.map_or(Vec3::ZERO, |f| f.color))
+                .into(),
+            scatter: space
+                .convert_from_linear_srgb(value.scatter_color)
+                .extend(value.scatter_radius)
+                .into(),
+            alpha_cutoff: value.alpha_cutoff,
+            alpha_texture: value.alpha_texture.map_or(u32::MAX, |f| f as u32),
+            light_only: value.light_only as u32,
+            emit_both_sides: value.emit_both_sides as u32,
         }
     }
 }
@@ -123,10 +168,40 @@ pub struct GpuObject {
     pub prop: GpuPhysProp,
     pub model_type: u32,
     pub model_index: u32,
+    /// Offset of the object's material table into the materials buffer.
+    pub material_offset: u32,
+    /// Number of entries in the object's material table.
+    pub material_count: u32,
+    /// Whether rays skip back-facing triangles of the object's mesh.
+    pub cull_backfaces: u32,
+    /// Kinds of rays that see the object, see [`RayVisibility`].
+    pub visibility_mask: u32,
+    /// Pads the struct to the 16-byte alignment the GPU uses for arrays of it.
+    pub _padding: [u32; 2],
 }
 unsafe impl Send for GpuObject {}
 unsafe impl Sync for GpuObject {}
 
+/// On-GPU representation of a sun.
+/// WARNING: The GPU types can be smaller than their alignment, so the most-aligned field must always come last.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, BufferContents)]
+pub struct GpuSun {
+    /// Sun color.
+    pub color: GpuVec4,
+    /// Unit vector pointing at the sun, with the dot product threshold in `w`.
+    pub direction: GpuVec4,
+}
+
+impl From<&Sun> for GpuSun {
+    fn from(value: &Sun) -> Self {
+        Self {
+            color: color_space().convert_from_linear_srgb(value.color).into(),
+            direction: value.direction.extend(value.radius).into(),
+        }
+    }
+}
+
 /// On-GPU representation of a skybox.
 /// WARNING: The GPU types can be smaller than their alignment, so the most-aligned field must always come last.
 #[repr(C)]
@@ -138,25 +213,26 @@ pub struct GpuSkybox {
     pub horizon_color: GpuVec4,
     /// Skybox color.
     pub skybox_color: GpuVec4,
-    /// Sun color.
-    pub sun_color: GpuVec4,
-    /// Unit vector pointing at the sun.
-    pub sun_direction: GpuVec4,
-    /// Dot product threshold for a ray to be pointing at the sun.
-    pub sun_radius: f32,
+    /// Suns; only the first `sun_count` are used.
+    pub suns: [GpuSun; Skybox::MAX_SUNS],
+    /// Number of suns.
+    pub sun_count: u32,
+    /// Whether to sample the cubemap instead of the color gradient.
+    pub use_cubemap: u32,
 }
 unsafe impl Send for GpuSkybox {}
 unsafe impl Sync for GpuSkybox {}
 
-impl From<Skybox> for GpuSkybox {
-    fn from(value: Skybox) -> Self {
+impl From<&Skybox> for GpuSkybox {
+    fn from(value: &Skybox) -> Self {
+        let space = color_space();
         Self {
-            ground_color: value.ground_color.into(),
-            horizon_color: value.horizon_color.into(),
-            skybox_color: value.skybox_color.into(),
-            sun_color: value.sun_color.into(),
-            sun_direction: value.sun_direction.into(),
-            sun_radius: value.sun_radius,
+            ground_color: space.convert_from_linear_srgb(value.ground_color).into(),
+            horizon_color: space.convert_from_linear_srgb(value.horizon_color).into(),
+            skybox_color: space.convert_from_linear_srgb(value.skybox_color).into(),
+            suns: std::array::from_fn(|i| value.suns.get(i).map(GpuSun::from).unwrap_or_default()),
+            sun_count: value.suns.len().min(Skybox::MAX_SUNS) as u32,
+            use_cubemap: value.cubemap.is_some() as u32,
         }
     }
 }
@@ -185,6 +261,11 @@ pub struct GpuMesh {
     /// UV coordinates' offset into the UVs buffer, or -1 if none.
     /// UV coordinates are a vec2.
     pub uv_offset: u32,
+    /// Triangle materials' offset into the triangle materials buffer, or -1 if none.
+    /// Triangle materials are a uint indexing the object's material table.
+    pub tri_mat_offset: u32,
+    /// Index of the next coarser LOD of this mesh, or -1 if none.
+    pub lod_next: u32,
 }
 unsafe impl Send for GpuMesh {}
 unsafe impl Sync for GpuMesh {}
@@ -207,6 +288,10 @@ unsafe impl Sync for GpuBvh {}
 
 /// On-GPU representation of a scene.
 /// Unlike the others, this is not a single bufferable object, but a collection of buffers.
+///
+/// Object `i` is always the `i`-th entry of [`Scene::objects`]: a depth-first, pre-order
+/// traversal of [`Scene::nodes`] that skips nodes without a model.
+/// Meshes are deduplicated separately, so instancing never changes object indices.
 pub struct GpuScene {
     pub objects: Subbuffer<[GpuObject]>,
     pub object_count: u32,
@@ -218,6 +303,16 @@ pub struct GpuScene {
     pub uvs: Subbuffer<[GpuVec2]>,
     pub skybox: Subbuffer<[GpuSkybox]>,
     pub bvh: Subbuffer<[GpuBvh]>,
+    pub tri_materials: Subbuffer<[u32]>,
+    pub materials: Subbuffer<[GpuPhysProp]>,
+    /// Top-level BVH over the objects; leaves index into `instances`.
+    pub tlas: Subbuffer<[GpuBvh]>,
+    /// Object indices referenced by the top-level BVH leaves.
+    pub instances: Subbuffer<[u32]>,
+    /// Object-space bounds of each object, used to rebuild the top-level BVH.
+    pub object_bounds: Vec<(Vec3, Vec3)>,
+    /// Alpha masks; see [`build_alpha_masks`] for the layout.
+    pub alpha_masks: Subbuffer<[u32]>,
 }
 
 impl Debug for GpuScene {
@@ -232,11 +327,65 @@ impl Debug for GpuScene {
             .field("vcols", &self.vcols.read().unwrap().deref())
             .field("uvs", &self.uvs.read().unwrap().deref())
             .field("bvh", &self.bvh.read().unwrap().deref())
+            .field("tri_materials", &self.tri_materials.read().unwrap().deref())
+            .field("materials", &self.materials.read().unwrap().deref())
+            .field("tlas", &self.tlas.read().unwrap().deref())
+            .field("instances", &self.instances.read().unwrap().deref())
             .field("skybox", &self.skybox.read().unwrap().deref())
+            .field("alpha_masks", &self.alpha_masks.len())
             .finish()
     }
 }
 
+/// Pack the alpha channels of textures, with their mip chains, into one buffer.
+/// It starts with the number of textures and the offset of each texture.
+/// Each texture is its width, height and number of mip levels, the offset of each level,
+/// and then each level's row-major alpha values, four per word.
+fn build_alpha_masks(textures: &[Texture]) -> Vec<u32> {
+    let mut out = vec![textures.len() as u32];
+    out.resize(1 + textures.len(), 0);
+    for (i, texture) in textures.iter().enumerate() {
+        out[1 + i] = out.len() as u32;
+        let levels = texture.mip_levels() as usize;
+        out.extend([texture.width, texture.height, levels as u32]);
+        let level_offsets = out.len();
+        out.resize(level_offsets + levels, 0);
+
+        let mut size = (texture.width as usize, texture.height as usize);
+        let mut alpha: Vec<u8> = texture.pixels.iter().map(|f| f[3]).collect();
+        for level in 0..levels {
+            out[level_offsets + level] = out.len() as u32;
+            out.extend(
+                alpha
+                    .chunks(4)
+                    .map(|f| f.iter().rev().fold(0, |acc, f| (acc << 8) | *f as u32)),
+            );
+            if level + 1 < levels {
+                (size, alpha) = downsample_alpha(size, &alpha);
+            }
+        }
+    }
+    out
+}
+
+/// Halve the size of an alpha mask, averaging each 2x2 block of texels.
+/// Alpha is stored linearly, so averaging the stored values is already gamma-correct.
+fn downsample_alpha((width, height): (usize, usize), alpha: &[u8]) -> ((usize, usize), Vec<u8>) {
+    let size = ((width / 2).max(1), (height / 2).max(1));
+    let texel = |x: usize, y: usize| alpha[y.min(height - 1) * width + x.min(width - 1)] as u32;
+    let mut out = Vec::with_capacity(size.0 * size.1);
+    for y in 0..size.1 {
+        for x in 0..size.0 {
+            let sum = texel(2 * x, 2 * y)
+                + texel(2 * x + 1, 2 * y)
+                + texel(2 * x, 2 * y + 1)
+                + texel(2 * x + 1, 2 * y + 1);
+            out.push(((sum + 2) / 4) as u8);
+        }
+    }
+    (size, out)
+}
+
 #[derive(Default)]
 struct NodeBuildCtx {
     objects: Vec<GpuObject>,
@@ -247,10 +396,293 @@ struct NodeBuildCtx {
     vcols: Vec<GpuVec4>,
     uvs: Vec<GpuVec2>,
     bvh: Vec<GpuBvh>,
+    tri_materials: Vec<u32>,
+    materials: Vec<GpuPhysProp>,
+    /// Object-space bounds of each mesh.
+    mesh_bounds: Vec<(Vec3, Vec3)>,
+    /// Meshes that have already been uploaded, so instances share them.
+    mesh_indices: HashMap<*const Mesh, usize>,
+}
+
+impl NodeBuildCtx {
+    /// Total size in bytes of the buffers that will be created from this.
+    fn buffer_size(&self) -> DeviceSize {
+        (self.objects.len() * size_of::<GpuObject>()
+            + self.meshes.len() * size_of::<GpuMesh>()
+            + self.tris.len() * size_of::<u32>()
+            + self.verts.len() * size_of::<GpuVec4>()
+            + self.norms.len() * size_of::<GpuVec4>()
+            + self.vcols.len() * size_of::<GpuVec4>()
+            + self.uvs.len() * size_of::<GpuVec2>()
+            + self.bvh.len() * size_of::<GpuBvh>()
+            + self.tri_materials.len() * size_of::<u32>()
+            + self.materials.len() * size_of::<GpuPhysProp>()
+            + size_of::<GpuSkybox>()) as DeviceSize
+    }
+
+    /// Collect the objects of a scene in the order of [`Scene::objects`],
+    /// which picking and updates rely on, along with the data they reference.
+    fn from_scene(scene: &Scene) -> Result<NodeBuildCtx, Box<dyn Error>> {
+        let mut ctx = NodeBuildCtx::default();
+        for (transform, node) in scene.objects() {
+            if let Some(object) = GpuScene::build_node(&mut ctx, transform, node)? {
+                ctx.objects.push(object);
+            }
+        }
+        ctx.check_lengths()?;
+        Ok(ctx)
+    }
+
+    /// Give every empty buffer a placeholder element, since buffers can't be empty.
+    /// An empty scene still needs an object buffer; its object count stays 0 so the shader
+    /// never reads it and only shows the skybox.
+    fn add_placeholders(&mut self) {
+        if self.objects.is_empty() {
+            self.objects.push(GpuObject {
+                transform: Transform::default().into(),
+                prop: PhysProp::default().into(),
+                model_type: GpuObjectType::Sphere as u32,
+                model_index: 0,
+                material_offset: 0,
+                material_count: 0,
+                cull_backfaces: 0,
+                visibility_mask: RayVisibility::ALL.0,
+                _padding: [0; 2],
+            });
+        }
+        if self.meshes.is_empty() {
+            self.meshes.push(GpuMesh {
+                num_tris: 0,
+                bvh_offset: 0,
+                tri_offset: 0,
+                vert_offset: 0,
+                norm_offset: 0,
+                vcol_offset: 0,
+                uv_offset: 0,
+                tri_mat_offset: 0,
+                lod_next: u32::MAX,
+            });
+        }
+        if self.tris.is_empty() {
+            self.tris.push(0);
+        }
+        if self.verts.is_empty() {
+            self.verts.push(Vec4::splat(0.0).into());
+        }
+        if self.norms.is_empty() {
+            self.norms.push(Vec4::splat(0.0).into());
+        }
+        if self.vcols.is_empty() {
+            self.vcols.push(Vec4::splat(0.0).into());
+        }
+        if self.uvs.is_empty() {
+            self.uvs.push(Vec2::splat(0.0).into());
+        }
+        if self.bvh.is_empty() {
+            self.bvh.push(GpuBvh {
+                min: Vec4::splat(0.0).into(),
+                max: Vec4::splat(0.0).into(),
+                children: 0,
+                tri_count: 0,
+            });
+        }
+        if self.tri_materials.is_empty() {
+            self.tri_materials.push(0);
+        }
+        if self.materials.is_empty() {
+            self.materials.push(PhysProp::default().into());
+        }
+    }
+
+    /// Check that every buffer can be indexed by the shader's 32-bit indices.
+    fn check_lengths(&self) -> Result<(), Box<dyn Error>> {
+        gpu_index(self.objects.len(), "objects")?;
+        gpu_index(self.meshes.len(), "meshes")?;
+        gpu_index(self.tris.len(), "triangle indices")?;
+        gpu_index(self.verts.len(), "vertices")?;
+        gpu_index(self.norms.len(), "normals")?;
+        gpu_index(self.vcols.len(), "vertex colors")?;
+        gpu_index(self.uvs.len(), "UVs")?;
+        gpu_index(self.bvh.len(), "BVH nodes")?;
+        gpu_index(self.tri_materials.len(), "triangle materials")?;
+        gpu_index(self.materials.len(), "materials")?;
+        Ok(())
+    }
+}
+
+/// Convert a buffer length, offset or index to the `u32` the shader uses.
+/// Fails rather than wrapping for scenes too large to address; `u32::MAX` is reserved for "none".
+fn gpu_index(value: usize, what: &str) -> Result<u32, Box<dyn Error>> {
+    u32::try_from(value)
+        .ok()
+        .filter(|f| *f != u32::MAX)
+        .ok_or_else(|| {
+            format!(
+                "Scene has {} {}, more than 32-bit GPU indices can address",
+                value, what
+            )
+            .into()
+        })
+}
+
+/// Whether `VK_EXT_memory_budget` can be enabled and queried on a physical device,
+/// which also needs `vkGetPhysicalDeviceMemoryProperties2` from Vulkan 1.1 or its instance extension.
+pub fn supports_memory_budget(physical_device: &PhysicalDevice) -> bool {
+    let has_props2 = physical_device.api_version() >= Version::V1_1
+        || physical_device
+            .instance()
+            .enabled_extensions()
+            .khr_get_physical_device_properties2;
+    physical_device.supported_extensions().ext_memory_budget && has_props2
+}
+
+/// Get the memory available for scene buffers, which are allocated in host-visible memory.
+/// Uses the remaining budget from `VK_EXT_memory_budget` if supported, or the heap size otherwise.
+pub fn available_memory(physical_device: &PhysicalDevice) -> DeviceSize {
+    let mem_props = physical_device.memory_properties();
+    let heaps = mem_props
+        .memory_types
+        .iter()
+        .filter(|f| f.property_flags.contains(MemoryPropertyFlags::HOST_VISIBLE))
+        .map(|f| f.heap_index as usize);
+
+    if !supports_memory_budget(physical_device) {
+        return heaps
+            .map(|f| mem_props.memory_heaps[f].size)
+            .max()
+            .unwrap_or(0);
+    }
+
+    let mut budget = ash::vk::PhysicalDeviceMemoryBudgetPropertiesEXT::default();
+    let mut props = ash::vk::PhysicalDeviceMemoryProperties2::default().push_next(&mut budget);
+    let fns = physical_device.instance().fns();
+    unsafe {
+        if physical_device.api_version() >= Version::V1_1 {
+            (fns.v1_1.get_physical_device_memory_properties2)(physical_device.handle(), &mut props);
+        } else {
+            (fns.khr_get_physical_device_properties2
+                .get_physical_device_memory_properties2_khr)(
+                physical_device.handle(), &mut props
+            );
+        }
+    }
+    heaps
+        .map(|f| budget.heap_budget[f].saturating_sub(budget.heap_usage[f]))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Get the world-space bounds of a box transformed by `matrix`.
+fn transform_bounds(matrix: &Mat4, (min, max): (Vec3, Vec3)) -> (Vec3, Vec3) {
+    (0..8)
+        .map(|i| {
+            let corner = Vec3::new(
+                if i & 1 == 0 { min.x } else { max.x },
+                if i & 2 == 0 { min.y } else { max.y },
+                if i & 4 == 0 { min.z } else { max.z },
+            );
+            matrix.transform_point3(corner)
+        })
+        .fold((Vec3::MAX, Vec3::MIN), |(min, max), f| {
+            (min.min(f), max.max(f))
+        })
+}
+
+/// Recursively build a top-level BVH node over `instances` by splitting at the median object.
+fn build_tlas_node(
+    tlas: &mut Vec<GpuBvh>,
+    index: usize,
+    bounds: &[(Vec3, Vec3)],
+    instances: &mut [u32],
+    offset: usize,
+) {
+    let (min, max) = instances
+        .iter()
+        .map(|f| bounds[*f as usize])
+        .fold((Vec3::MAX, Vec3::MIN), |(min_a, max_a), (min_b, max_b)| {
+            (min_a.min(min_b), max_a.max(max_b))
+        });
+    tlas[index].min = min.into();
+    tlas[index].max = max.into();
+
+    // Leaves hold a single object so their bounds can double as the object's bounds.
+    if instances.len() <= 1 {
+        tlas[index].children = offset as u32;
+        tlas[index].tri_count = instances.len() as u32;
+        return;
+    }
+
+    // Split along the longest axis.
+    let size = max - min;
+    let axis = if size.x >= size.y && size.x >= size.z {
+        0
+    } else if size.y >= size.z {
+        1
+    } else {
+        2
+    };
+    let center = |f: &u32| {
+        let (min, max) = bounds[*f as usize];
+        (min + max)[axis]
+    };
+    instances.sort_by(|a, b| center(a).total_cmp(&center(b)));
+
+    let child_index = tlas.len();
+    tlas[index].children = child_index as u32;
+    tlas[index].tri_count = 0;
+    for _ in 0..2 {
+        tlas.push(GpuBvh {
+            min: Vec4::ZERO.into(),
+            max: Vec4::ZERO.into(),
+            children: 0,
+            tri_count: 0,
+        });
+    }
+    let midpoint = instances.len() / 2;
+    let (first, second) = instances.split_at_mut(midpoint);
+    build_tlas_node(tlas, child_index, bounds, first, offset);
+    build_tlas_node(tlas, child_index + 1, bounds, second, offset + midpoint);
+}
+
+/// Build a top-level BVH from the world-space bounds of every object.
+fn build_tlas(bounds: &[(Vec3, Vec3)]) -> (Vec<GpuBvh>, Vec<u32>) {
+    let mut instances: Vec<u32> = (0..bounds.len() as u32).collect();
+    let mut tlas = vec![GpuBvh {
+        min: Vec4::ZERO.into(),
+        max: Vec4::ZERO.into(),
+        children: 0,
+        tri_count: 0,
+    }];
+    build_tlas_node(&mut tlas, 0, bounds, &mut instances, 0);
+    (tlas, instances)
+}
+
+impl GpuObject {
+    /// Get this object's object-space bounds.
+    fn local_bounds(&self, mesh_bounds: &[(Vec3, Vec3)]) -> (Vec3, Vec3) {
+        match self.model_type {
+            x if x == GpuObjectType::Sphere as u32 => (Vec3::NEG_ONE, Vec3::ONE),
+            // Planes get some thickness so the bounds are never degenerate.
+            x if x == GpuObjectType::Plane as u32 => {
+                (Vec3::new(-1.0, -1.0, -0.001), Vec3::new(1.0, 1.0, 0.001))
+            }
+            _ => mesh_bounds[self.model_index as usize],
+        }
+    }
+
+    /// Get this object's world-space bounds.
+    fn world_bounds(&self, local_bounds: (Vec3, Vec3)) -> (Vec3, Vec3) {
+        transform_bounds(&Mat4::from_cols_array(&self.transform.matrix), local_bounds)
+    }
 }
 
 impl GpuScene {
-    fn build_bvh(out: &mut NodeBuildCtx, node: &Bvh, index: usize, tri_offset: u32) {
+    fn build_bvh(
+        out: &mut NodeBuildCtx,
+        node: &Bvh,
+        index: usize,
+        tri_offset: u32,
+    ) -> Result<(), Box<dyn Error>> {
         match &node.content {
             BvhContent::Node(val) => {
                 out.bvh.push(GpuBvh {
@@ -266,26 +698,37 @@ impl GpuScene {
                     tri_count: 0,
                 });
                 let child_index = out.bvh.len() - 2;
-                out.bvh[index].children = child_index as u32;
-                Self::build_bvh(out, &val.0, child_index, tri_offset);
-                Self::build_bvh(out, &val.1, child_index + 1, tri_offset);
+                out.bvh[index].children = gpu_index(child_index, "BVH nodes")?;
+                Self::build_bvh(out, &val.0, child_index, tri_offset)?;
+                Self::build_bvh(out, &val.1, child_index + 1, tri_offset)?;
             }
             BvhContent::Leaf(leaf) => {
-                out.bvh[index].children = leaf.begin as u32 + tri_offset;
-                out.bvh[index].tri_count = (leaf.end - leaf.begin) as u32;
+                out.bvh[index].children = gpu_index(leaf.begin + tri_offset as usize, "triangles")?;
+                out.bvh[index].tri_count = gpu_index(leaf.end - leaf.begin, "triangles")?;
             }
         }
+        Ok(())
     }
 
-    fn build_mesh(out: &mut NodeBuildCtx, mesh: &Mesh) {
+    /// Build a mesh followed by its LODs and return its index.
+    fn build_mesh(out: &mut NodeBuildCtx, mesh: &Mesh) -> Result<usize, Box<dyn Error>> {
+        out.mesh_bounds.push(
+            mesh.verts
+                .iter()
+                .fold((Vec3::MAX, Vec3::MIN), |(min, max), f| {
+                    (min.min(*f), max.max(*f))
+                }),
+        );
         let mut gpu_mesh = GpuMesh {
-            num_tris: mesh.tris.len() as u32,
+            num_tris: gpu_index(mesh.tris.len(), "triangles")?,
             bvh_offset: u32::MAX,
-            tri_offset: (out.tris.len() / 3) as u32,
-            vert_offset: out.verts.len() as u32,
+            tri_offset: gpu_index(out.tris.len() / 3, "triangles")?,
+            vert_offset: gpu_index(out.verts.len(), "vertices")?,
             norm_offset: u32::MAX,
             vcol_offset: u32::MAX,
             uv_offset: u32::MAX,
+            tri_mat_offset: u32::MAX,
+            lod_next: u32::MAX,
         };
         out.tris.reserve(mesh.tris.len() * 3);
         for tri in &mesh.tris {
@@ -296,67 +739,172 @@ impl GpuScene {
         out.verts
             .extend(mesh.verts.iter().map(|f| GpuVec4::from(*f)));
         if let Some(bvh) = mesh.bvh.as_ref() {
-            gpu_mesh.bvh_offset = out.bvh.len() as u32;
+            gpu_mesh.bvh_offset = gpu_index(out.bvh.len(), "BVH nodes")?;
             out.bvh.push(GpuBvh {
                 min: bvh.min.into(),
                 max: bvh.max.into(),
                 children: 0,
                 tri_count: 0,
             });
-            println!("Converting BVH to GPU format...");
-            Self::build_bvh(out, bvh, out.bvh.len() - 1, gpu_mesh.tri_offset);
-            println!("Done! Created {} entries", out.bvh.len());
+            debug!("Converting BVH to GPU format...");
+            Self::build_bvh(out, bvh, out.bvh.len() - 1, gpu_mesh.tri_offset)?;
+            debug!("Done! Created {} entries", out.bvh.len());
         }
-        if let Some(normals) = mesh.normals.as_ref() {
-            gpu_mesh.norm_offset = out.norms.len() as u32;
+        // Attributes of the wrong length (see `Mesh::validate`) are dropped so the shader never reads past them.
+        let verts = mesh.verts.len();
+        if let Some(normals) = mesh.normals.as_ref().filter(|f| f.len() == verts) {
+            gpu_mesh.norm_offset = gpu_index(out.norms.len(), "normals")?;
             out.norms.extend(normals.iter().map(|f| GpuVec4::from(*f)));
         }
-        if let Some(vcols) = mesh.vert_cols.as_ref() {
-            gpu_mesh.vcol_offset = out.vcols.len() as u32;
-            out.vcols.extend(vcols.iter().map(|f| GpuVec4::from(*f)));
+        if let Some(vcols) = mesh.vert_cols.as_ref().filter(|f| f.len() == verts) {
+            gpu_mesh.vcol_offset = gpu_index(out.vcols.len(), "vertex colors")?;
+            let space = color_space();
+            out.vcols.extend(
+                vcols
+                    .iter()
+                    .map(|f| GpuVec4::from(space.convert_from_linear_srgb(*f))),
+            );
         }
-        if let Some(uvs) = mesh.vert_uv.as_ref() {
-            gpu_mesh.uv_offset = out.uvs.len() as u32;
+        if let Some(uvs) = mesh.vert_uv.as_ref().filter(|f| f.len() == verts) {
+            gpu_mesh.uv_offset = gpu_index(out.uvs.len(), "UVs")?;
             out.uvs.extend(uvs.iter().map(|f| GpuVec2::from(*f)));
         }
+        if let Some(tri_materials) = mesh
+            .tri_materials
+            .as_ref()
+            .filter(|f| f.len() == mesh.tris.len())
+        {
+            gpu_mesh.tri_mat_offset = gpu_index(out.tri_materials.len(), "triangle materials")?;
+            out.tri_materials.extend(tri_materials.iter());
+        }
         out.meshes.push(gpu_mesh);
+
+        let index = out.meshes.len() - 1;
+        let mut prev = index;
+        for lod in &mesh.lods {
+            let lod_index = Self::build_mesh(out, lod)?;
+            out.meshes[prev].lod_next = gpu_index(lod_index, "meshes")?;
+            prev = lod_index;
+        }
+        Ok(index)
     }
 
-    fn build_node(out: &mut NodeBuildCtx, transform: Transform, node: &Node) -> GpuObject {
+    /// Build an object from a node, given its world transform.
+    /// Returns `None` for nodes without a model, which only group their children.
+    fn build_node(
+        out: &mut NodeBuildCtx,
+        transform: Transform,
+        node: &Node,
+    ) -> Result<Option<GpuObject>, Box<dyn Error>> {
         let (model_type, model_index) = match &node.model {
-            Model::None => unreachable!(),
+            Model::None => return Ok(None),
             Model::Sphere => (GpuObjectType::Sphere, 0),
             Model::Plane => (GpuObjectType::Plane, 0),
             Model::Mesh(mesh) => {
-                let index = out.meshes.len();
-                Self::build_mesh(out, mesh);
+                // Instances of the same mesh share its GPU data.
+                let key = Arc::as_ptr(mesh);
+                let index = match out.mesh_indices.get(&key) {
+                    Some(index) => *index,
+                    None => {
+                        let index = Self::build_mesh(out, mesh)?;
+                        out.mesh_indices.insert(key, index);
+                        index
+                    }
+                };
                 (GpuObjectType::Mesh, index)
             }
         };
-        GpuObject {
-            transform: (node.transform * transform).into(),
+        // Culling is only safe if no ray can ever see the inside of the mesh.
+        let cull_backfaces = match &node.model {
+            Model::Mesh(mesh) => {
+                mesh.cull_backfaces
+                    && node.prop.is_opaque()
+                    && node.materials.iter().all(PhysProp::is_opaque)
+            }
+            _ => false,
+        };
+        let material_offset = gpu_index(out.materials.len(), "materials")?;
+        out.materials
+            .extend(node.materials.iter().map(|f| GpuPhysProp::from(*f)));
+        Ok(Some(GpuObject {
+            transform: transform.into(),
             prop: node.prop.into(),
             model_type: model_type as u32,
-            model_index: model_index as u32,
+            model_index: gpu_index(model_index, "meshes")?,
+            material_offset,
+            material_count: gpu_index(node.materials.len(), "materials")?,
+            cull_backfaces: cull_backfaces as u32,
+            visibility_mask: node.visibility.0,
+            _padding: [0; 2],
+        }))
+    }
+
+    /// Upload new skybox parameters.
+    pub fn update_skybox(&self, skybox: &Skybox) -> Result<(), Box<dyn Error>> {
+        self.skybox.write()?[0] = skybox.into();
+        Ok(())
+    }
+
+    /// Upload a new material for a single object, by its index as in [`Scene::objects`].
+    pub fn update_object(&self, index: usize, prop: PhysProp) -> Result<(), Box<dyn Error>> {
+        if index >= self.object_count as usize {
+            return Err(format!("Object {} out of range", index).into());
+        }
+        let mut objects = self.objects.write()?;
+        objects[index].prop = prop.into();
+        // A material that rays can pass through would expose the culled back faces.
+        if !prop.is_opaque() {
+            objects[index].cull_backfaces = 0;
         }
+        Ok(())
     }
 
-    fn build_nodes(out: &mut NodeBuildCtx, transform: Transform, nodes: &[Node]) {
-        for node in nodes {
-            if node.model != Model::None {
-                let tmp = Self::build_node(out, transform, node);
-                out.objects.push(tmp);
-            }
-            Self::build_nodes(out, transform * node.transform, &node.children);
+    /// Update the object transforms and top-level BVH in place after the scene's nodes have moved.
+    /// The scene must have the same structure as the one this was built from.
+    pub fn update_transforms(&self, scene: &Scene) -> Result<(), Box<dyn Error>> {
+        let mut objects = self.objects.write()?;
+        for (object, (transform, _)) in objects.iter_mut().zip(scene.objects()) {
+            object.transform = transform.into();
+        }
+        if self.object_count == 0 {
+            return Ok(());
         }
+
+        let bounds: Vec<_> = objects
+            .iter()
+            .zip(&self.object_bounds)
+            .map(|(object, local)| object.world_bounds(*local))
+            .collect();
+        let (tlas, instances) = build_tlas(&bounds);
+        self.tlas.write()?.copy_from_slice(&tlas);
+        self.instances.write()?.copy_from_slice(&instances);
+        Ok(())
     }
 
     pub fn build(
         allocator: Arc<dyn MemoryAllocator>,
         scene: &Scene,
     ) -> Result<Self, Box<dyn Error>> {
-        let mut ctx = NodeBuildCtx::default();
-        Self::build_nodes(&mut ctx, Default::default(), &scene.nodes);
+        let mut ctx = NodeBuildCtx::from_scene(scene)?;
+        let object_count = ctx.objects.len() as u32;
+
+        // Build the top-level BVH over all objects.
+        let object_bounds: Vec<_> = ctx
+            .objects
+            .iter()
+            .map(|f| f.local_bounds(&ctx.mesh_bounds))
+            .collect();
+        let world_bounds: Vec<_> = ctx
+            .objects
+            .iter()
+            .zip(&object_bounds)
+            .map(|(object, local)| object.world_bounds(*local))
+            .collect();
+        let (tlas, instances) = if world_bounds.is_empty() {
+            (vec![], vec![])
+        } else {
+            build_tlas(&world_bounds)
+        };
 
         let buf_info = BufferCreateInfo {
             usage: BufferUsage::STORAGE_BUFFER,
@@ -368,99 +916,125 @@ impl GpuScene {
         };
 
         // TODO: Vulkano doesn't support bindless yet.
-        if ctx.meshes.is_empty() {
-            ctx.meshes.push(GpuMesh {
-                num_tris: 0,
-                bvh_offset: 0,
-                tri_offset: 0,
-                vert_offset: 0,
-                norm_offset: 0,
-                vcol_offset: 0,
-                uv_offset: 0,
-            });
-        }
-        if ctx.tris.is_empty() {
-            ctx.tris.push(0);
-        }
-        if ctx.verts.is_empty() {
-            ctx.verts.push(Vec4::splat(0.0).into());
-        }
-        if ctx.norms.is_empty() {
-            ctx.norms.push(Vec4::splat(0.0).into());
-        }
-        if ctx.vcols.is_empty() {
-            ctx.vcols.push(Vec4::splat(0.0).into());
-        }
-        if ctx.uvs.is_empty() {
-            ctx.uvs.push(Vec2::splat(0.0).into());
-        }
-        if ctx.bvh.is_empty() {
-            ctx.bvh.push(GpuBvh {
-                min: Vec4::splat(0.0).into(),
-                max: Vec4::splat(0.0).into(),
-                children: 0,
-                tri_count: 0,
-            });
+        ctx.add_placeholders();
+
+        // Fail early with a useful message rather than on allocation.
+        let alpha_masks = build_alpha_masks(&scene.textures);
+        let required = ctx.buffer_size()
+            + (tlas.len() * size_of::<GpuBvh>()
+                + instances.len() * size_of::<u32>()
+                + alpha_masks.len() * size_of::<u32>()) as DeviceSize;
+        let available = available_memory(allocator.device().physical_device());
+        if required > available {
+            return Err(format!(
+                "Scene needs an estimated {:.1} MiB of buffers, but only {:.1} MiB of device memory is available",
+                required as f64 / 1048576.0,
+                available as f64 / 1048576.0
+            )
+            .into());
         }
 
         let objects = Buffer::from_iter(
             allocator.clone(),
             buf_info.clone(),
             alloc_info.clone(),
-            ctx.objects.into_iter(),
+            ctx.objects,
         )?;
         let meshes = Buffer::from_iter(
             allocator.clone(),
             buf_info.clone(),
             alloc_info.clone(),
-            ctx.meshes.into_iter(),
+            ctx.meshes,
         )?;
         let tris = Buffer::from_iter(
             allocator.clone(),
             buf_info.clone(),
             alloc_info.clone(),
-            ctx.tris.into_iter(),
+            ctx.tris,
         )?;
         let verts = Buffer::from_iter(
             allocator.clone(),
             buf_info.clone(),
             alloc_info.clone(),
-            ctx.verts.into_iter(),
+            ctx.verts,
         )?;
         let norms = Buffer::from_iter(
             allocator.clone(),
             buf_info.clone(),
             alloc_info.clone(),
-            ctx.norms.into_iter(),
+            ctx.norms,
         )?;
         let vcols = Buffer::from_iter(
             allocator.clone(),
             buf_info.clone(),
             alloc_info.clone(),
-            ctx.vcols.into_iter(),
+            ctx.vcols,
         )?;
         let uvs = Buffer::from_iter(
             allocator.clone(),
             buf_info.clone(),
             alloc_info.clone(),
-            ctx.uvs.into_iter(),
+            ctx.uvs,
         )?;
         let bvh = Buffer::from_iter(
             allocator.clone(),
             buf_info.clone(),
             alloc_info.clone(),
-            ctx.bvh.into_iter(),
+            ctx.bvh,
+        )?;
+        let tri_materials = Buffer::from_iter(
+            allocator.clone(),
+            buf_info.clone(),
+            alloc_info.clone(),
+            ctx.tri_materials,
+        )?;
+        let materials = Buffer::from_iter(
+            allocator.clone(),
+            buf_info.clone(),
+            alloc_info.clone(),
+            ctx.materials,
+        )?;
+        let tlas = Buffer::from_iter(
+            allocator.clone(),
+            buf_info.clone(),
+            alloc_info.clone(),
+            if tlas.is_empty() {
+                vec![GpuBvh {
+                    min: Vec4::ZERO.into(),
+                    max: Vec4::ZERO.into(),
+                    children: 0,
+                    tri_count: 0,
+                }]
+            } else {
+                tlas
+            },
+        )?;
+        let instances = Buffer::from_iter(
+            allocator.clone(),
+            buf_info.clone(),
+            alloc_info.clone(),
+            if instances.is_empty() {
+                vec![0]
+            } else {
+                instances
+            },
+        )?;
+        let alpha_masks = Buffer::from_iter(
+            allocator.clone(),
+            buf_info.clone(),
+            alloc_info.clone(),
+            alpha_masks,
         )?;
         let skybox = Buffer::from_iter(
             allocator,
             buf_info.clone(),
             alloc_info.clone(),
-            [GpuSkybox::from(scene.skybox)].into_iter(),
+            [GpuSkybox::from(&scene.skybox)],
         )?;
 
         Ok(Self {
             objects,
-            object_count: scene.nodes.len() as u32,
+            object_count,
             skybox,
             meshes,
             tris,
@@ -469,6 +1043,143 @@ impl GpuScene {
             vcols,
             uvs,
             bvh,
+            tri_materials,
+            materials,
+            tlas,
+            instances,
+            object_bounds,
+            alpha_masks,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scene(nodes: Vec<Node>) -> Scene {
+        Scene {
+            nodes,
+            skybox: Skybox::default(),
+            camera: None,
+            textures: vec![],
+            preprocess_threads: None,
+        }
+    }
+
+    fn triangle() -> Arc<Mesh> {
+        Arc::new(Mesh {
+            bvh: None,
+            tris: vec![[0, 1, 2]],
+            verts: vec![Vec3::ZERO, Vec3::X, Vec3::Y],
+            normals: None,
+            vert_cols: None,
+            vert_uv: None,
+            tri_materials: None,
+            lods: vec![],
+            cull_backfaces: false,
+        })
+    }
+
+    /// Node with its IOR set to `tag`, to tell the objects apart after building.
+    fn tagged(model: Model, tag: f32, x: f32, children: Vec<Node>) -> Node {
+        Node {
+            model,
+            transform: Mat4::from_translation(Vec3::new(x, 0.0, 0.0)).into(),
+            prop: PhysProp {
+                ior: tag,
+                ..Default::default()
+            },
+            children,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn objects_match_gpu_order() {
+        let mut scene = scene(vec![
+            tagged(
+                Model::None,
+                1.0,
+                1.0,
+                vec![
+                    tagged(Model::Sphere, 1.1, 2.0, vec![]),
+                    tagged(
+                        Model::None,
+                        1.0,
+                        3.0,
+                        vec![tagged(Model::Plane, 1.2, 4.0, vec![])],
+                    ),
+                ],
+            ),
+            tagged(Model::Mesh(triangle()), 1.3, 5.0, vec![]),
+            tagged(
+                Model::Sphere,
+                1.4,
+                6.0,
+                vec![tagged(Model::Mesh(triangle()), 1.5, 7.0, vec![])],
+            ),
+        ]);
+        let ctx = NodeBuildCtx::from_scene(&scene).unwrap();
+        let objects: Vec<_> = scene
+            .objects()
+            .into_iter()
+            .map(|(transform, node)| (transform, node.prop.ior))
+            .collect();
+        assert_eq!(objects.len(), ctx.objects.len());
+        assert_eq!(
+            objects.iter().map(|f| f.1).collect::<Vec<_>>(),
+            [1.1, 1.2, 1.3, 1.4, 1.5]
+        );
+
+        for (i, (gpu, (transform, ior))) in ctx.objects.iter().zip(objects).enumerate() {
+            assert_eq!(gpu.prop.ior, ior, "object {i}");
+            assert_eq!(gpu.transform.matrix, transform.matrix().to_cols_array());
+            assert_eq!(scene.object_mut(i).unwrap().prop.ior, ior);
+        }
+    }
+
+    #[test]
+    fn empty_scene_gets_placeholders() {
+        let mut ctx = NodeBuildCtx::from_scene(&scene(vec![])).unwrap();
+        assert!(ctx.objects.is_empty());
+        ctx.add_placeholders();
+        let lengths = [
+            ctx.objects.len(),
+            ctx.meshes.len(),
+            ctx.tris.len(),
+            ctx.verts.len(),
+            ctx.norms.len(),
+            ctx.vcols.len(),
+            ctx.uvs.len(),
+            ctx.bvh.len(),
+            ctx.tri_materials.len(),
+            ctx.materials.len(),
+        ];
+        assert_eq!(lengths, [1; 10]);
+    }
+
+    #[test]
+    fn instances_share_one_mesh() {
+        let transforms: Vec<Transform> = (0..5)
+            .map(|i| Mat4::from_translation(Vec3::new(i as f32, 0.0, 0.0)).into())
+            .collect();
+        let mut scene = scene(vec![]);
+        scene.add_instances(triangle(), &transforms, PhysProp::default());
+        let ctx = NodeBuildCtx::from_scene(&scene).unwrap();
+        assert_eq!(ctx.objects.len(), 5);
+        assert_eq!(ctx.meshes.len(), 1);
+        assert_eq!(ctx.tris.len(), 3);
+    }
+
+    #[test]
+    fn gpu_index_rejects_reserved_and_overflowing_values() {
+        assert_eq!(gpu_index(0, "meshes").unwrap(), 0);
+        assert_eq!(
+            gpu_index(u32::MAX as usize - 1, "meshes").unwrap(),
+            u32::MAX - 1
+        );
+        assert!(gpu_index(u32::MAX as usize, "meshes").is_err());
+        assert!(gpu_index(u32::MAX as usize + 1, "meshes").is_err());
+    }
+}
@@ -0,0 +1,261 @@
+use std::{error::Error, fs, io, path::Path, sync::Arc};
+
+use glam::Vec3;
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage},
+    command_buffer::{
+        allocator::StandardCommandBufferAllocator, AutoCommandBufferBuilder, CommandBufferUsage,
+        CopyBufferToImageInfo, PrimaryCommandBufferAbstract,
+    },
+    device::{DeviceOwned, Queue},
+    format::Format,
+    image::{
+        sampler::{Filter, Sampler, SamplerAddressMode, SamplerCreateInfo},
+        view::{ImageView, ImageViewCreateInfo, ImageViewType},
+        Image, ImageCreateFlags, ImageCreateInfo, ImageType, ImageUsage,
+    },
+    memory::allocator::{AllocationCreateInfo, MemoryAllocator, MemoryTypeFilter},
+    sync::GpuFuture,
+};
+
+use crate::export::{linear_to_srgb, srgb_to_linear, ColorSpace};
+
+/// 8-bit RGBA texture in CPU memory.
+#[derive(Debug, Clone)]
+pub struct Texture {
+    pub width: u32,
+    pub height: u32,
+    /// Pixels in row-major order, starting at the top-left.
+    pub pixels: Vec<[u8; 4]>,
+    /// Whether the color channels are sRGB-encoded; alpha is always linear.
+    pub srgb: bool,
+}
+
+/// Texture uploaded to the GPU, with its sampler.
+#[derive(Debug, Clone)]
+pub struct GpuTexture {
+    pub view: Arc<ImageView>,
+    pub sampler: Arc<Sampler>,
+}
+
+impl Texture {
+    /// Create a texture filled with a single color.
+    pub fn solid(width: u32, height: u32, color: [u8; 4]) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![color; (width * height) as usize],
+            srgb: true,
+        }
+    }
+
+    /// Convert the colors from sRGB to another working color space.
+    pub fn convert_color_space(&mut self, space: ColorSpace) {
+        if space == ColorSpace::LinearSrgb {
+            return;
+        }
+        let srgb = self.srgb;
+        let decode = |x: u8| {
+            let x = x as f32 / 255.0;
+            if srgb {
+                srgb_to_linear(x)
+            } else {
+                x
+            }
+        };
+        let encode = |x: f32| {
+            let x = if srgb { linear_to_srgb(x) } else { x };
+            (x * 255.0 + 0.5) as u8
+        };
+        for pixel in &mut self.pixels {
+            let color = Vec3::from_array([0, 1, 2].map(|i| decode(pixel[i])));
+            let color = space
+                .convert_from_linear_srgb(color)
+                .clamp(Vec3::ZERO, Vec3::ONE);
+            for i in 0..3 {
+                pixel[i] = encode(color[i]);
+            }
+        }
+    }
+
+    /// Load an sRGB texture from a binary (P6) or ASCII (P3) PPM file.
+    pub fn load_ppm(path: impl AsRef<Path>) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let data = fs::read(path)?;
+
+        // Read the header, skipping whitespace and comments.
+        let mut pos = 0;
+        let mut header = vec![];
+        while header.len() < 4 {
+            while pos < data.len() && (data[pos].is_ascii_whitespace() || data[pos] == b'#') {
+                if data[pos] == b'#' {
+                    while pos < data.len() && data[pos] != b'\n' {
+                        pos += 1;
+                    }
+                } else {
+                    pos += 1;
+                }
+            }
+            let start = pos;
+            while pos < data.len() && !data[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            if start == pos {
+                return Err(invalid("truncated PPM header"));
+            }
+            header.push(
+                std::str::from_utf8(&data[start..pos])
+                    .map_err(|_| invalid("invalid PPM header"))?,
+            );
+        }
+        let binary = match header[0] {
+            "P6" => true,
+            "P3" => false,
+            _ => return Err(invalid("unsupported PPM format")),
+        };
+        let mut dims = header[1..].iter().map(|f| f.parse::<u32>());
+        let (Some(Ok(width)), Some(Ok(height)), Some(Ok(max_val))) =
+            (dims.next(), dims.next(), dims.next())
+        else {
+            return Err(invalid("invalid PPM header"));
+        };
+        if max_val == 0 || max_val > 255 {
+            return Err(invalid("unsupported PPM bit depth"));
+        }
+
+        let count = (width * height * 3) as usize;
+        let values: Vec<u32> = if binary {
+            // Exactly one whitespace byte separates the header from the data.
+            data.get(pos + 1..pos + 1 + count)
+                .ok_or_else(|| invalid("truncated PPM data"))?
+                .iter()
+                .map(|f| *f as u32)
+                .collect()
+        } else {
+            std::str::from_utf8(&data[pos..])
+                .map_err(|_| invalid("invalid PPM data"))?
+                .split_whitespace()
+                .take(count)
+                .map(|f| f.parse().map_err(|_| invalid("invalid PPM data")))
+                .collect::<io::Result<_>>()?
+        };
+        if values.len() < count {
+            return Err(invalid("truncated PPM data"));
+        }
+
+        Ok(Self {
+            width,
+            height,
+            pixels: values
+                .chunks(3)
+                .map(|f| {
+                    let [r, g, b] = [0, 1, 2].map(|i| (f[i].min(max_val) * 255 / max_val) as u8);
+                    [r, g, b, 255]
+                })
+                .collect(),
+            srgb: true,
+        })
+    }
+
+    /// Save the color channels as a binary (P6) PPM file, dropping alpha.
+    pub fn save_ppm(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut data = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        data.extend(self.pixels.iter().flat_map(|f| [f[0], f[1], f[2]]));
+        fs::write(path, data)
+    }
+
+    /// Number of mip levels in a full chain down to 1x1.
+    pub fn mip_levels(&self) -> u32 {
+        32 - self.width.max(self.height).max(1).leading_zeros()
+    }
+
+    /// Image format to use; sRGB formats make the GPU filter in linear space.
+    pub fn format(&self) -> Format {
+        if self.srgb {
+            Format::R8G8B8A8_SRGB
+        } else {
+            Format::R8G8B8A8_UNORM
+        }
+    }
+
+    /// Upload six square faces, ordered +X, -X, +Y, -Y, +Z, -Z, as a cubemap with a bilinear sampler.
+    pub fn upload_cubemap(
+        faces: &[Texture; 6],
+        allocator: Arc<dyn MemoryAllocator>,
+        cmd_alloc: Arc<StandardCommandBufferAllocator>,
+        queue: Arc<Queue>,
+    ) -> Result<GpuTexture, Box<dyn Error>> {
+        let size = faces[0].width;
+        if faces
+            .iter()
+            .any(|f| f.width != size || f.height != size || f.srgb != faces[0].srgb)
+        {
+            return Err("Cubemap faces must be square and of the same size and format".into());
+        }
+        let device = allocator.device().clone();
+
+        let staging = Buffer::from_iter(
+            allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::TRANSFER_SRC,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            faces
+                .iter()
+                .flat_map(|f| f.pixels.iter().copied())
+                .collect::<Vec<_>>(),
+        )?;
+        let image = Image::new(
+            allocator,
+            ImageCreateInfo {
+                flags: ImageCreateFlags::CUBE_COMPATIBLE,
+                image_type: ImageType::Dim2d,
+                format: faces[0].format(),
+                extent: [size, size, 1],
+                array_layers: 6,
+                usage: ImageUsage::TRANSFER_DST | ImageUsage::SAMPLED,
+                ..Default::default()
+            },
+            AllocationCreateInfo::default(),
+        )?;
+
+        let mut cmd_buf = AutoCommandBufferBuilder::primary(
+            cmd_alloc,
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+        cmd_buf
+            .copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(staging, image.clone()))?;
+        cmd_buf
+            .build()?
+            .execute(queue)?
+            .then_signal_fence_and_flush()?
+            .wait(None)?;
+
+        let sampler = Sampler::new(
+            device,
+            SamplerCreateInfo {
+                mag_filter: Filter::Linear,
+                min_filter: Filter::Linear,
+                address_mode: [SamplerAddressMode::ClampToEdge; 3],
+                ..Default::default()
+            },
+        )?;
+
+        Ok(GpuTexture {
+            view: ImageView::new(
+                image.clone(),
+                ImageViewCreateInfo {
+                    view_type: ImageViewType::Cube,
+                    ..ImageViewCreateInfo::from_image(&image)
+                },
+            )?,
+            sampler,
+        })
+    }
+}